@@ -0,0 +1,361 @@
+//! Steganalysis of the lowest bit plane of an image.
+//!
+//! Three classic attacks on LSB replacement estimate, channel by channel, the
+//! share of samples whose lowest bit carries a payload:
+//!
+//! - The chi-square attack of Westfeld and Pfitzmann. Replacing LSBs with
+//!   random bits evens out the counts of each pair of values `2k` and
+//!   `2k + 1`, which the attack tests for over growing prefixes of the channel,
+//!   as a payload embedded in order only evens them out where it went.
+//! - RS analysis of Fridrich, Goljan and Du, which measures how flipping LSBs
+//!   changes the noise of small groups of neighbouring samples.
+//! - Sample pair analysis of Dumitrescu, Wu and Wang, which counts how pairs
+//!   of neighbouring samples fall into the classes LSB replacement moves them
+//!   between.
+//!
+//! The estimates only hint at a payload: they assume LSB replacement of
+//! randomly distributed bits, and natural images rarely read exactly zero.
+
+use image::ColorType;
+
+/// Least expected count of a pair of values for the chi-square attack to take
+/// it into account.
+const MIN_EXPECTED: f64 = 5.0;
+/// Growing prefixes of the channel the chi-square attack tests.
+const CHI_SQUARE_STEPS: usize = 100;
+/// p-value from which the chi-square attack deems a prefix embedded.
+const CHI_SQUARE_THRESHOLD: f64 = 0.5;
+/// Samples in an RS analysis group.
+const GROUP_SIZE: usize = 4;
+/// Samples of a group that RS analysis flips.
+const MASK: [bool; GROUP_SIZE] = [false, true, true, false];
+
+/// Results of the attacks on every channel, see [`StegoImage::analyze`].
+///
+/// [`StegoImage::analyze`]: crate::stego_image::StegoImage::analyze
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnalysisReport {
+  pub color: ColorType,
+  pub channels: Vec<ChannelAnalysis>,
+}
+
+/// Results of the attacks on one channel. Rates are shares of the samples of
+/// the channel, `None` when an attack is inconclusive on the image.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelAnalysis {
+  /// `r`, `g`, `b`, `l` for grey or `a`.
+  pub name: char,
+  /// Probability of the chi-square attack that the LSBs of the whole channel
+  /// are random.
+  pub chi_square_p: f64,
+  /// Share of the channel, from its start, the chi-square attack finds
+  /// embedded.
+  pub chi_square_rate: f64,
+  pub rs_rate: Option<f64>,
+  pub spa_rate: Option<f64>,
+}
+
+/// Runs every attack on a channel whose `values` are given row by row, `width`
+/// to a row.
+pub(crate) fn analyze_channel(name: char, values: &[u16], width: usize) -> ChannelAnalysis {
+  let (chi_square_p, chi_square_rate) = chi_square(values);
+  ChannelAnalysis {
+    name,
+    chi_square_p,
+    chi_square_rate,
+    rs_rate: rs(values, width),
+    spa_rate: spa(values, width),
+  }
+}
+
+/// Returns the p-value of the whole channel and the longest prefix, as a share
+/// of the channel, whose p-values all reach [`CHI_SQUARE_THRESHOLD`].
+fn chi_square(values: &[u16]) -> (f64, f64) {
+  let mut histogram = vec![0u64; 1 << 16];
+  let mut p = 0.0;
+  let mut rate = 0.0;
+  let mut start = 0;
+  for step in 1..=CHI_SQUARE_STEPS {
+    let end = values.len() * step / CHI_SQUARE_STEPS;
+    for &value in &values[start..end] {
+      histogram[usize::from(value)] += 1;
+    }
+    start = end;
+    p = chi_square_p(&histogram);
+    if p >= CHI_SQUARE_THRESHOLD {
+      rate = step as f64 / CHI_SQUARE_STEPS as f64;
+    }
+  }
+  (p, rate)
+}
+
+/// Probability that the counts of each pair of values are as even as LSB
+/// replacement leaves them.
+fn chi_square_p(histogram: &[u64]) -> f64 {
+  let mut statistic = 0.0;
+  let mut categories = 0;
+  for pair in histogram.chunks_exact(2) {
+    let expected = (pair[0] + pair[1]) as f64 / 2.0;
+    if expected >= MIN_EXPECTED {
+      statistic += (pair[0] as f64 - expected).powi(2) / expected;
+      categories += 1;
+    }
+  }
+  if categories < 2 {
+    return 0.0;
+  }
+  upper_gamma((categories - 1) as f64 / 2.0, statistic / 2.0)
+}
+
+/// Regularized upper incomplete gamma function `Q(a, x)`, the survival
+/// function of a chi-square distribution with `2a` degrees of freedom at `2x`.
+fn upper_gamma(a: f64, x: f64) -> f64 {
+  const EPSILON: f64 = 1e-12;
+  const MAX_ITERATIONS: usize = 100_000;
+  if x <= 0.0 {
+    return 1.0;
+  }
+  let prefactor = (a * x.ln() - x - ln_gamma(a)).exp();
+  if x < a + 1.0 {
+    // Series of the lower function.
+    let mut term = 1.0 / a;
+    let mut sum = term;
+    for n in 1..MAX_ITERATIONS {
+      term *= x / (a + n as f64);
+      sum += term;
+      if term < sum * EPSILON {
+        break;
+      }
+    }
+    (1.0 - sum * prefactor).clamp(0.0, 1.0)
+  } else {
+    // Continued fraction of the upper function, evaluated with Lentz's method.
+    let tiny = f64::MIN_POSITIVE / EPSILON;
+    let mut b = x + 1.0 - a;
+    let mut c = 1.0 / tiny;
+    let mut d = 1.0 / b;
+    let mut fraction = d;
+    for n in 1..MAX_ITERATIONS {
+      let an = -(n as f64) * (n as f64 - a);
+      b += 2.0;
+      d = an * d + b;
+      if d.abs() < tiny {
+        d = tiny;
+      }
+      c = b + an / c;
+      if c.abs() < tiny {
+        c = tiny;
+      }
+      d = 1.0 / d;
+      let delta = d * c;
+      fraction *= delta;
+      if (delta - 1.0).abs() < EPSILON {
+        break;
+      }
+    }
+    (fraction * prefactor).clamp(0.0, 1.0)
+  }
+}
+
+/// Natural logarithm of the gamma function for positive `x`, after Lanczos.
+fn ln_gamma(x: f64) -> f64 {
+  const COEFFICIENTS: [f64; 9] = [
+    0.999_999_999_999_809_9,
+    676.520_368_121_885_1,
+    -1_259.139_216_722_402_8,
+    771.323_428_777_653_1,
+    -176.615_029_162_140_6,
+    12.507_343_278_686_905,
+    -0.138_571_095_265_720_12,
+    9.984_369_578_019_572e-6,
+    1.505_632_735_149_311_6e-7,
+  ];
+  let x = x - 1.0;
+  let t = x + 7.5;
+  let series = COEFFICIENTS[1..]
+    .iter()
+    .enumerate()
+    .fold(COEFFICIENTS[0], |sum, (i, &coefficient)| {
+      sum + coefficient / (x + i as f64 + 1.0)
+    });
+  0.5 * (2.0 * std::f64::consts::PI).ln() + (x + 0.5) * t.ln() - t + series.ln()
+}
+
+/// Differences between regular and singular groups, per group, under the
+/// mask and the negated mask.
+fn rs_differences(values: &[i32], width: usize) -> (f64, f64) {
+  let (mut positive, mut negative, mut groups) = (0i64, 0i64, 0usize);
+  for row in values.chunks_exact(width) {
+    for group in row.chunks_exact(GROUP_SIZE) {
+      let noise = smoothness(group.iter().copied());
+      let flipped = |flip: fn(i32) -> i32| {
+        let masked = group
+          .iter()
+          .zip(MASK)
+          .map(|(&value, masked)| if masked { flip(value) } else { value });
+        smoothness(masked).cmp(&noise) as i64
+      };
+      positive += flipped(|value| value ^ 1);
+      negative += flipped(|value| ((value + 1) ^ 1) - 1);
+      groups += 1;
+    }
+  }
+  let groups = groups.max(1) as f64;
+  (positive as f64 / groups, negative as f64 / groups)
+}
+
+/// Sum of the differences between neighbouring samples of a group.
+fn smoothness(group: impl Iterator<Item = i32> + Clone) -> i32 {
+  group
+    .clone()
+    .zip(group.skip(1))
+    .map(|(a, b)| (a - b).abs())
+    .sum()
+}
+
+/// RS analysis on groups of neighbouring samples within rows.
+fn rs(values: &[u16], width: usize) -> Option<f64> {
+  if width < GROUP_SIZE {
+    return None;
+  }
+  let values: Vec<i32> = values.iter().map(|&value| i32::from(value)).collect();
+  let flipped: Vec<i32> = values.iter().map(|value| value ^ 1).collect();
+  let (d0, dn0) = rs_differences(&values, width);
+  let (d1, dn1) = rs_differences(&flipped, width);
+  let a = 2.0 * (d1 + d0);
+  let b = dn0 - dn1 - d1 - 3.0 * d0;
+  let c = d0 - dn0;
+  let root = if a.abs() < f64::EPSILON {
+    -c / b
+  } else {
+    let [first, second] = roots(a, b, c)?;
+    if first.abs() < second.abs() {
+      first
+    } else {
+      second
+    }
+  };
+  let rate = root / (root - 0.5);
+  rate.is_finite().then(|| rate.clamp(0.0, 1.0))
+}
+
+/// Sample pair analysis on horizontally neighbouring samples.
+fn spa(values: &[u16], width: usize) -> Option<f64> {
+  let (mut x, mut y, mut k, mut pairs) = (0i64, 0i64, 0i64, 0i64);
+  for row in values.chunks_exact(width) {
+    for pair in row.windows(2) {
+      let (u, v) = (pair[0], pair[1]);
+      if v % 2 == 0 && u < v || v % 2 == 1 && u > v {
+        x += 1;
+      }
+      if v % 2 == 0 && u > v || v % 2 == 1 && u < v {
+        y += 1;
+      }
+      if u / 2 == v / 2 {
+        k += 1;
+      }
+      pairs += 1;
+    }
+  }
+  if k == 0 {
+    return None;
+  }
+  let a = 2.0 * k as f64;
+  let b = 2.0 * (2 * x - pairs) as f64;
+  let c = (y - x) as f64;
+  // Heavy embedding pushes the discriminant slightly below zero, where the
+  // double root it is close to remains a good estimate. The root is the share
+  // of samples whose LSB changed, half of those carrying the payload.
+  let root = match roots(a, b, c) {
+    Some([first, second]) => first.min(second),
+    None => -b / (2.0 * a),
+  };
+  let rate = 2.0 * root;
+  rate.is_finite().then(|| rate.clamp(0.0, 1.0))
+}
+
+/// Real roots of `ax^2 + bx + c`, if any.
+fn roots(a: f64, b: f64, c: f64) -> Option<[f64; 2]> {
+  let discriminant = b * b - 4.0 * a * c;
+  let root = (discriminant >= 0.0).then(|| discriminant.sqrt())?;
+  Some([(-b + root) / (2.0 * a), (-b - root) / (2.0 * a)])
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const WIDTH: usize = 128;
+
+  /// Pseudo-random values in `0..bound`.
+  fn noise(len: usize, bound: u32) -> Vec<u16> {
+    let mut state = 7u32;
+    (0..len)
+      .map(|_| {
+        state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+        ((state >> 16) % bound) as u16
+      })
+      .collect()
+  }
+
+  /// Smooth waves with some sensor noise, like a natural photograph.
+  fn cover() -> Vec<u16> {
+    let noise = noise(WIDTH * WIDTH, 3);
+    (0..WIDTH * WIDTH)
+      .map(|i| {
+        let (x, y) = ((i % WIDTH) as f64, (i / WIDTH) as f64);
+        let wave = 120.0 + 50.0 * (x / 9.0).sin() + 40.0 * (y / 13.0).cos();
+        wave as u16 + noise[i] * 3
+      })
+      .collect()
+  }
+
+  /// [`cover`] with three even values to every odd one, so that the counts of
+  /// each pair of values are far apart.
+  fn uneven_cover() -> Vec<u16> {
+    let odd = noise(WIDTH * WIDTH, 4).into_iter().rev();
+    let values = cover().into_iter().zip(odd);
+    values
+      .map(|(value, odd)| value & !1 | u16::from(odd == 0))
+      .collect()
+  }
+
+  /// `values` with the LSBs of their first `share` replaced by random bits.
+  fn embedded(mut values: Vec<u16>, share: f64) -> Vec<u16> {
+    let end = (values.len() as f64 * share) as usize;
+    for (value, bit) in values[..end].iter_mut().zip(noise(end, 2)) {
+      *value = *value & !1 | bit;
+    }
+    values
+  }
+
+  #[test]
+  fn chi_square_finds_how_far_the_payload_reaches() {
+    let (p, rate) = chi_square(&uneven_cover());
+    assert!(p < 0.01 && rate < 0.1, "{p} {rate}");
+    let (_, rate) = chi_square(&embedded(uneven_cover(), 0.5));
+    assert!((0.4..=0.7).contains(&rate), "{rate}");
+    let (p, rate) = chi_square(&embedded(uneven_cover(), 1.0));
+    assert!(p > 0.9 && rate == 1.0, "{p} {rate}");
+  }
+
+  #[test]
+  fn rs_and_spa_estimate_the_embedding_rate() {
+    let clean = analyze_channel('r', &cover(), WIDTH);
+    assert!(clean.rs_rate.unwrap() < 0.1, "{clean:?}");
+    assert!(clean.spa_rate.unwrap() < 0.1, "{clean:?}");
+    let half = analyze_channel('r', &embedded(cover(), 0.5), WIDTH);
+    assert!((0.3..=0.7).contains(&half.rs_rate.unwrap()), "{half:?}");
+    let full = analyze_channel('r', &embedded(cover(), 1.0), WIDTH);
+    assert!(full.spa_rate.unwrap() > 0.6, "{full:?}");
+  }
+
+  #[test]
+  fn special_functions_match_closed_forms() {
+    for x in [0.5, 3.0, 20.0] {
+      assert!((upper_gamma(1.0, x) - (-x).exp()).abs() < 1e-9);
+    }
+    assert!((ln_gamma(5.0) - 24f64.ln()).abs() < 1e-9);
+    assert!((ln_gamma(0.5) - std::f64::consts::PI.sqrt().ln()).abs() < 1e-9);
+  }
+}
@@ -0,0 +1,86 @@
+//! Archive of several files stored as a single payload.
+//!
+//! Layout (all integers little endian): a version byte and a `u32` entry count,
+//! followed by every entry as a `u64` length and an encoded [`FileEnvelope`].
+
+use crate::{
+  envelope::FileEnvelope,
+  stego_image::{StegoError, StegoResult},
+};
+
+const VERSION: u8 = 1;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Archive {
+  entries: Vec<FileEnvelope>,
+}
+
+impl Archive {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn entries(&self) -> &[FileEnvelope] {
+    &self.entries
+  }
+
+  pub fn get(&self, name: &str) -> Option<&FileEnvelope> {
+    self.entries.iter().find(|entry| entry.name == name)
+  }
+
+  /// Appends `entry`, replacing an existing entry with the same name.
+  pub fn add(&mut self, entry: FileEnvelope) {
+    match self
+      .entries
+      .iter_mut()
+      .find(|existing| existing.name == entry.name)
+    {
+      Some(existing) => *existing = entry,
+      None => self.entries.push(entry),
+    }
+  }
+
+  pub fn remove(&mut self, name: &str) -> Option<FileEnvelope> {
+    let index = self.entries.iter().position(|entry| entry.name == name)?;
+    Some(self.entries.remove(index))
+  }
+
+  pub fn encode(&self) -> Vec<u8> {
+    let mut bytes = vec![VERSION];
+    bytes.extend_from_slice(&(self.entries.len() as u32).to_le_bytes());
+    for entry in &self.entries {
+      let encoded = entry.encode();
+      bytes.extend_from_slice(&(encoded.len() as u64).to_le_bytes());
+      bytes.extend_from_slice(&encoded);
+    }
+    bytes
+  }
+
+  pub fn decode(bytes: &[u8]) -> StegoResult<Self> {
+    let (&version, mut rest) = bytes.split_first().ok_or(StegoError::InvalidArchive)?;
+    if version != VERSION || rest.len() < 4 {
+      return Err(StegoError::InvalidArchive);
+    }
+    let count = u32::from_le_bytes(rest[..4].try_into().unwrap());
+    rest = &rest[4..];
+    let mut entries = Vec::new();
+    for _ in 0..count {
+      if rest.len() < 8 {
+        return Err(StegoError::InvalidArchive);
+      }
+      let len = u64::from_le_bytes(rest[..8].try_into().unwrap());
+      rest = &rest[8..];
+      let len = usize::try_from(len)
+        .ok()
+        .filter(|&len| len <= rest.len())
+        .ok_or(StegoError::InvalidArchive)?;
+      let (entry, tail) = rest.split_at(len);
+      entries.push(FileEnvelope::decode(entry)?);
+      rest = tail;
+    }
+    if !rest.is_empty() {
+      return Err(StegoError::InvalidArchive);
+    }
+    Ok(Self { entries })
+  }
+}
@@ -0,0 +1,65 @@
+use image::ColorType;
+
+use crate::config::EmbedConfig;
+
+/// Payload bytes available with one embedding configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModeCapacity {
+  pub config: EmbedConfig,
+  /// Bytes of payload that fit once every overhead is accounted for.
+  pub usable: usize,
+}
+
+/// Breakdown of how much an image can hold, see [`StegoImage::capacity`].
+///
+/// [`StegoImage::capacity`]: crate::stego_image::StegoImage::capacity
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapacityReport {
+  /// Colour type and depth the image is embedded in and saved with.
+  pub color: ColorType,
+  pub pixels: usize,
+  /// Pixels reserved for the container header.
+  pub header_pixels: usize,
+  /// Largest bits per channel the samples allow.
+  pub max_bits_per_channel: u8,
+  /// Bytes taken by the checksum stored in front of the payload.
+  pub checksum_overhead: usize,
+  /// Bytes added by encryption, zero without a password.
+  pub encryption_overhead: usize,
+  /// Bytes of the selected mode taken by error correction parity, zero when
+  /// error correction is disabled.
+  pub error_correction_overhead: usize,
+  /// Capacity of every bits per channel and channel mask combination.
+  pub modes: Vec<ModeCapacity>,
+}
+
+impl CapacityReport {
+  /// Usable bytes of the mode with the bits per channel and channels of
+  /// `config`, zero if there is none.
+  pub fn usable(&self, config: &EmbedConfig) -> usize {
+    let key = |config: &EmbedConfig| (config.bits_per_channel(), config.channels());
+    self
+      .modes
+      .iter()
+      .find(|mode| key(&mode.config) == key(config))
+      .map_or(0, |mode| mode.usable)
+  }
+}
+
+/// Breakdown of how much a JPEG can hold, see [`StegoJpeg::capacity`].
+///
+/// [`StegoJpeg::capacity`]: crate::stego_jpeg::StegoJpeg::capacity
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DctCapacityReport {
+  /// AC coefficients in the image.
+  pub coefficients: usize,
+  /// AC coefficients able to carry a bit.
+  pub usable_coefficients: usize,
+  /// Usable coefficients reserved for the container header.
+  pub header_coefficients: usize,
+  pub checksum_overhead: usize,
+  pub encryption_overhead: usize,
+  pub error_correction_overhead: usize,
+  /// Bytes of payload that fit once every overhead is accounted for.
+  pub usable: usize,
+}
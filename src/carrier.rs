@@ -0,0 +1,420 @@
+//! Images of either embedding domain behind one trait and one type.
+//!
+//! [`StegoCarrier`] holds what both domains share: the settings a payload is
+//! compressed, sealed and checked with, and how it is inserted, extracted and
+//! verified. [`StegoImage`] and [`StegoJpeg`] only decide where its bits go.
+
+use std::{
+  fs::File,
+  io::{self, Read},
+  ops::{Deref, DerefMut},
+  path::Path,
+};
+
+use image::{ImageFormat, ImageResult};
+
+use self::sealed::deflate;
+use crate::{
+  archive::Archive,
+  config::ConfigOverrides,
+  envelope::FileEnvelope,
+  header::{ChecksumKind, Flags, Header},
+  stego_image::{Extracted, PayloadReader, StegoError, StegoImage, StegoResult, Stored},
+  stego_jpeg::{self, StegoJpeg},
+};
+
+/// Image a payload is hidden in, either [`StegoImage`] or [`StegoJpeg`].
+/// Sealed, the domain-specific methods it relies on being internal.
+pub trait StegoCarrier: sealed::Domain {
+  /// Bytes of payload that fit with the current settings, once the header,
+  /// checksum and error correction are taken off.
+  fn available(&self) -> usize;
+
+  /// Reads the versioned header, returning `None` for images without one, i.e.
+  /// carrying no payload or written with the legacy layout.
+  fn header(&self) -> StegoResult<Option<Header>>;
+
+  /// Sets the secret that seeds the pseudo-random order in which the payload
+  /// is embedded. `None` embeds in the natural order of the image.
+  /// The key is stretched with scrypt, so this takes a moment.
+  fn set_ordering_key(&mut self, key: Option<&str>);
+
+  /// Writes the image to `path`.
+  fn save(&self, path: &Path) -> StegoResult<()>;
+
+  /// Sets the passphrase used to seal the payload on insertion and to open it
+  /// on extraction. `None` stores the payload in the clear.
+  fn set_password(&mut self, password: Option<&str>) {
+    self.settings_mut().password = password.map(|password| password.as_bytes().to_vec());
+  }
+
+  /// Selects the integrity check stored in front of the payload. Fails with
+  /// [`StegoError::InvalidConfig`] for [`ChecksumKind::DefaultHasher`], which
+  /// is only read from legacy images.
+  fn set_checksum(&mut self, checksum: ChecksumKind) -> StegoResult<()> {
+    if checksum == ChecksumKind::DefaultHasher {
+      return Err(StegoError::InvalidConfig);
+    }
+    self.settings_mut().checksum = checksum;
+    Ok(())
+  }
+
+  /// Enables zlib compression of the payload before it is sealed and embedded.
+  /// Compression is skipped for payloads it does not shrink.
+  fn set_compression(&mut self, compression: bool) {
+    self.settings_mut().compression = compression;
+  }
+
+  /// Estimates how many bytes of data that compresses like `sample` fit into
+  /// the image once sealing overhead is taken into account.
+  fn available_compressed(&self, sample: &[u8]) -> usize {
+    let settings = self.settings();
+    let usable = self
+      .available()
+      .saturating_sub(settings.encryption_overhead());
+    match settings.compression.then(|| deflate(sample)).flatten() {
+      Some(compressed) => usable * sample.len() / compressed.len(),
+      None => usable,
+    }
+  }
+
+  /// Number of bytes `data` occupies in the image once compressed and sealed,
+  /// to be compared against [`Self::available`].
+  fn required_space(&self, data: &[u8]) -> usize {
+    let settings = self.settings();
+    let compressed_len = match settings.compression.then(|| deflate(data)).flatten() {
+      Some(compressed) => compressed.len(),
+      None => data.len(),
+    };
+    compressed_len + settings.encryption_overhead()
+  }
+
+  fn insert_data(&mut self, data: &[u8]) -> StegoResult<()> {
+    if data.is_empty() {
+      return Err(StegoError::NothingToInsert);
+    }
+    self.insert(data, Flags::default())
+  }
+
+  /// Inserts a file together with its name and metadata, so it can be
+  /// restored with [`Self::extract_file`].
+  fn insert_file(&mut self, file: &FileEnvelope) -> StegoResult<()> {
+    let flags = Flags {
+      envelope: true,
+      ..Flags::default()
+    };
+    self.insert(&file.encode(), flags)
+  }
+
+  /// Inserts several files at once, see [`Self::extract_archive`].
+  fn insert_archive(&mut self, archive: &Archive) -> StegoResult<()> {
+    let flags = Flags {
+      archive: true,
+      ..Flags::default()
+    };
+    self.insert(&archive.encode(), flags)
+  }
+
+  /// Extracts, opens and decompresses the payload, see [`Extracted`].
+  fn extract(&self) -> StegoResult<Extracted> {
+    let (data, flags, corrected) = match self.stored()? {
+      Some(Stored {
+        header: Header { shard: Some(_), .. },
+        ..
+      }) => return Err(StegoError::Sharded),
+      Some(stored) => (stored.data, stored.header.flags, stored.corrected),
+      // The legacy layout had no encryption, whatever password is set.
+      None => (self.legacy()?, Flags::default(), 0),
+    };
+    Ok(Extracted {
+      data: self.settings().decode(data, flags)?,
+      flags,
+      corrected,
+    })
+  }
+
+  /// Extracts the payload. For images carrying a file only its contents are
+  /// returned, see [`Self::extract_file`]. How many bytes error correction had
+  /// to repair is told by [`Self::extract`].
+  fn extract_data(&self) -> StegoResult<Vec<u8>> {
+    self.extract()?.into_data()
+  }
+
+  /// Extracts a file inserted with [`Self::insert_file`].
+  fn extract_file(&self) -> StegoResult<FileEnvelope> {
+    self.extract()?.into_file()
+  }
+
+  /// Extracts the files inserted with [`Self::insert_archive`]. A single file
+  /// inserted with [`Self::insert_file`] is returned as a one-entry archive.
+  fn extract_archive(&self) -> StegoResult<Archive> {
+    self.extract()?.into_archive()
+  }
+
+  /// Like [`Self::save`], then opens the written file and checks that the
+  /// header and the payload, as stored, read back identical, failing with
+  /// [`StegoError::VerificationFailed`] otherwise. Shards are verified on
+  /// their own.
+  fn save_verified(&self, path: &Path) -> StegoResult<()> {
+    let expected = self.load()?;
+    self.save(path)?;
+    match self.reopen(path)?.load() {
+      Ok(actual) if actual.header == expected.header && actual.data == expected.data => Ok(()),
+      _ => Err(StegoError::VerificationFailed),
+    }
+  }
+}
+
+pub(crate) mod sealed {
+  use std::{
+    borrow::Cow,
+    io::{Read, Write},
+    path::Path,
+  };
+
+  use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+
+  use super::StegoCarrier;
+  use crate::{
+    crypto,
+    header::{ChecksumKind, Flags, Shard},
+    stego_image::{StegoError, StegoResult, Stored},
+  };
+
+  /// How a payload is sealed, compressed, checked and ordered, the same in
+  /// both domains.
+  #[derive(Clone, Default)]
+  pub struct PayloadSettings {
+    pub password: Option<Vec<u8>>,
+    /// Derived from the ordering key, see [`StegoCarrier::set_ordering_key`].
+    pub ordering_seed: Option<[u8; 32]>,
+    pub checksum: ChecksumKind,
+    pub compression: bool,
+  }
+
+  impl PayloadSettings {
+    /// Compresses `data` if compression is enabled and it helps, then seals it
+    /// if a password is set, recording both in `flags`.
+    pub fn encode<'a>(&self, data: &'a [u8], flags: &mut Flags) -> Cow<'a, [u8]> {
+      let mut data = Cow::Borrowed(data);
+      if let Some(compressed) = self.compression.then(|| deflate(&data)).flatten() {
+        data = Cow::Owned(compressed);
+        flags.compressed = true;
+      }
+      if let Some(password) = &self.password {
+        data = Cow::Owned(crypto::seal(password, &data));
+        flags.encrypted = true;
+      }
+      data
+    }
+
+    /// Reverses [`Self::encode`] as described by `flags`.
+    pub fn decode(&self, mut data: Vec<u8>, flags: Flags) -> StegoResult<Vec<u8>> {
+      if flags.encrypted {
+        let password = self
+          .password
+          .as_deref()
+          .ok_or(StegoError::PasswordRequired)?;
+        data = crypto::open(password, &data).map_err(|err| match err {
+          crypto::OpenError::Malformed => StegoError::InvalidEncryptedData,
+          crypto::OpenError::Unauthenticated => StegoError::WrongPassword,
+        })?;
+      }
+      if flags.compressed {
+        data = inflate(&data)?;
+      }
+      Ok(data)
+    }
+
+    /// Bytes sealing adds to a payload, zero without a password.
+    pub fn encryption_overhead(&self) -> usize {
+      if self.password.is_some() {
+        crypto::OVERHEAD
+      } else {
+        0
+      }
+    }
+  }
+
+  /// Where the bits of a payload go, see [`StegoCarrier`].
+  pub trait Domain {
+    fn settings(&self) -> &PayloadSettings;
+
+    fn settings_mut(&mut self) -> &mut PayloadSettings;
+
+    /// Embeds `data` as it was left by [`PayloadSettings::encode`], along with
+    /// the header describing it.
+    fn store(&mut self, data: &[u8], flags: Flags, shard: Option<Shard>) -> StegoResult<()>;
+
+    /// Reads the payload behind the versioned header without opening or
+    /// decompressing it, `None` for images without a header.
+    fn stored(&self) -> StegoResult<Option<Stored>>;
+
+    /// Reads a payload embedded before the versioned header was introduced.
+    fn legacy(&self) -> StegoResult<Vec<u8>> {
+      Err(StegoError::NoPayload)
+    }
+
+    /// Opens the image written to `path` with the same settings.
+    fn reopen(&self, path: &Path) -> StegoResult<Box<dyn StegoCarrier>>;
+
+    /// Like [`Self::stored`], failing for images without a header.
+    fn load(&self) -> StegoResult<Stored> {
+      self.stored()?.ok_or(StegoError::NoPayload)
+    }
+
+    /// Inserts `data`, `flags` describing what kind of payload it is.
+    fn insert(&mut self, data: &[u8], mut flags: Flags) -> StegoResult<()> {
+      let data = self.settings().encode(data, &mut flags);
+      self.store(&data, flags, None)
+    }
+  }
+
+  /// Compresses `data` with zlib, returning `None` if that does not make it
+  /// smaller.
+  pub fn deflate(data: &[u8]) -> Option<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(data).ok()?;
+    let compressed = encoder.finish().ok()?;
+    (compressed.len() < data.len()).then_some(compressed)
+  }
+
+  fn inflate(data: &[u8]) -> StegoResult<Vec<u8>> {
+    let mut decompressed = Vec::new();
+    ZlibDecoder::new(data)
+      .read_to_end(&mut decompressed)
+      .map_err(|_| StegoError::InvalidCompressedData)?;
+    Ok(decompressed)
+  }
+}
+
+/// Image hiding its payload either in pixel samples or in JPEG coefficients,
+/// usable as a [`StegoCarrier`] of either domain.
+pub enum Carrier {
+  Pixel(StegoImage),
+  Dct(Box<StegoJpeg>),
+}
+
+/// Why an image [`Carrier::write`] produces may not be what its path
+/// suggests, see [`Carrier::output_warning`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputWarning {
+  /// The format is lossy and was let through by
+  /// [`StegoImage::set_allow_lossy`], so the payload will not survive.
+  Lossy,
+  /// The DCT domain writes JPEG data whatever the extension.
+  JpegData,
+}
+
+impl Carrier {
+  /// Opens a JPEG in the DCT domain, see [`StegoJpeg::open`], and any other
+  /// image in the pixel domain, see [`StegoImage::open`]. JPEGs are told
+  /// apart by their contents rather than their extension.
+  pub fn open(path: &Path, quality: u8) -> ImageResult<Self> {
+    let mut magic = Vec::new();
+    File::open(path)?.take(3).read_to_end(&mut magic)?;
+    if stego_jpeg::is_jpeg(&magic) {
+      Ok(Carrier::Dct(Box::new(StegoJpeg::open(path, quality)?)))
+    } else {
+      Ok(Carrier::Pixel(StegoImage::open(path)?))
+    }
+  }
+
+  /// Reads the archive this carrier holds so it can be rewritten, set up to
+  /// embed it again the way it was written unless `overrides` says otherwise.
+  /// A carrier without a payload yields an empty archive.
+  pub fn existing_archive(&mut self, overrides: &ConfigOverrides) -> StegoResult<Archive> {
+    let header = match self.header()? {
+      Some(header) => header,
+      None => return Ok(Archive::new()),
+    };
+    match self {
+      Carrier::Pixel(img) => img.set_config(header.config()?.merged(overrides)?),
+      Carrier::Dct(img) => {
+        img.set_error_correction(overrides.ecc_parity.unwrap_or(header.ecc_parity))?
+      }
+    }
+    self.set_checksum(overrides.checksum.unwrap_or(header.checksum))?;
+    self.set_compression(overrides.compression || header.flags.compressed);
+    self.extract_archive()
+  }
+
+  /// Inserts the data read from `payload`. The pixel domain embeds it as it
+  /// is read, see [`StegoImage::writer`], the DCT domain once all of it is.
+  pub fn insert_from(&mut self, payload: &mut dyn Read) -> StegoResult<()> {
+    let img = match self {
+      Carrier::Pixel(img) => img,
+      Carrier::Dct(img) => {
+        let mut data = Vec::new();
+        payload
+          .read_to_end(&mut data)
+          .map_err(StegoError::Payload)?;
+        return img.insert_data(&data);
+      }
+    };
+    let mut writer = img.writer()?;
+    match io::copy(payload, &mut writer) {
+      Ok(_) => writer.finish(),
+      // Running out of space surfaces as a write error carrying the cause.
+      Err(err) if err.get_ref().is_some_and(|err| err.is::<StegoError>()) => {
+        Err(*err.into_inner().unwrap().downcast::<StegoError>().unwrap())
+      }
+      Err(err) => Err(StegoError::Payload(err)),
+    }
+  }
+
+  /// Starts reading the payload piece by piece, see [`StegoImage::reader`].
+  /// `None` where only [`StegoCarrier::extract`] can read it: in the DCT
+  /// domain and for payloads with error correction, whose repairs it reports.
+  pub fn reader(&self) -> StegoResult<Option<PayloadReader<'_>>> {
+    match self {
+      Carrier::Pixel(img) if img.header()?.is_none_or(|header| header.ecc_parity == 0) => {
+        img.reader().map(Some)
+      }
+      _ => Ok(None),
+    }
+  }
+
+  /// What writing the image to `path` does that its extension does not
+  /// suggest, if anything.
+  pub fn output_warning(&self, path: &Path) -> Option<OutputWarning> {
+    let format = ImageFormat::from_path(path).ok();
+    match self {
+      Carrier::Pixel(img) if img.allows_lossy() && format.is_some_and(|f| img.is_lossy(f)) => {
+        Some(OutputWarning::Lossy)
+      }
+      Carrier::Dct(_) if format != Some(ImageFormat::Jpeg) => Some(OutputWarning::JpegData),
+      _ => None,
+    }
+  }
+
+  /// Writes the image to `path`, checking with `verify` that the payload
+  /// reads back, see [`StegoCarrier::save_verified`].
+  pub fn write(&self, path: &Path, verify: bool) -> StegoResult<()> {
+    if verify {
+      self.save_verified(path)
+    } else {
+      self.save(path)
+    }
+  }
+}
+
+impl Deref for Carrier {
+  type Target = dyn StegoCarrier;
+
+  fn deref(&self) -> &Self::Target {
+    match self {
+      Carrier::Pixel(img) => img,
+      Carrier::Dct(img) => &**img,
+    }
+  }
+}
+
+impl DerefMut for Carrier {
+  fn deref_mut(&mut self) -> &mut Self::Target {
+    match self {
+      Carrier::Pixel(img) => img,
+      Carrier::Dct(img) => &mut **img,
+    }
+  }
+}
@@ -0,0 +1,623 @@
+//! Command-line argument parsing.
+
+use std::{path::PathBuf, str::FromStr};
+
+use rustego::{ChannelMask, ChecksumKind, ConfigOverrides, EmbedConfig};
+
+pub const USAGE: &str = "\
+Usage: rustego <COMMAND> [OPTIONS]
+
+Hide data in the low bits of an image and get it back.
+
+Commands:
+  embed     Embed a payload into an image
+  extract   Extract the payload from an image
+  capacity  Report how many bytes an image can hold
+  inspect   Show the header of an image carrying a payload
+  list      List the files of an archive embedded in an image
+  add       Add a file to the archive embedded in an image
+  remove    Remove a file from the archive embedded in an image
+  analyze   Estimate how much of an image carries a hidden payload
+  plane     Render bit planes of one channel as an image
+  diff      Map the pixels a stego image changed in its cover
+  metrics   Measure how much a stego image strays from its cover
+
+Run `rustego <COMMAND> --help` for the options of a command.";
+
+const EMBED_USAGE: &str = "\
+Usage: rustego embed --input <IMAGE> --output <IMAGE> [OPTIONS]
+
+The dct domain hides the payload in the DCT coefficients of a JPEG, so the
+output is always written as a JPEG. The pixel domain needs a lossless output
+format such as PNG, and keeps the colour type and bit depth of the carrier as
+well as the metadata chunks and compression settings of a PNG carrier.
+
+With a directory or a pattern such as `photos/*.png` as input, the payload is
+spread over all matching images in proportion to their capacity, and each one
+is written under its own name to the --output directory. Extracting needs all
+of them, in any order. With --threshold K every image instead carries a share as
+large as the payload, any K of the images recover it and fewer reveal nothing.
+
+Options:
+  -i, --input <IMAGE>     Carrier image, or a directory or `*`/`?` pattern of carriers
+  -o, --output <IMAGE>    Where to write the resulting image, or directory for several
+  -p, --payload <FILE>    Payload to embed [default: stdin]
+      --password <PASS>   Encrypt the payload [env: RUSTEGO_PASSWORD]
+      --key <KEY>         Scatter the payload in a key-seeded order [env: RUSTEGO_KEY]
+      --bits <1-8>        Bits per channel carrying the payload, at most 4 for 8-bit
+                          images [default: 2]
+      --channels <RGBA>   Channels carrying the payload, e.g. `rgb` [default: rgba]
+      --checksum <KIND>   Integrity check, `crc32` or `sha256` [default: crc32]
+      --compress          Compress the payload before embedding
+      --lsb-matching      Move samples by ±1 instead of replacing their low bits, which is
+                          harder to detect
+      --adaptive          Keep the payload to the most textured pixels that hold it
+      --domain <DOMAIN>   Embed in `pixel` samples or JPEG `dct` coefficients [default: dct for
+                          JPEG input, pixel otherwise]
+      --quality <1-100>   JPEG quality when the dct domain converts a carrier [default: 90]
+      --ecc <PARITY>      Reed-Solomon parity bytes per 255-byte block, 0-128 [default: 0]
+      --matrix <K>        Hide every K bits in 2^K-1 samples changing at most one, 1-8;
+                          implies --bits 1
+      --file              Store the payload file name and metadata alongside it
+      --threshold <K>     Share the payload so that any K of several carriers recover it
+      --allow-lossy       Write a lossy output format even though it destroys the payload
+      --verify            Re-open the written image and check that the payload reads back
+  -h, --help              Print this help";
+
+const EXTRACT_USAGE: &str = "\
+Usage: rustego extract --input <IMAGE> [OPTIONS]
+
+Options:
+  -i, --input <IMAGE>     Image carrying the payload, or a directory or `*`/`?` pattern
+                          of images carrying its shards or shares
+  -o, --output <FILE>     Where to write the payload [default: stdout]
+  -d, --dir <DIR>         Restore an embedded file under its original name in DIR
+  -e, --entry <NAME>      Extract the archive entry called NAME
+      --password <PASS>   Password the payload was encrypted with [env: RUSTEGO_PASSWORD]
+      --key <KEY>         Key the payload was scattered with [env: RUSTEGO_KEY]
+  -h, --help              Print this help";
+
+const CAPACITY_USAGE: &str = "\
+Usage: rustego capacity --input <IMAGE> [OPTIONS]
+
+Reports the usable bytes for every bits per channel and channel combination,
+or the usable bytes of the DCT coefficients of a JPEG. With --payload, exits with a failure status if the payload does not fit.
+
+Options:
+  -i, --input <IMAGE>     Carrier image
+  -p, --payload <FILE>    Check whether this payload fits
+      --password <PASS>   Account for encryption overhead [env: RUSTEGO_PASSWORD]
+      --bits <1-8>        Bits per channel carrying the payload, at most 4 for 8-bit
+                          images [default: 2]
+      --channels <RGBA>   Channels carrying the payload, e.g. `rgb` [default: rgba]
+      --checksum <KIND>   Integrity check, `crc32` or `sha256` [default: crc32]
+      --compress          Account for compressing the payload
+      --domain <DOMAIN>   Embed in `pixel` samples or JPEG `dct` coefficients [default: dct for
+                          JPEG input, pixel otherwise]
+      --quality <1-100>   JPEG quality when the dct domain converts a carrier [default: 90]
+      --ecc <PARITY>      Reed-Solomon parity bytes per 255-byte block, 0-128 [default: 0]
+      --matrix <K>        Hide every K bits in 2^K-1 samples changing at most one, 1-8;
+                          implies --bits 1
+  -h, --help              Print this help";
+
+const LIST_USAGE: &str = "\
+Usage: rustego list --input <IMAGE> [OPTIONS]
+
+Options:
+  -i, --input <IMAGE>     Image carrying the archive
+      --password <PASS>   Password the archive was encrypted with [env: RUSTEGO_PASSWORD]
+      --key <KEY>         Key the archive was scattered with [env: RUSTEGO_KEY]
+  -h, --help              Print this help";
+
+const ADD_USAGE: &str = "\
+Usage: rustego add --input <IMAGE> --output <IMAGE> --payload <FILE> [OPTIONS]
+
+Adds a file to the archive in the image, replacing an entry with the same name.
+An image without a versioned header starts a new archive. The embedding options
+of the existing archive are kept unless overridden.
+
+Options:
+  -i, --input <IMAGE>     Image carrying the archive
+  -o, --output <IMAGE>    Where to write the resulting image
+  -p, --payload <FILE>    File to add
+      --password <PASS>   Encrypt the archive [env: RUSTEGO_PASSWORD]
+      --key <KEY>         Scatter the archive in a key-seeded order [env: RUSTEGO_KEY]
+      --bits <1-8>        Bits per channel carrying the archive, at most 4 for 8-bit
+                          images
+      --channels <RGBA>   Channels carrying the archive, e.g. `rgb`
+      --checksum <KIND>   Integrity check, `crc32` or `sha256`
+      --compress          Compress the archive before embedding
+      --lsb-matching      Move samples by ±1 instead of replacing their low bits, which is
+                          harder to detect
+      --adaptive          Keep the archive to the most textured pixels that hold it
+      --domain <DOMAIN>   Embed in `pixel` samples or JPEG `dct` coefficients [default: dct for
+                          JPEG input, pixel otherwise]
+      --quality <1-100>   JPEG quality when the dct domain converts a carrier [default: 90]
+      --ecc <PARITY>      Reed-Solomon parity bytes per 255-byte block, 0-128
+      --matrix <K>        Hide every K bits in 2^K-1 samples changing at most one, 1-8;
+                          0 disables it
+      --allow-lossy       Write a lossy output format even though it destroys the payload
+      --verify            Re-open the written image and check that the payload reads back
+  -h, --help              Print this help";
+
+const REMOVE_USAGE: &str = "\
+Usage: rustego remove --input <IMAGE> --output <IMAGE> --entry <NAME> [OPTIONS]
+
+Options:
+  -i, --input <IMAGE>     Image carrying the archive
+  -o, --output <IMAGE>    Where to write the resulting image
+  -e, --entry <NAME>      Name of the file to remove
+      --password <PASS>   Password the archive was encrypted with [env: RUSTEGO_PASSWORD]
+      --key <KEY>         Key the archive was scattered with [env: RUSTEGO_KEY]
+      --allow-lossy       Write a lossy output format even though it destroys the payload
+      --verify            Re-open the written image and check that the payload reads back
+  -h, --help              Print this help";
+
+const INSPECT_USAGE: &str = "\
+Usage: rustego inspect --input <IMAGE> [OPTIONS]
+
+Options:
+  -i, --input <IMAGE>     Image carrying the payload
+      --key <KEY>         Key the payload was scattered with [env: RUSTEGO_KEY]
+  -h, --help              Print this help";
+
+const ANALYZE_USAGE: &str = "\
+Usage: rustego analyze --input <IMAGE>
+
+Runs the chi-square attack, RS analysis and sample pair analysis on the lowest
+bit plane of every channel, and prints the share of each channel they find
+carrying a payload. The estimates assume LSB replacement and only hint at a
+payload: natural images rarely read exactly zero.
+
+Options:
+  -i, --input <IMAGE>     Image to analyze
+  -h, --help              Print this help";
+
+const PLANE_USAGE: &str = "\
+Usage: rustego plane --input <IMAGE> --output <IMAGE> --channel <C> [OPTIONS]
+
+Renders bits of one channel as a greyscale image, stretched over the full range
+so a single bit plane comes out black and white. `--bits 2` shows the bits
+rustego writes by default.
+
+Options:
+  -i, --input <IMAGE>     Image to render
+  -o, --output <IMAGE>    Where to write the rendering
+      --channel <C>       Channel to render, `r`, `g`, `b` or `a`; any of `rgb` for grey
+                          images
+      --bit <N>           Lowest bit to render [default: 0]
+      --bits <1-8>        Bits to render from the lowest up [default: 1]
+  -h, --help              Print this help";
+
+const DIFF_USAGE: &str = "\
+Usage: rustego diff --input <COVER> --stego <IMAGE> --output <IMAGE>
+
+Maps the pixels in which a stego image differs from its cover. Every pixel shows
+its largest difference over the channels, stretched so the largest difference
+in the image comes out white.
+
+Options:
+  -i, --input <COVER>     Cover image
+      --stego <IMAGE>     The cover with a payload embedded
+  -o, --output <IMAGE>    Where to write the map
+  -h, --help              Print this help";
+
+const METRICS_USAGE: &str = "\
+Usage: rustego metrics --input <COVER> --stego <IMAGE> [OPTIONS]
+
+Reports the MSE, PSNR and SSIM of a stego image against its cover along with
+the samples embedding modified and the largest change of a sample, overall and
+for every channel. With --min-psnr or --min-ssim, exits with a failure status if
+the stego image falls short.
+
+Options:
+  -i, --input <COVER>     Cover image
+      --stego <IMAGE>     The cover with a payload embedded
+      --min-psnr <DB>     Least PSNR to accept
+      --min-ssim <SSIM>   Least SSIM to accept, at most 1
+  -h, --help              Print this help";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+  Embed,
+  Extract,
+  Capacity,
+  Inspect,
+  List,
+  Add,
+  Remove,
+  Analyze,
+  Plane,
+  Diff,
+  Metrics,
+}
+
+impl Command {
+  fn name(self) -> &'static str {
+    match self {
+      Command::Embed => "embed",
+      Command::Extract => "extract",
+      Command::Capacity => "capacity",
+      Command::Inspect => "inspect",
+      Command::List => "list",
+      Command::Add => "add",
+      Command::Remove => "remove",
+      Command::Analyze => "analyze",
+      Command::Plane => "plane",
+      Command::Diff => "diff",
+      Command::Metrics => "metrics",
+    }
+  }
+
+  fn usage(self) -> &'static str {
+    match self {
+      Command::Embed => EMBED_USAGE,
+      Command::Extract => EXTRACT_USAGE,
+      Command::Capacity => CAPACITY_USAGE,
+      Command::Inspect => INSPECT_USAGE,
+      Command::List => LIST_USAGE,
+      Command::Add => ADD_USAGE,
+      Command::Remove => REMOVE_USAGE,
+      Command::Analyze => ANALYZE_USAGE,
+      Command::Plane => PLANE_USAGE,
+      Command::Diff => DIFF_USAGE,
+      Command::Metrics => METRICS_USAGE,
+    }
+  }
+
+  fn accepts(self, option: &str) -> bool {
+    let options: &[&str] = match self {
+      Command::Embed => &[
+        "input",
+        "output",
+        "payload",
+        "password",
+        "key",
+        "bits",
+        "channels",
+        "checksum",
+        "compress",
+        "lsb-matching",
+        "adaptive",
+        "domain",
+        "quality",
+        "ecc",
+        "matrix",
+        "file",
+        "threshold",
+        "allow-lossy",
+        "verify",
+      ],
+      Command::Extract => &["input", "output", "dir", "entry", "password", "key"],
+      Command::Capacity => &[
+        "input", "payload", "password", "bits", "channels", "checksum", "compress", "domain",
+        "quality", "ecc", "matrix",
+      ],
+      Command::Inspect => &["input", "key"],
+      Command::List => &["input", "password", "key"],
+      Command::Add => &[
+        "input",
+        "output",
+        "payload",
+        "password",
+        "key",
+        "bits",
+        "channels",
+        "checksum",
+        "compress",
+        "lsb-matching",
+        "adaptive",
+        "domain",
+        "quality",
+        "ecc",
+        "matrix",
+        "allow-lossy",
+        "verify",
+      ],
+      Command::Remove => &[
+        "input",
+        "output",
+        "entry",
+        "password",
+        "key",
+        "allow-lossy",
+        "verify",
+      ],
+      Command::Analyze => &["input"],
+      Command::Plane => &["input", "output", "channel", "bit", "bits"],
+      Command::Diff => &["input", "stego", "output"],
+      Command::Metrics => &["input", "stego", "min-psnr", "min-ssim"],
+    };
+    options.contains(&option)
+  }
+}
+
+/// Where the payload is hidden.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Domain {
+  /// Low bits of the pixel samples, see [`StegoImage`](rustego::StegoImage).
+  Pixel,
+  /// DCT coefficients of a JPEG, see [`StegoJpeg`](crate::stego_jpeg::StegoJpeg).
+  Dct,
+}
+
+impl FromStr for Domain {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "pixel" => Ok(Domain::Pixel),
+      "dct" => Ok(Domain::Dct),
+      _ => Err(format!("unknown domain `{s}`, expected `pixel` or `dct`")),
+    }
+  }
+}
+
+#[derive(Debug, Default)]
+pub struct Options {
+  pub input: Option<PathBuf>,
+  pub output: Option<PathBuf>,
+  pub payload: Option<PathBuf>,
+  pub dir: Option<PathBuf>,
+  pub entry: Option<String>,
+  pub password: Option<String>,
+  pub key: Option<String>,
+  pub bits: Option<u8>,
+  pub channels: Option<ChannelMask>,
+  pub checksum: Option<ChecksumKind>,
+  pub compress: bool,
+  pub lsb_matching: bool,
+  pub adaptive: bool,
+  pub domain: Option<Domain>,
+  pub quality: Option<u8>,
+  pub ecc: Option<u8>,
+  pub matrix: Option<u8>,
+  pub file: bool,
+  pub threshold: Option<u8>,
+  /// Channel to render, an index into a [`ChannelMask`].
+  pub channel: Option<usize>,
+  pub bit: Option<u8>,
+  pub stego: Option<PathBuf>,
+  pub min_psnr: Option<f64>,
+  pub min_ssim: Option<f64>,
+  pub allow_lossy: bool,
+  pub verify: bool,
+}
+
+impl Options {
+  /// Choices made on the command line, to be kept when rewriting an archive.
+  pub fn overrides(&self) -> ConfigOverrides {
+    ConfigOverrides {
+      bits_per_channel: self.bits,
+      channels: self.channels,
+      ecc_parity: self.ecc,
+      matrix_embedding: self.matrix,
+      lsb_matching: self.lsb_matching,
+      adaptive: self.adaptive,
+      checksum: self.checksum,
+      compression: self.compress,
+    }
+  }
+
+  pub fn config(&self) -> Result<EmbedConfig, String> {
+    if self.adaptive && self.lsb_matching {
+      return Err(
+        "`--adaptive` measures texture on bits `--lsb-matching` may change, pick one".to_owned(),
+      );
+    }
+    let default = EmbedConfig::default();
+    // Matrix embedding only works on the lowest bit.
+    let default_bits = match self.matrix {
+      Some(1..) => 1,
+      _ => default.bits_per_channel(),
+    };
+    EmbedConfig::new(
+      self.bits.unwrap_or(default_bits),
+      self.channels.unwrap_or(default.channels()),
+    )
+    .map_err(|_| {
+      format!(
+        "--bits must be between 1 and {}",
+        EmbedConfig::MAX_BITS_PER_CHANNEL
+      )
+    })?
+    .with_error_correction(self.ecc.unwrap_or(default.ecc_parity()))
+    .map_err(|_| {
+      format!(
+        "--ecc must be between 0 and {}",
+        EmbedConfig::MAX_ECC_PARITY
+      )
+    })?
+    .with_matrix_embedding(self.matrix.unwrap_or(default.matrix_embedding()))
+    .map_err(|_| {
+      format!(
+        "--matrix must be between 0 and {} and needs --bits 1",
+        EmbedConfig::MAX_MATRIX_EMBEDDING
+      )
+    })
+    .map(|config| {
+      config
+        .with_lsb_matching(self.lsb_matching)
+        .with_adaptive(self.adaptive)
+    })
+  }
+}
+
+pub enum Parsed {
+  Run(Command, Box<Options>),
+  Help(&'static str),
+}
+
+pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Parsed, String> {
+  let command = match args.next().as_deref() {
+    None => return Err("missing command".to_owned()),
+    Some("-h" | "--help" | "help") => return Ok(Parsed::Help(USAGE)),
+    Some("embed") => Command::Embed,
+    Some("extract") => Command::Extract,
+    Some("capacity") => Command::Capacity,
+    Some("inspect") => Command::Inspect,
+    Some("list") => Command::List,
+    Some("add") => Command::Add,
+    Some("remove") => Command::Remove,
+    Some("analyze") => Command::Analyze,
+    Some("plane") => Command::Plane,
+    Some("diff") => Command::Diff,
+    Some("metrics") => Command::Metrics,
+    Some(other) => return Err(format!("unknown command `{other}`")),
+  };
+
+  let mut options = Options::default();
+  while let Some(arg) = args.next() {
+    let (name, inline_value) = match arg.split_once('=') {
+      Some((name, value)) if name.starts_with("--") => (name.to_owned(), Some(value.to_owned())),
+      _ => (arg.clone(), None),
+    };
+    let name = match name.as_str() {
+      "-h" | "--help" => return Ok(Parsed::Help(command.usage())),
+      "-i" => "input",
+      "-o" => "output",
+      "-p" => "payload",
+      "-d" => "dir",
+      "-e" => "entry",
+      other => other.strip_prefix("--").unwrap_or_default(),
+    };
+    if !command.accepts(name) {
+      return Err(format!(
+        "unexpected argument `{arg}` for `{}`",
+        command.name()
+      ));
+    }
+    let flag = match name {
+      "compress" => Some(&mut options.compress),
+      "lsb-matching" => Some(&mut options.lsb_matching),
+      "adaptive" => Some(&mut options.adaptive),
+      "file" => Some(&mut options.file),
+      "allow-lossy" => Some(&mut options.allow_lossy),
+      "verify" => Some(&mut options.verify),
+      _ => None,
+    };
+    if let Some(flag) = flag {
+      *flag = true;
+      continue;
+    }
+    let value = inline_value
+      .or_else(|| args.next())
+      .ok_or_else(|| format!("missing value for `--{name}`"))?;
+    match name {
+      "input" => options.input = Some(value.into()),
+      "output" => options.output = Some(value.into()),
+      "payload" => options.payload = Some(value.into()),
+      "dir" => options.dir = Some(value.into()),
+      "entry" => options.entry = Some(value),
+      "password" => options.password = Some(value),
+      "key" => options.key = Some(value),
+      "bits" => {
+        options.bits = Some(
+          value
+            .parse()
+            .map_err(|_| format!("invalid value `{value}` for `--bits`"))?,
+        )
+      }
+      "domain" => options.domain = Some(value.parse()?),
+      "quality" => {
+        options.quality = Some(
+          value
+            .parse()
+            .ok()
+            .filter(|quality| (1..=100).contains(quality))
+            .ok_or_else(|| format!("invalid value `{value}` for `--quality`"))?,
+        )
+      }
+      "ecc" => {
+        options.ecc = Some(
+          value
+            .parse()
+            .map_err(|_| format!("invalid value `{value}` for `--ecc`"))?,
+        )
+      }
+      "matrix" => {
+        options.matrix = Some(
+          value
+            .parse()
+            .map_err(|_| format!("invalid value `{value}` for `--matrix`"))?,
+        )
+      }
+      "threshold" => {
+        options.threshold = Some(
+          value
+            .parse()
+            .ok()
+            .filter(|&threshold| threshold > 0)
+            .ok_or_else(|| format!("invalid value `{value}` for `--threshold`"))?,
+        )
+      }
+      "channel" => {
+        let channel: ChannelMask = value.parse()?;
+        if channel.bits().count_ones() != 1 {
+          return Err(format!("`--channel` takes a single channel, not `{value}`"));
+        }
+        options.channel = Some(channel.bits().trailing_zeros() as usize);
+      }
+      "bit" => {
+        options.bit = Some(
+          value
+            .parse()
+            .map_err(|_| format!("invalid value `{value}` for `--bit`"))?,
+        )
+      }
+      "stego" => options.stego = Some(value.into()),
+      "min-psnr" => {
+        options.min_psnr = Some(
+          value
+            .parse()
+            .ok()
+            .filter(|psnr: &f64| !psnr.is_nan())
+            .ok_or_else(|| format!("invalid value `{value}` for `--min-psnr`"))?,
+        )
+      }
+      "min-ssim" => {
+        options.min_ssim = Some(
+          value
+            .parse()
+            .ok()
+            .filter(|ssim: &f64| *ssim <= 1.0)
+            .ok_or_else(|| format!("invalid value `{value}` for `--min-ssim`"))?,
+        )
+      }
+      "channels" => options.channels = Some(value.parse()?),
+      "checksum" => options.checksum = Some(value.parse()?),
+      _ => unreachable!("option `{name}` is accepted but not handled"),
+    }
+  }
+
+  if options.input.is_none() {
+    return Err("missing required option `--input`".to_owned());
+  }
+  if matches!(
+    command,
+    Command::Embed | Command::Add | Command::Remove | Command::Plane | Command::Diff
+  ) && options.output.is_none()
+  {
+    return Err("missing required option `--output`".to_owned());
+  }
+  if command == Command::Plane && options.channel.is_none() {
+    return Err("missing required option `--channel`".to_owned());
+  }
+  if matches!(command, Command::Diff | Command::Metrics) && options.stego.is_none() {
+    return Err("missing required option `--stego`".to_owned());
+  }
+  if command == Command::Add && options.payload.is_none() {
+    return Err("missing required option `--payload`".to_owned());
+  }
+  if command == Command::Remove && options.entry.is_none() {
+    return Err("missing required option `--entry`".to_owned());
+  }
+  if options.file && options.payload.is_none() {
+    return Err("`--file` requires `--payload`".to_owned());
+  }
+  if options.dir.is_some() && options.output.is_some() {
+    return Err("`--dir` and `--output` cannot be used together".to_owned());
+  }
+  options.config()?;
+  Ok(Parsed::Run(command, Box::new(options)))
+}
@@ -0,0 +1,239 @@
+use std::{fmt::Display, str::FromStr};
+
+use crate::{
+  header::ChecksumKind,
+  stego_image::{StegoError, StegoResult},
+};
+
+/// Set of channels that carry payload bits, one bit per channel in RGBA order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelMask(u8);
+
+impl ChannelMask {
+  pub const R: Self = Self(0b0001);
+  pub const G: Self = Self(0b0010);
+  pub const B: Self = Self(0b0100);
+  pub const A: Self = Self(0b1000);
+  pub const RGBA: Self = Self(0b1111);
+
+  /// Returns `None` for an empty mask or one with bits beyond the alpha channel.
+  pub fn from_bits(bits: u8) -> Option<Self> {
+    (bits != 0 && bits & !Self::RGBA.0 == 0).then_some(Self(bits))
+  }
+
+  /// Every non-empty mask, in increasing order of its bits.
+  pub fn all() -> impl Iterator<Item = Self> {
+    (1..=Self::RGBA.0).map(Self)
+  }
+
+  pub fn bits(self) -> u8 {
+    self.0
+  }
+
+  pub fn contains(self, channel: usize) -> bool {
+    self.0 >> channel & 1 != 0
+  }
+}
+
+impl FromStr for ChannelMask {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut bits = 0;
+    for channel in s.chars() {
+      bits |= match channel.to_ascii_lowercase() {
+        'r' => Self::R.0,
+        'g' => Self::G.0,
+        'b' => Self::B.0,
+        'a' => Self::A.0,
+        _ => {
+          return Err(format!(
+            "unknown channel `{channel}`, expected any of `rgba`"
+          ))
+        }
+      };
+    }
+    Self::from_bits(bits).ok_or_else(|| "channel mask must not be empty".to_owned())
+  }
+}
+
+impl Display for ChannelMask {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    for (channel, name) in "rgba".chars().enumerate() {
+      if self.contains(channel) {
+        write!(f, "{name}")?;
+      }
+    }
+    Ok(())
+  }
+}
+
+/// How payload bits are spread over the samples of the image. The choice is
+/// recorded in the header, so extraction does not need it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EmbedConfig {
+  bits_per_channel: u8,
+  channels: ChannelMask,
+  ecc_parity: u8,
+  lsb_matching: bool,
+  matrix_embedding: u8,
+  adaptive: bool,
+}
+
+impl EmbedConfig {
+  /// Most bits per channel, only available with 16-bit samples.
+  pub const MAX_BITS_PER_CHANNEL: u8 = 8;
+  /// Most bits per channel of 8-bit samples.
+  pub const MAX_BITS_PER_8_BIT_CHANNEL: u8 = 4;
+  pub const MAX_ECC_PARITY: u8 = 128;
+  /// Largest `k` of matrix embedding, hiding 8 bits in 255 samples.
+  pub const MAX_MATRIX_EMBEDDING: u8 = 8;
+
+  pub fn new(bits_per_channel: u8, channels: ChannelMask) -> StegoResult<Self> {
+    if !(1..=Self::MAX_BITS_PER_CHANNEL).contains(&bits_per_channel) {
+      return Err(StegoError::InvalidConfig);
+    }
+    Ok(Self {
+      bits_per_channel,
+      channels,
+      ecc_parity: 0,
+      lsb_matching: false,
+      matrix_embedding: 0,
+      adaptive: false,
+    })
+  }
+
+  /// Protects the payload with Reed-Solomon codes adding `parity` bytes to
+  /// every block of up to `255 - parity` bytes, which repairs up to
+  /// `parity / 2` corrupted bytes per block. Zero disables error correction.
+  pub fn with_error_correction(self, parity: u8) -> StegoResult<Self> {
+    if parity > Self::MAX_ECC_PARITY {
+      return Err(StegoError::InvalidConfig);
+    }
+    Ok(Self {
+      ecc_parity: parity,
+      ..self
+    })
+  }
+
+  /// Moves every sample to the nearest value carrying its payload bits,
+  /// randomly up or down when both are as near, instead of replacing its low
+  /// bits. With one bit per channel this is ±1 embedding, which avoids the
+  /// evened out pairs of values the chi-square attack and RS analysis look for.
+  /// Extraction is the same either way, so the choice is not recorded.
+  pub fn with_lsb_matching(self, lsb_matching: bool) -> Self {
+    Self {
+      lsb_matching,
+      ..self
+    }
+  }
+
+  /// Hides every `k` payload bits in the lowest bits of `2^k - 1` samples with
+  /// a Hamming code, changing at most one of them instead of about half. This
+  /// carries `k / (2^k - 1)` bits per sample, so a larger `k` trades capacity
+  /// for fewer changes. Needs one bit per channel; zero disables it.
+  pub fn with_matrix_embedding(self, k: u8) -> StegoResult<Self> {
+    if k > Self::MAX_MATRIX_EMBEDDING || k > 0 && self.bits_per_channel != 1 {
+      return Err(StegoError::InvalidConfig);
+    }
+    Ok(Self {
+      matrix_embedding: k,
+      ..self
+    })
+  }
+
+  /// Keeps the payload to the most textured pixels that hold it, away from
+  /// smooth areas where its noise shows. Texture is measured above the bits
+  /// carrying the payload, so this cannot be combined with LSB matching, whose
+  /// carries reach those bits.
+  pub fn with_adaptive(self, adaptive: bool) -> Self {
+    Self { adaptive, ..self }
+  }
+
+  pub fn bits_per_channel(&self) -> u8 {
+    self.bits_per_channel
+  }
+
+  pub fn channels(&self) -> ChannelMask {
+    self.channels
+  }
+
+  /// Reed-Solomon parity bytes per block, zero if disabled.
+  pub fn ecc_parity(&self) -> u8 {
+    self.ecc_parity
+  }
+
+  pub fn lsb_matching(&self) -> bool {
+    self.lsb_matching
+  }
+
+  /// `k` of matrix embedding, zero if disabled.
+  pub fn matrix_embedding(&self) -> u8 {
+    self.matrix_embedding
+  }
+
+  pub fn adaptive(&self) -> bool {
+    self.adaptive
+  }
+
+  /// This configuration with the choices made in `overrides` in place of its
+  /// own. Asking for matrix embedding drops to one bit per channel, and asking
+  /// for more bits per channel drops matrix embedding, unless both are given.
+  pub fn merged(self, overrides: &ConfigOverrides) -> StegoResult<Self> {
+    let bits = match (overrides.bits_per_channel, overrides.matrix_embedding) {
+      (Some(bits), _) => bits,
+      (None, Some(1..)) => 1,
+      (None, _) => self.bits_per_channel,
+    };
+    let matrix = match overrides.matrix_embedding {
+      Some(k) => k,
+      None if bits == 1 => self.matrix_embedding,
+      None => 0,
+    };
+    Ok(
+      Self::new(bits, overrides.channels.unwrap_or(self.channels))?
+        .with_error_correction(overrides.ecc_parity.unwrap_or(self.ecc_parity))?
+        .with_matrix_embedding(matrix)?
+        .with_lsb_matching(overrides.lsb_matching)
+        .with_adaptive(overrides.adaptive || self.adaptive),
+    )
+  }
+
+  /// Payload bits `samples` carrying samples hold.
+  pub fn carried_bits(&self, samples: usize) -> usize {
+    match self.matrix_embedding {
+      0 => samples * usize::from(self.bits_per_channel),
+      k => samples / ((1 << k) - 1) * usize::from(k),
+    }
+  }
+}
+
+/// Embedding choices made explicitly, to be kept when rewriting a payload that
+/// was embedded with other ones, see [`EmbedConfig::merged`] and
+/// [`Carrier::existing_archive`](crate::Carrier::existing_archive). Switches
+/// that are off leave the recorded choice alone, except LSB matching, which the
+/// header does not record.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConfigOverrides {
+  pub bits_per_channel: Option<u8>,
+  pub channels: Option<ChannelMask>,
+  pub ecc_parity: Option<u8>,
+  pub matrix_embedding: Option<u8>,
+  pub lsb_matching: bool,
+  pub adaptive: bool,
+  pub checksum: Option<ChecksumKind>,
+  pub compression: bool,
+}
+
+impl Default for EmbedConfig {
+  fn default() -> Self {
+    Self {
+      bits_per_channel: 2,
+      channels: ChannelMask::RGBA,
+      ecc_parity: 0,
+      lsb_matching: false,
+      matrix_embedding: 0,
+      adaptive: false,
+    }
+  }
+}
@@ -0,0 +1,197 @@
+//! Cryptography protecting embedded payloads.
+//!
+//! The payload is sealed with ChaCha20-Poly1305 (RFC 8439) under a key derived
+//! from a passphrase with scrypt (RFC 7914), both taken from the RustCrypto
+//! crates. This module only fixes the layout of a sealed payload and the
+//! parameters its key is derived with.
+
+use chacha20::{
+  cipher::{KeyIvInit, StreamCipher},
+  ChaCha20,
+};
+use chacha20poly1305::{aead::AeadInPlace, ChaCha20Poly1305, KeyInit};
+
+/// Deterministic random number generator driven by the ChaCha20 keystream,
+/// with a zero nonce and the block counter starting at zero.
+pub struct ChaChaRng(ChaCha20);
+
+impl ChaChaRng {
+  pub fn new(key: [u8; 32]) -> Self {
+    Self(ChaCha20::new(&key.into(), &[0; NONCE_SIZE].into()))
+  }
+
+  pub fn next_u32(&mut self) -> u32 {
+    let mut word = [0u8; 4];
+    self.0.apply_keystream(&mut word);
+    u32::from_le_bytes(word)
+  }
+
+  /// Returns a uniformly distributed value in `0..bound`.
+  pub fn below(&mut self, bound: u32) -> u32 {
+    let zone = u32::MAX - u32::MAX % bound;
+    loop {
+      let value = self.next_u32();
+      if value < zone {
+        return value % bound;
+      }
+    }
+  }
+}
+
+pub const SALT_SIZE: usize = 16;
+pub const NONCE_SIZE: usize = 12;
+pub const TAG_SIZE: usize = 16;
+const KDF_PARAMS_SIZE: usize = 3;
+/// Bytes in front of the ciphertext: KDF parameters, salt and nonce.
+pub const PREFIX_SIZE: usize = KDF_PARAMS_SIZE + SALT_SIZE + NONCE_SIZE;
+/// Number of bytes sealing adds on top of the plaintext.
+pub const OVERHEAD: usize = PREFIX_SIZE + TAG_SIZE;
+
+const KDF_LOG_N: u8 = 15;
+const KDF_R: u8 = 8;
+const KDF_P: u8 = 1;
+/// Largest cost an opener accepts, bounding the memory scrypt takes on
+/// untrusted input to `128 * KDF_R * 2^KDF_MAX_LOG_N` bytes, 1 GiB.
+const KDF_MAX_LOG_N: u8 = 20;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum OpenError {
+  /// The sealed blob is too short or carries unsupported KDF parameters.
+  Malformed,
+  /// The authentication tag does not match, i.e. the key is wrong.
+  Unauthenticated,
+}
+
+fn derive_key(password: &[u8], salt: &[u8], log_n: u8, r: u8, p: u8) -> [u8; 32] {
+  let params = scrypt::Params::new(log_n, r.into(), p.into(), 32)
+    .expect("scrypt parameters are checked before deriving a key");
+  let mut key = [0u8; 32];
+  scrypt::scrypt(password, salt, &params, &mut key).expect("the key length is valid");
+  key
+}
+
+/// Stretches a secret `key` into a seed with the scrypt parameters sealing
+/// uses, so that guessing a weak key costs as much as guessing a passphrase.
+/// `domain` salts the derivation, keeping seeds for different purposes apart.
+pub fn derive_seed(key: &[u8], domain: &[u8]) -> [u8; 32] {
+  derive_key(key, domain, KDF_LOG_N, KDF_R, KDF_P)
+}
+
+/// Encrypts `plaintext` under `password`.
+///
+/// Layout: `log_n | r | p | salt | nonce | ciphertext | tag`.
+pub fn seal(password: &[u8], plaintext: &[u8]) -> Vec<u8> {
+  let mut salt = [0u8; SALT_SIZE];
+  let mut nonce = [0u8; NONCE_SIZE];
+  getrandom::getrandom(&mut salt).expect("system random number generator is unavailable");
+  getrandom::getrandom(&mut nonce).expect("system random number generator is unavailable");
+  let key = derive_key(password, &salt, KDF_LOG_N, KDF_R, KDF_P);
+
+  let mut sealed = Vec::with_capacity(plaintext.len() + OVERHEAD);
+  sealed.extend_from_slice(&[KDF_LOG_N, KDF_R, KDF_P]);
+  sealed.extend_from_slice(&salt);
+  sealed.extend_from_slice(&nonce);
+  sealed.extend_from_slice(plaintext);
+  let tag = ChaCha20Poly1305::new(&key.into())
+    .encrypt_in_place_detached(&nonce.into(), &[], &mut sealed[PREFIX_SIZE..])
+    .expect("the plaintext fits in memory");
+  sealed.extend_from_slice(&tag);
+  sealed
+}
+
+/// Reverses [`seal`], discarding the plaintext unless the authentication tag
+/// matches.
+pub fn open(password: &[u8], sealed: &[u8]) -> Result<Vec<u8>, OpenError> {
+  if sealed.len() < OVERHEAD {
+    return Err(OpenError::Malformed);
+  }
+  let (prefix, rest) = sealed.split_at(PREFIX_SIZE);
+  let (ciphertext, tag) = rest.split_at(rest.len() - TAG_SIZE);
+  let (params, rest) = prefix.split_at(KDF_PARAMS_SIZE);
+  let (salt, nonce) = rest.split_at(SALT_SIZE);
+  let (log_n, r, p) = (params[0], params[1], params[2]);
+  // The parameters come from the image, so only those sealing writes are
+  // accepted rather than letting the image pick the cost of opening it.
+  if log_n == 0 || log_n > KDF_MAX_LOG_N || r != KDF_R || p != KDF_P {
+    return Err(OpenError::Malformed);
+  }
+  let key = derive_key(password, salt, log_n, r, p);
+  let mut plaintext = ciphertext.to_vec();
+  ChaCha20Poly1305::new(&key.into())
+    .decrypt_in_place_detached(nonce.into(), &[], &mut plaintext, tag.into())
+    .map_err(|_| OpenError::Unauthenticated)?;
+  Ok(plaintext)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn hex(text: &str) -> Vec<u8> {
+    (0..text.len())
+      .step_by(2)
+      .map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap())
+      .collect()
+  }
+
+  /// RFC 8439 §A.1, the first vector. Keyed orders depend on this stream, so
+  /// it must not change.
+  #[test]
+  fn rng_known_answer() {
+    let mut rng = ChaChaRng::new([0; 32]);
+    let words: Vec<u32> = (0..16).map(|_| rng.next_u32()).collect();
+    let block = hex(concat!(
+      "76b8e0ada0f13d90405d6ae55386bd28bdd219b8a08ded1aa836efcc8b770dc7",
+      "da41597c5157488d7724e03fb8d84a376a43b8f41518a11cc387b669b2ee6586",
+    ));
+    let expected: Vec<u32> = block
+      .chunks(4)
+      .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+      .collect();
+    assert_eq!(words, expected);
+  }
+
+  /// RFC 7914 §12, the first two vectors.
+  #[test]
+  fn scrypt_known_answers() {
+    let key = derive_key(b"", b"", 4, 1, 1);
+    assert_eq!(
+      key.to_vec(),
+      hex("77d6576238657b203b19ca42c18a0497f16b4844e3074ae8dfdffa3fede21442")
+    );
+    let key = derive_key(b"password", b"NaCl", 10, 8, 16);
+    assert_eq!(
+      key.to_vec(),
+      hex("fdbabe1c9d3472007856e7190d01e9fe7c6ad7cbc8237830e77376634b373162")
+    );
+  }
+
+  #[test]
+  fn open_reverses_seal() {
+    let sealed = seal(b"password", b"payload");
+    assert_eq!(sealed.len(), b"payload".len() + OVERHEAD);
+    assert_eq!(sealed[..KDF_PARAMS_SIZE], [KDF_LOG_N, KDF_R, KDF_P]);
+    assert_eq!(open(b"password", &sealed).unwrap(), b"payload");
+    assert_eq!(open(b"wrong", &sealed), Err(OpenError::Unauthenticated));
+    let mut tampered = sealed.clone();
+    tampered[PREFIX_SIZE] ^= 1;
+    assert_eq!(
+      open(b"password", &tampered),
+      Err(OpenError::Unauthenticated)
+    );
+  }
+
+  #[test]
+  fn open_rejects_costly_parameters() {
+    let mut sealed = seal(b"password", b"payload");
+    for params in [
+      [KDF_MAX_LOG_N + 1, KDF_R, KDF_P],
+      [KDF_LOG_N, 255, KDF_P],
+      [KDF_LOG_N, KDF_R, 255],
+      [0, KDF_R, KDF_P],
+    ] {
+      sealed[..KDF_PARAMS_SIZE].copy_from_slice(&params);
+      assert_eq!(open(b"password", &sealed), Err(OpenError::Malformed));
+    }
+  }
+}
@@ -0,0 +1,335 @@
+//! Reed-Solomon forward error correction over GF(2^8).
+//!
+//! The stream is split into equally sized blocks of at most
+//! `255 - parity` bytes, each extended with `parity` bytes of redundancy, which
+//! lets a block repair up to `parity / 2` corrupted bytes. Codewords are then
+//! interleaved byte by byte, so damage concentrated in one area of the image is
+//! spread over all blocks instead of exhausting one of them.
+
+const FIELD_SIZE: usize = 255;
+/// x^8 + x^4 + x^3 + x^2 + 1
+const PRIMITIVE: u16 = 0x11d;
+
+const fn build_tables() -> ([u8; 2 * FIELD_SIZE], [u8; FIELD_SIZE + 1]) {
+  let mut exp = [0u8; 2 * FIELD_SIZE];
+  let mut log = [0u8; FIELD_SIZE + 1];
+  let mut value: u16 = 1;
+  let mut i = 0;
+  while i < FIELD_SIZE {
+    exp[i] = value as u8;
+    exp[i + FIELD_SIZE] = value as u8;
+    log[value as usize] = i as u8;
+    value <<= 1;
+    if value & 0x100 != 0 {
+      value ^= PRIMITIVE;
+    }
+    i += 1;
+  }
+  (exp, log)
+}
+
+const TABLES: ([u8; 2 * FIELD_SIZE], [u8; FIELD_SIZE + 1]) = build_tables();
+const EXP: [u8; 2 * FIELD_SIZE] = TABLES.0;
+const LOG: [u8; FIELD_SIZE + 1] = TABLES.1;
+
+pub(crate) fn mul(a: u8, b: u8) -> u8 {
+  if a == 0 || b == 0 {
+    return 0;
+  }
+  EXP[LOG[a as usize] as usize + LOG[b as usize] as usize]
+}
+
+pub(crate) fn div(a: u8, b: u8) -> u8 {
+  if a == 0 {
+    return 0;
+  }
+  EXP[(LOG[a as usize] as usize + FIELD_SIZE - LOG[b as usize] as usize) % FIELD_SIZE]
+}
+
+fn alpha_pow(power: usize) -> u8 {
+  EXP[power % FIELD_SIZE]
+}
+
+/// Evaluates a polynomial stored lowest degree first.
+pub(crate) fn eval(poly: &[u8], x: u8) -> u8 {
+  poly.iter().rev().fold(0, |acc, &coef| mul(acc, x) ^ coef)
+}
+
+/// Generator polynomial `(x + a^0)(x + a^1)...(x + a^(parity - 1))`, lowest
+/// degree first.
+fn generator(parity: usize) -> Vec<u8> {
+  let mut generator = vec![1u8];
+  for i in 0..parity {
+    let root = alpha_pow(i);
+    let mut next = vec![0u8; generator.len() + 1];
+    for (j, &coef) in generator.iter().enumerate() {
+      next[j + 1] ^= coef;
+      next[j] ^= mul(coef, root);
+    }
+    generator = next;
+  }
+  generator
+}
+
+/// Appends `parity` bytes to `message`. The codeword stores the coefficient of
+/// the highest degree first.
+fn encode_block(message: &[u8], generator: &[u8], codeword: &mut Vec<u8>) {
+  let parity = generator.len() - 1;
+  let mut remainder = vec![0u8; parity];
+  for &byte in message {
+    let feedback = byte ^ remainder[parity - 1];
+    for j in (1..parity).rev() {
+      remainder[j] = remainder[j - 1] ^ mul(feedback, generator[j]);
+    }
+    remainder[0] = mul(feedback, generator[0]);
+  }
+  codeword.extend_from_slice(message);
+  codeword.extend(remainder.iter().rev());
+}
+
+fn syndromes(codeword: &[u8], parity: usize) -> Vec<u8> {
+  (0..parity)
+    .map(|i| {
+      let x = alpha_pow(i);
+      codeword.iter().fold(0, |acc, &byte| mul(acc, x) ^ byte)
+    })
+    .collect()
+}
+
+/// Corrects `codeword` in place, returning the number of repaired bytes or
+/// `None` if there are more errors than the block can correct.
+fn decode_block(codeword: &mut [u8], parity: usize) -> Option<usize> {
+  let syndromes = syndromes(codeword, parity);
+  if syndromes.iter().all(|&s| s == 0) {
+    return Some(0);
+  }
+
+  // Berlekamp-Massey: find the error locator polynomial.
+  let mut locator = vec![1u8];
+  let mut previous = vec![1u8];
+  let mut errors = 0;
+  let mut shift = 1;
+  let mut previous_discrepancy = 1u8;
+  for r in 0..parity {
+    let discrepancy = (1..=errors.min(locator.len() - 1)).fold(syndromes[r], |acc, i| {
+      acc ^ mul(locator[i], syndromes[r - i])
+    });
+    if discrepancy == 0 {
+      shift += 1;
+      continue;
+    }
+    let scale = div(discrepancy, previous_discrepancy);
+    let mut next = locator.clone();
+    next.resize(next.len().max(previous.len() + shift), 0);
+    for (i, &coef) in previous.iter().enumerate() {
+      next[i + shift] ^= mul(scale, coef);
+    }
+    if 2 * errors <= r {
+      previous = std::mem::replace(&mut locator, next);
+      errors = r + 1 - errors;
+      previous_discrepancy = discrepancy;
+      shift = 1;
+    } else {
+      locator = next;
+      shift += 1;
+    }
+  }
+  if 2 * errors > parity {
+    return None;
+  }
+
+  // Chien search: the roots of the locator are the inverses of the error
+  // positions.
+  let n = codeword.len();
+  let positions: Vec<usize> = (0..n)
+    .filter(|&j| eval(&locator, alpha_pow(FIELD_SIZE - (n - 1 - j) % FIELD_SIZE)) == 0)
+    .collect();
+  if positions.len() != errors {
+    return None;
+  }
+
+  // Forney: compute the error values from the evaluator polynomial.
+  let mut evaluator = vec![0u8; parity];
+  for (i, &s) in syndromes.iter().enumerate() {
+    for (j, &l) in locator.iter().enumerate().take(parity - i) {
+      evaluator[i + j] ^= mul(s, l);
+    }
+  }
+  let derivative: Vec<u8> = locator
+    .iter()
+    .enumerate()
+    .skip(1)
+    .map(|(i, &coef)| if i % 2 == 1 { coef } else { 0 })
+    .collect();
+  for &j in &positions {
+    let x = alpha_pow(n - 1 - j);
+    let x_inverse = div(1, x);
+    let denominator = eval(&derivative, x_inverse);
+    if denominator == 0 {
+      return None;
+    }
+    codeword[j] ^= mul(x, div(eval(&evaluator, x_inverse), denominator));
+  }
+
+  syndromes_are_zero(codeword, parity).then_some(errors)
+}
+
+fn syndromes_are_zero(codeword: &[u8], parity: usize) -> bool {
+  syndromes(codeword, parity).iter().all(|&s| s == 0)
+}
+
+/// Sizes of the message blocks `len` bytes are split into.
+fn block_sizes(len: usize, parity: u8) -> impl Iterator<Item = usize> {
+  let capacity = FIELD_SIZE - parity as usize;
+  let count = len.div_ceil(capacity);
+  let base = len.checked_div(count).unwrap_or_default();
+  let extra = len.checked_rem(count).unwrap_or_default();
+  (0..count).map(move |i| base + (i < extra) as usize)
+}
+
+/// Number of bytes `len` bytes of data occupy once encoded.
+pub fn encoded_len(len: usize, parity: u8) -> usize {
+  len + len.div_ceil(FIELD_SIZE - parity as usize) * parity as usize
+}
+
+/// Largest amount of data whose encoding fits into `encoded_capacity` bytes.
+pub fn data_capacity(encoded_capacity: usize, parity: u8) -> usize {
+  let full_blocks = encoded_capacity / FIELD_SIZE;
+  let rest = encoded_capacity % FIELD_SIZE;
+  full_blocks * (FIELD_SIZE - parity as usize) + rest.saturating_sub(parity as usize)
+}
+
+/// Positions of the codeword bytes in the interleaved stream, as
+/// `(block, offset)` pairs in stream order.
+fn interleaving(block_lens: &[usize]) -> impl Iterator<Item = (usize, usize)> + '_ {
+  let longest = block_lens.iter().copied().max().unwrap_or_default();
+  (0..longest).flat_map(move |offset| {
+    block_lens
+      .iter()
+      .enumerate()
+      .filter(move |(_, &len)| offset < len)
+      .map(move |(block, _)| (block, offset))
+  })
+}
+
+pub fn encode(data: &[u8], parity: u8) -> Vec<u8> {
+  let generator = generator(parity as usize);
+  let mut codewords = Vec::new();
+  let mut rest = data;
+  for size in block_sizes(data.len(), parity) {
+    let (message, tail) = rest.split_at(size);
+    let mut codeword = Vec::with_capacity(size + parity as usize);
+    encode_block(message, &generator, &mut codeword);
+    codewords.push(codeword);
+    rest = tail;
+  }
+  let block_lens: Vec<_> = codewords.iter().map(Vec::len).collect();
+  interleaving(&block_lens)
+    .map(|(block, offset)| codewords[block][offset])
+    .collect()
+}
+
+/// Reverses [`encode`] for `len` bytes of data, returning the repaired data and
+/// the number of corrected bytes, or `None` if some block is beyond repair.
+pub fn decode(encoded: &[u8], len: usize, parity: u8) -> Option<(Vec<u8>, usize)> {
+  let block_lens: Vec<_> = block_sizes(len, parity)
+    .map(|size| size + parity as usize)
+    .collect();
+  let mut codewords: Vec<Vec<u8>> = block_lens
+    .iter()
+    .map(|&len| Vec::with_capacity(len))
+    .collect();
+  for ((block, _), &byte) in interleaving(&block_lens).zip(encoded) {
+    codewords[block].push(byte);
+  }
+  let mut data = Vec::with_capacity(len);
+  let mut corrected = 0;
+  for mut codeword in codewords {
+    corrected += decode_block(&mut codeword, parity as usize)?;
+    data.extend_from_slice(&codeword[..codeword.len() - parity as usize]);
+  }
+  Some((data, corrected))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Deterministic bytes, distinct enough for error positions and values.
+  fn noise(seed: u32) -> impl Iterator<Item = u32> {
+    let mut state = seed;
+    std::iter::repeat_with(move || {
+      state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+      state >> 8
+    })
+  }
+
+  /// Flips `count` distinct bytes of a single-block encoding.
+  fn corrupt(encoded: &mut [u8], count: usize, seed: u32) {
+    let mut positions = Vec::new();
+    for value in noise(seed) {
+      let position = value as usize % encoded.len();
+      if !positions.contains(&position) {
+        positions.push(position);
+      }
+      if positions.len() == count {
+        break;
+      }
+    }
+    for (position, value) in positions.into_iter().zip(noise(!seed)) {
+      encoded[position] ^= (value % 255 + 1) as u8;
+    }
+  }
+
+  #[test]
+  fn corrects_up_to_half_the_parity() {
+    let data: Vec<u8> = noise(1).take(200).map(|value| value as u8).collect();
+    for parity in [2, 16, 32] {
+      let errors = usize::from(parity / 2);
+      for seed in 0..20 {
+        let mut encoded = encode(&data, parity);
+        assert_eq!(encoded.len(), encoded_len(data.len(), parity));
+        corrupt(&mut encoded, errors, seed);
+        assert_eq!(
+          decode(&encoded, data.len(), parity),
+          Some((data.clone(), errors))
+        );
+      }
+    }
+  }
+
+  #[test]
+  fn fails_beyond_half_the_parity() {
+    let data: Vec<u8> = noise(2).take(200).map(|value| value as u8).collect();
+    for parity in [16, 32] {
+      let errors = usize::from(parity / 2) + 1;
+      for seed in 0..20 {
+        let mut encoded = encode(&data, parity);
+        corrupt(&mut encoded, errors, seed);
+        assert_eq!(decode(&encoded, data.len(), parity), None);
+      }
+    }
+  }
+
+  #[test]
+  fn interleaving_spreads_bursts_over_blocks() {
+    let data: Vec<u8> = noise(3).take(1000).map(|value| value as u8).collect();
+    let mut encoded = encode(&data, 8);
+    // Five blocks of four correctable bytes each absorb a burst of twenty.
+    for byte in &mut encoded[100..120] {
+      *byte ^= 0xff;
+    }
+    assert_eq!(decode(&encoded, data.len(), 8), Some((data, 20)));
+  }
+
+  #[test]
+  fn data_capacity_inverts_encoded_len() {
+    for parity in [1, 8, 128] {
+      for capacity in 0..1000 {
+        let len = data_capacity(capacity, parity);
+        assert!(encoded_len(len, parity) <= capacity);
+        assert!(encoded_len(len + 1, parity) > capacity);
+      }
+    }
+  }
+}
@@ -0,0 +1,231 @@
+//! File envelope: a payload that remembers the file it came from.
+//!
+//! Layout (all integers little endian):
+//!
+//! | size | field                                              |
+//! |------|----------------------------------------------------|
+//! | 1    | envelope version                                   |
+//! | 2    | file name length `n`                               |
+//! | n    | file name, UTF-8                                   |
+//! | 1    | content type length `m`                            |
+//! | m    | content type, ASCII                                |
+//! | 1    | `1` if a modification time follows, `0` otherwise  |
+//! | 8    | modification time, seconds since the Unix epoch    |
+//! | 8    | file size                                          |
+//! | ...  | file contents                                      |
+
+use std::{
+  fs,
+  io::{self, Write},
+  path::{Component, Path, PathBuf},
+  time::{Duration, UNIX_EPOCH},
+};
+
+use crate::stego_image::{StegoError, StegoResult};
+
+const VERSION: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEnvelope {
+  pub name: String,
+  pub content_type: String,
+  /// Seconds since the Unix epoch.
+  pub modified: Option<u64>,
+  pub data: Vec<u8>,
+}
+
+impl FileEnvelope {
+  /// Reads the file at `path` together with its name and metadata.
+  pub fn from_path(path: &Path) -> io::Result<Self> {
+    let name = path
+      .file_name()
+      .and_then(|name| name.to_str())
+      .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "file name is not valid UTF-8"))?
+      .to_owned();
+    let modified = fs::metadata(path)?
+      .modified()
+      .ok()
+      .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+      .map(|since_epoch| since_epoch.as_secs());
+    Ok(Self {
+      content_type: content_type(&name).to_owned(),
+      name,
+      modified,
+      data: fs::read(path)?,
+    })
+  }
+
+  pub fn encode(&self) -> Vec<u8> {
+    let name = truncate_utf8(&self.name, u16::MAX as usize);
+    let content_type = truncate_utf8(&self.content_type, u8::MAX as usize);
+    let mut bytes = Vec::with_capacity(32 + name.len() + content_type.len() + self.data.len());
+    bytes.push(VERSION);
+    bytes.extend_from_slice(&(name.len() as u16).to_le_bytes());
+    bytes.extend_from_slice(name.as_bytes());
+    bytes.push(content_type.len() as u8);
+    bytes.extend_from_slice(content_type.as_bytes());
+    bytes.push(self.modified.is_some() as u8);
+    bytes.extend_from_slice(&self.modified.unwrap_or_default().to_le_bytes());
+    bytes.extend_from_slice(&(self.data.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(&self.data);
+    bytes
+  }
+
+  pub fn decode(bytes: &[u8]) -> StegoResult<Self> {
+    let mut reader = Reader(bytes);
+    if reader.take(1)?[0] != VERSION {
+      return Err(StegoError::InvalidEnvelope);
+    }
+    let name_len = u16::from_le_bytes(reader.array()?) as usize;
+    let name = reader.string(name_len)?;
+    let content_type_len = reader.take(1)?[0] as usize;
+    let content_type = reader.string(content_type_len)?;
+    let has_modified = reader.take(1)?[0] != 0;
+    let modified = u64::from_le_bytes(reader.array()?);
+    let size = u64::from_le_bytes(reader.array()?);
+    if size != reader.0.len() as u64 {
+      return Err(StegoError::InvalidEnvelope);
+    }
+    Ok(Self {
+      name,
+      content_type,
+      modified: has_modified.then_some(modified),
+      data: reader.0.to_vec(),
+    })
+  }
+
+  /// Writes the file into `dir` under its original name and restores its
+  /// modification time. Names that are not a single plain path component are
+  /// rejected, so a crafted envelope cannot write outside of `dir`, and so are
+  /// names already taken in `dir`, which could be symlinks pointing anywhere.
+  pub fn restore(&self, dir: &Path) -> io::Result<PathBuf> {
+    let path = dir.join(safe_file_name(&self.name)?);
+    let created = fs::File::options().write(true).create_new(true).open(&path);
+    let mut file = created.map_err(|err| match err.kind() {
+      io::ErrorKind::AlreadyExists => io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!("refusing to overwrite existing `{}`", path.display()),
+      ),
+      _ => err,
+    })?;
+    file.write_all(&self.data)?;
+    if let Some(modified) = self.modified {
+      file.set_modified(UNIX_EPOCH + Duration::from_secs(modified))?;
+    }
+    Ok(path)
+  }
+}
+
+fn safe_file_name(name: &str) -> io::Result<&Path> {
+  let path = Path::new(name);
+  let mut components = path.components();
+  let is_plain = matches!(components.next(), Some(Component::Normal(_)))
+    && components.next().is_none()
+    && !name.contains(['/', '\\', '\0']);
+  if !is_plain {
+    return Err(io::Error::new(
+      io::ErrorKind::InvalidData,
+      format!(
+        "refusing to restore file with unsafe name `{}`",
+        name.escape_debug()
+      ),
+    ));
+  }
+  Ok(path)
+}
+
+fn truncate_utf8(s: &str, max_len: usize) -> &str {
+  let mut end = s.len().min(max_len);
+  while !s.is_char_boundary(end) {
+    end -= 1;
+  }
+  &s[..end]
+}
+
+/// Guesses a MIME type from the extension of `name`.
+fn content_type(name: &str) -> &'static str {
+  let extension = Path::new(name)
+    .extension()
+    .and_then(|extension| extension.to_str())
+    .map(str::to_ascii_lowercase)
+    .unwrap_or_default();
+  match extension.as_str() {
+    "txt" | "log" | "md" => "text/plain",
+    "csv" => "text/csv",
+    "html" | "htm" => "text/html",
+    "json" => "application/json",
+    "xml" => "application/xml",
+    "toml" => "application/toml",
+    "yaml" | "yml" => "application/yaml",
+    "pem" | "key" | "crt" => "application/x-pem-file",
+    "pdf" => "application/pdf",
+    "zip" => "application/zip",
+    "gz" => "application/gzip",
+    "tar" => "application/x-tar",
+    "png" => "image/png",
+    "jpg" | "jpeg" => "image/jpeg",
+    "gif" => "image/gif",
+    _ => "application/octet-stream",
+  }
+}
+
+/// Cursor over the encoded envelope that fails with
+/// [`StegoError::InvalidEnvelope`] instead of reading past the end.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+  fn take(&mut self, len: usize) -> StegoResult<&'a [u8]> {
+    if self.0.len() < len {
+      return Err(StegoError::InvalidEnvelope);
+    }
+    let (taken, rest) = self.0.split_at(len);
+    self.0 = rest;
+    Ok(taken)
+  }
+
+  fn array<const N: usize>(&mut self) -> StegoResult<[u8; N]> {
+    Ok(self.take(N)?.try_into().unwrap())
+  }
+
+  fn string(&mut self, len: usize) -> StegoResult<String> {
+    String::from_utf8(self.take(len)?.to_vec()).map_err(|_| StegoError::InvalidEnvelope)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn restore_refuses_existing_paths() {
+    let dir = std::env::temp_dir().join(format!("rustego-envelope-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let file = FileEnvelope {
+      name: "note.txt".to_owned(),
+      content_type: "text/plain".to_owned(),
+      modified: Some(1_600_000_000),
+      data: b"payload".to_vec(),
+    };
+    let path = file.restore(&dir).unwrap();
+    assert_eq!(fs::read(&path).unwrap(), b"payload");
+
+    let err = file.restore(&dir).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+    #[cfg(unix)]
+    {
+      let target = dir.join("target");
+      fs::write(&target, b"keep").unwrap();
+      fs::remove_file(&path).unwrap();
+      std::os::unix::fs::symlink(&target, &path).unwrap();
+      assert!(file.restore(&dir).is_err());
+      assert_eq!(fs::read(&target).unwrap(), b"keep");
+    }
+    fs::remove_dir_all(&dir).unwrap();
+
+    let unsafe_name = FileEnvelope {
+      name: "../note.txt".to_owned(),
+      ..file
+    };
+    assert!(unsafe_name.restore(&dir).is_err());
+  }
+}
@@ -0,0 +1,485 @@
+//! Self-describing container header written in front of every payload.
+//!
+//! Layout (all integers little endian):
+//!
+//! | offset | size | field                                   |
+//! |--------|------|-----------------------------------------|
+//! | 0      | 4    | magic `RSTG`                            |
+//! | 4      | 1    | format version                          |
+//! | 5      | 1    | flags                                   |
+//! | 6      | 1    | bits per channel used by the payload    |
+//! | 7      | 1    | channel mask used by the payload        |
+//! | 8      | 1    | checksum kind                           |
+//! | 9      | 1    | Reed-Solomon parity bytes, 0 if none    |
+//! | 10     | 4    | shard set ID, zero unless sharded       |
+//! | 14     | 1    | shard index, zero unless sharded        |
+//! | 15     | 1    | shard count, zero unless sharded        |
+//! | 16     | 8    | payload length                          |
+//! | 24     | 1    | shares needed, zero unless shared       |
+//! | 25     | 1    | matrix embedding `k`, zero if none      |
+//! | 26     | 2    | adaptive texture threshold, 0 if none   |
+//! | 28     | 4    | CRC-32 of the preceding 28 bytes        |
+//!
+//! Error correction only covers the payload, so a header announcing it is
+//! followed by [`PROTECTED_COPIES`]` - 1` more copies of itself, which
+//! [`Header::decode_copies`] reads back by majority vote when the first copy
+//! is damaged.
+
+use std::{
+  collections::hash_map::DefaultHasher,
+  hash::{Hash, Hasher},
+  str::FromStr,
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::{
+  config::{ChannelMask, EmbedConfig},
+  ecc,
+  stego_image::{StegoError, StegoResult},
+};
+
+pub const MAGIC: [u8; 4] = *b"RSTG";
+pub const VERSION: u8 = 1;
+pub const HEADER_SIZE: usize = 32;
+/// Copies of a header announcing error correction.
+pub const PROTECTED_COPIES: usize = 5;
+
+const FLAG_COMPRESSED: u8 = 1 << 0;
+const FLAG_ENCRYPTED: u8 = 1 << 1;
+const FLAG_KEYED_ORDER: u8 = 1 << 2;
+const FLAG_ENVELOPE: u8 = 1 << 3;
+const FLAG_ARCHIVE: u8 = 1 << 4;
+const FLAG_DCT: u8 = 1 << 5;
+/// Set when the header describes a [`Shard`], kept out of [`Flags`] since it
+/// follows from [`Header::shard`].
+const FLAG_SHARDED: u8 = 1 << 6;
+const KNOWN_FLAGS: u8 = FLAG_COMPRESSED
+  | FLAG_ENCRYPTED
+  | FLAG_KEYED_ORDER
+  | FLAG_ENVELOPE
+  | FLAG_ARCHIVE
+  | FLAG_DCT
+  | FLAG_SHARDED;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Flags {
+  pub compressed: bool,
+  pub encrypted: bool,
+  pub keyed_order: bool,
+  /// The payload is a [`FileEnvelope`](crate::envelope::FileEnvelope).
+  pub envelope: bool,
+  /// The payload is an [`Archive`](crate::archive::Archive).
+  pub archive: bool,
+  /// The payload lives in the DCT coefficients of a JPEG, one bit per
+  /// coefficient, and the bits per channel and channel mask are unused.
+  pub dct: bool,
+}
+
+impl Flags {
+  fn to_byte(self) -> u8 {
+    let mut byte = 0;
+    for (set, flag) in [
+      (self.compressed, FLAG_COMPRESSED),
+      (self.encrypted, FLAG_ENCRYPTED),
+      (self.keyed_order, FLAG_KEYED_ORDER),
+      (self.envelope, FLAG_ENVELOPE),
+      (self.archive, FLAG_ARCHIVE),
+      (self.dct, FLAG_DCT),
+    ] {
+      if set {
+        byte |= flag;
+      }
+    }
+    byte
+  }
+
+  fn from_byte(byte: u8) -> StegoResult<Self> {
+    if byte & !KNOWN_FLAGS != 0 {
+      return Err(StegoError::UnsupportedHeader);
+    }
+    Ok(Self {
+      compressed: byte & FLAG_COMPRESSED != 0,
+      encrypted: byte & FLAG_ENCRYPTED != 0,
+      keyed_order: byte & FLAG_KEYED_ORDER != 0,
+      envelope: byte & FLAG_ENVELOPE != 0,
+      archive: byte & FLAG_ARCHIVE != 0,
+      dct: byte & FLAG_DCT != 0,
+    })
+  }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChecksumKind {
+  /// `std`'s `DefaultHasher`, as used by the original layout. Its algorithm is
+  /// unspecified and may change between Rust releases, so it is only kept to
+  /// read images written with it.
+  DefaultHasher = 0,
+  /// CRC-32 (IEEE 802.3), stored little endian.
+  #[default]
+  Crc32 = 1,
+  /// SHA-256 digest.
+  Sha256 = 2,
+}
+
+impl ChecksumKind {
+  /// Reads the checksum of a versioned header, which is never
+  /// [`ChecksumKind::DefaultHasher`].
+  fn from_byte(byte: u8) -> StegoResult<Self> {
+    match byte {
+      0 => Err(StegoError::InvalidHeader),
+      1 => Ok(ChecksumKind::Crc32),
+      2 => Ok(ChecksumKind::Sha256),
+      _ => Err(StegoError::UnsupportedHeader),
+    }
+  }
+
+  /// Number of bytes the checksum occupies in front of the payload.
+  pub fn size(self) -> usize {
+    match self {
+      ChecksumKind::DefaultHasher => std::mem::size_of::<u64>(),
+      ChecksumKind::Crc32 => std::mem::size_of::<u32>(),
+      ChecksumKind::Sha256 => 32,
+    }
+  }
+
+  pub fn compute(self, data: &[u8]) -> Vec<u8> {
+    let mut checksum = self.hasher();
+    checksum.update(data);
+    checksum.finalize()
+  }
+
+  /// Starts computing the checksum of data fed piece by piece.
+  pub fn hasher(self) -> Checksum {
+    Checksum(match self {
+      ChecksumKind::DefaultHasher => ChecksumState::DefaultHasher(DefaultHasher::new()),
+      ChecksumKind::Crc32 => ChecksumState::Crc32(crc32fast::Hasher::new()),
+      ChecksumKind::Sha256 => ChecksumState::Sha256(Sha256::new()),
+    })
+  }
+}
+
+/// Checksum being computed, see [`ChecksumKind::hasher`].
+pub struct Checksum(ChecksumState);
+
+enum ChecksumState {
+  DefaultHasher(DefaultHasher),
+  Crc32(crc32fast::Hasher),
+  Sha256(Sha256),
+}
+
+impl Checksum {
+  pub fn update(&mut self, data: &[u8]) {
+    match &mut self.0 {
+      ChecksumState::DefaultHasher(hasher) => {
+        for byte in data {
+          byte.hash(hasher);
+        }
+      }
+      ChecksumState::Crc32(hasher) => hasher.update(data),
+      ChecksumState::Sha256(hasher) => hasher.update(data),
+    }
+  }
+
+  pub fn finalize(self) -> Vec<u8> {
+    match self.0 {
+      ChecksumState::DefaultHasher(hasher) => hasher.finish().to_le_bytes().to_vec(),
+      ChecksumState::Crc32(hasher) => hasher.finalize().to_le_bytes().to_vec(),
+      ChecksumState::Sha256(hasher) => hasher.finalize().to_vec(),
+    }
+  }
+}
+
+impl FromStr for ChecksumKind {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "crc32" => Ok(ChecksumKind::Crc32),
+      "sha256" => Ok(ChecksumKind::Sha256),
+      _ => Err(format!(
+        "unknown checksum `{s}`, expected `crc32` or `sha256`"
+      )),
+    }
+  }
+}
+
+/// Place of a payload piece among the carriers a payload is spread over, see
+/// [`crate::shard`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shard {
+  /// Random identifier shared by all shards of one payload.
+  pub set_id: u32,
+  pub index: u8,
+  pub count: u8,
+  /// Shares needed to recover the payload when it is shared rather than
+  /// split with Shamir's secret sharing. Zero for a split payload.
+  pub threshold: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+  pub flags: Flags,
+  pub bits_per_channel: u8,
+  pub channel_mask: u8,
+  pub checksum: ChecksumKind,
+  pub ecc_parity: u8,
+  /// `k` of matrix embedding, zero if none.
+  pub matrix_embedding: u8,
+  /// Least texture of the pixels carrying the payload when it was embedded
+  /// adaptively, zero if all pixels carry it.
+  pub adaptive_threshold: u16,
+  pub payload_len: u64,
+  /// Set when the payload is one piece of a larger one.
+  pub shard: Option<Shard>,
+}
+
+impl Header {
+  /// Embedding configuration the payload was written with.
+  pub fn config(&self) -> StegoResult<EmbedConfig> {
+    ChannelMask::from_bits(self.channel_mask)
+      .and_then(|channels| EmbedConfig::new(self.bits_per_channel, channels).ok())
+      .and_then(|config| config.with_error_correction(self.ecc_parity).ok())
+      .and_then(|config| config.with_matrix_embedding(self.matrix_embedding).ok())
+      .map(|config| config.with_adaptive(self.adaptive_threshold > 0))
+      .ok_or(StegoError::UnsupportedHeader)
+  }
+
+  /// Copies of a header stored in front of a payload with `ecc_parity`
+  /// parity bytes.
+  pub fn copies(ecc_parity: u8) -> usize {
+    if ecc_parity > 0 {
+      PROTECTED_COPIES
+    } else {
+      1
+    }
+  }
+
+  /// Every copy of the header, see [`Self::copies`].
+  pub fn encode_copies(&self) -> Vec<u8> {
+    self.encode().repeat(Self::copies(self.ecc_parity))
+  }
+
+  pub fn encode(&self) -> [u8; HEADER_SIZE] {
+    let mut bytes = [0u8; HEADER_SIZE];
+    bytes[0..4].copy_from_slice(&MAGIC);
+    bytes[4] = VERSION;
+    bytes[5] = self.flags.to_byte();
+    if let Some(shard) = self.shard {
+      bytes[5] |= FLAG_SHARDED;
+      bytes[10..14].copy_from_slice(&shard.set_id.to_le_bytes());
+      bytes[14] = shard.index;
+      bytes[15] = shard.count;
+      bytes[24] = shard.threshold;
+    }
+    bytes[6] = self.bits_per_channel;
+    bytes[7] = self.channel_mask;
+    bytes[8] = self.checksum as u8;
+    bytes[9] = self.ecc_parity;
+    bytes[16..24].copy_from_slice(&self.payload_len.to_le_bytes());
+    bytes[25] = self.matrix_embedding;
+    bytes[26..28].copy_from_slice(&self.adaptive_threshold.to_le_bytes());
+    let crc = crc32fast::hash(&bytes[..HEADER_SIZE - 4]);
+    bytes[HEADER_SIZE - 4..].copy_from_slice(&crc.to_le_bytes());
+    bytes
+  }
+
+  /// Decodes a header, returning `Ok(None)` if `bytes` do not start with the
+  /// magic, i.e. the image was not written with a versioned header.
+  pub fn decode(bytes: &[u8; HEADER_SIZE]) -> StegoResult<Option<Self>> {
+    if bytes[0..4] != MAGIC {
+      return Ok(None);
+    }
+    let crc = u32::from_le_bytes(bytes[HEADER_SIZE - 4..].try_into().unwrap());
+    if crc32fast::hash(&bytes[..HEADER_SIZE - 4]) != crc {
+      return Err(StegoError::InvalidHeader);
+    }
+    if bytes[4] != VERSION {
+      return Err(StegoError::UnsupportedHeader);
+    }
+    let shard = if bytes[5] & FLAG_SHARDED != 0 {
+      let shard = Shard {
+        set_id: u32::from_le_bytes(bytes[10..14].try_into().unwrap()),
+        index: bytes[14],
+        count: bytes[15],
+        threshold: bytes[24],
+      };
+      if shard.index >= shard.count || shard.threshold > shard.count {
+        return Err(StegoError::InvalidHeader);
+      }
+      Some(shard)
+    } else if bytes[10..16]
+      .iter()
+      .chain(&bytes[24..25])
+      .any(|&byte| byte != 0)
+    {
+      return Err(StegoError::UnsupportedHeader);
+    } else {
+      None
+    };
+    Ok(Some(Self {
+      flags: Flags::from_byte(bytes[5] & !FLAG_SHARDED)?,
+      bits_per_channel: bytes[6],
+      channel_mask: bytes[7],
+      checksum: ChecksumKind::from_byte(bytes[8])?,
+      ecc_parity: bytes[9],
+      matrix_embedding: bytes[25],
+      adaptive_threshold: u16::from_le_bytes(bytes[26..28].try_into().unwrap()),
+      payload_len: u64::from_le_bytes(bytes[16..24].try_into().unwrap()),
+      shard,
+    }))
+  }
+
+  /// Recovers a header announcing error correction from its
+  /// [`PROTECTED_COPIES`] copies, given back to back, taking the majority of
+  /// every bit. `None` if no such header results.
+  pub fn decode_copies(copies: &[u8]) -> Option<Self> {
+    if copies.len() != PROTECTED_COPIES * HEADER_SIZE {
+      return None;
+    }
+    let mut bytes = [0u8; HEADER_SIZE];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+      for bit in 0..8 {
+        let votes = copies
+          .chunks_exact(HEADER_SIZE)
+          .filter(|copy| copy[i] >> bit & 1 == 1)
+          .count();
+        *byte |= u8::from(votes > PROTECTED_COPIES / 2) << bit;
+      }
+    }
+    Self::decode(&bytes)
+      .ok()
+      .flatten()
+      .filter(|header| header.ecc_parity > 0)
+  }
+
+  /// Decodes the first of the header copies read back to back into `copies`,
+  /// falling back to [`Self::decode_copies`] when it is damaged.
+  pub(crate) fn recover(copies: &[u8]) -> StegoResult<Option<Self>> {
+    match Self::decode(copies[..HEADER_SIZE].try_into().unwrap()) {
+      Ok(Some(header)) => Ok(Some(header)),
+      // A damaged header announcing error correction is recovered from its
+      // copies.
+      decoded => match Self::decode_copies(copies) {
+        Some(header) => Ok(Some(header)),
+        None => decoded,
+      },
+    }
+  }
+
+  /// Length of the payload, checked to fit along with its checksum into a
+  /// body of `capacity` bytes once error correction is taken off.
+  pub(crate) fn payload_size(&self, capacity: usize) -> StegoResult<usize> {
+    usize::try_from(self.payload_len)
+      .ok()
+      .filter(|&size| {
+        size
+          .checked_add(self.checksum.size())
+          .is_some_and(|len| len <= ecc::data_capacity(capacity, self.ecc_parity))
+      })
+      .ok_or(StegoError::InvalidDataLength)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn header(ecc_parity: u8) -> Header {
+    Header {
+      flags: Flags::default(),
+      bits_per_channel: 1,
+      channel_mask: 0b0111,
+      checksum: ChecksumKind::Crc32,
+      ecc_parity,
+      matrix_embedding: 0,
+      adaptive_threshold: 0,
+      payload_len: 1234,
+      shard: None,
+    }
+  }
+
+  #[test]
+  fn copies_outvote_damage() {
+    let header = header(16);
+    let mut copies = header.encode_copies();
+    assert_eq!(copies.len(), PROTECTED_COPIES * HEADER_SIZE);
+    // Every byte of the first copy and a different bit in each other copy.
+    for byte in &mut copies[..HEADER_SIZE] {
+      *byte ^= 0xff;
+    }
+    for (i, copy) in copies.chunks_exact_mut(HEADER_SIZE).enumerate().skip(1) {
+      copy[i] ^= 1 << i;
+    }
+    assert!(matches!(
+      Header::decode(copies[..HEADER_SIZE].try_into().unwrap()),
+      Ok(None)
+    ));
+    assert_eq!(Header::decode_copies(&copies), Some(header));
+  }
+
+  #[test]
+  fn only_protected_headers_have_copies() {
+    assert_eq!(header(0).encode_copies().len(), HEADER_SIZE);
+    let copies = header(0).encode().repeat(PROTECTED_COPIES);
+    assert_eq!(Header::decode_copies(&copies), None);
+  }
+
+  #[test]
+  fn versioned_headers_never_use_the_default_hasher() {
+    let header = Header {
+      checksum: ChecksumKind::DefaultHasher,
+      ..header(0)
+    };
+    assert!(matches!(
+      Header::decode(&header.encode()),
+      Err(StegoError::InvalidHeader)
+    ));
+  }
+
+  #[test]
+  fn headers_round_trip() {
+    let header = Header {
+      flags: Flags {
+        compressed: true,
+        encrypted: true,
+        keyed_order: true,
+        envelope: true,
+        ..Flags::default()
+      },
+      checksum: ChecksumKind::Sha256,
+      matrix_embedding: 3,
+      adaptive_threshold: 513,
+      payload_len: u64::MAX,
+      shard: Some(Shard {
+        set_id: 0xdead_beef,
+        index: 1,
+        count: 3,
+        threshold: 2,
+      }),
+      ..header(4)
+    };
+    assert_eq!(Header::decode(&header.encode()).unwrap(), Some(header));
+  }
+
+  #[test]
+  fn decode_tells_legacy_damaged_and_newer_headers_apart() {
+    assert_eq!(Header::decode(&[0; HEADER_SIZE]).unwrap(), None);
+
+    let mut bytes = header(0).encode();
+    bytes[16] ^= 1;
+    assert!(matches!(
+      Header::decode(&bytes),
+      Err(StegoError::InvalidHeader)
+    ));
+
+    let mut bytes = header(0).encode();
+    bytes[4] = VERSION + 1;
+    let crc = crc32fast::hash(&bytes[..HEADER_SIZE - 4]);
+    bytes[HEADER_SIZE - 4..].copy_from_slice(&crc.to_le_bytes());
+    assert!(matches!(
+      Header::decode(&bytes),
+      Err(StegoError::UnsupportedHeader)
+    ));
+  }
+}
@@ -0,0 +1,979 @@
+//! Baseline JPEG at the level of quantized DCT coefficients.
+//!
+//! Decoding stops after the entropy decoding step, so coefficients can be
+//! changed and written back without the rounding of an inverse DCT and another
+//! quantization. Only sequential Huffman-coded 8-bit files are understood;
+//! progressive, arithmetic-coded and 12-bit files are rejected by
+//! [`JpegImage::decode`].
+//!
+//! Written files keep the quantization tables, sampling factors and
+//! application segments of the original but always use the standard Huffman
+//! tables of Annex K and a single scan without restart markers.
+
+const SOI: u8 = 0xd8;
+const EOI: u8 = 0xd9;
+const SOF0: u8 = 0xc0;
+const SOF1: u8 = 0xc1;
+const DHT: u8 = 0xc4;
+const SOS: u8 = 0xda;
+const DQT: u8 = 0xdb;
+const DRI: u8 = 0xdd;
+const APP0: u8 = 0xe0;
+const APP15: u8 = 0xef;
+const COM: u8 = 0xfe;
+const RST0: u8 = 0xd0;
+const RST7: u8 = 0xd7;
+
+const BLOCK_SIZE: usize = 64;
+/// Channels of the pixels [`JpegImage::from_pixels`] takes.
+const RGBA_CHANNELS: usize = 4;
+/// Blocks an interleaved MCU may hold at most.
+const MAX_BLOCKS_PER_MCU: usize = 10;
+
+/// Natural (row-major) index of every zigzag position.
+#[rustfmt::skip]
+const UNZIGZAG: [usize; BLOCK_SIZE] = [
+   0,  1,  8, 16,  9,  2,  3, 10,
+  17, 24, 32, 25, 18, 11,  4,  5,
+  12, 19, 26, 33, 40, 48, 41, 34,
+  27, 20, 13,  6,  7, 14, 21, 28,
+  35, 42, 49, 56, 57, 50, 43, 36,
+  29, 22, 15, 23, 30, 37, 44, 51,
+  58, 59, 52, 45, 38, 31, 39, 46,
+  53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+/// Table K.1, natural order.
+#[rustfmt::skip]
+const LUMA_QUANTIZATION: [u16; BLOCK_SIZE] = [
+  16, 11, 10, 16,  24,  40,  51,  61,
+  12, 12, 14, 19,  26,  58,  60,  55,
+  14, 13, 16, 24,  40,  57,  69,  56,
+  14, 17, 22, 29,  51,  87,  80,  62,
+  18, 22, 37, 56,  68, 109, 103,  77,
+  24, 35, 55, 64,  81, 104, 113,  92,
+  49, 64, 78, 87, 103, 121, 120, 101,
+  72, 92, 95, 98, 112, 100, 103,  99,
+];
+
+/// Table K.2, natural order.
+#[rustfmt::skip]
+const CHROMA_QUANTIZATION: [u16; BLOCK_SIZE] = [
+  17, 18, 24, 47, 99, 99, 99, 99,
+  18, 21, 26, 66, 99, 99, 99, 99,
+  24, 26, 56, 99, 99, 99, 99, 99,
+  47, 66, 99, 99, 99, 99, 99, 99,
+  99, 99, 99, 99, 99, 99, 99, 99,
+  99, 99, 99, 99, 99, 99, 99, 99,
+  99, 99, 99, 99, 99, 99, 99, 99,
+  99, 99, 99, 99, 99, 99, 99, 99,
+];
+
+/// Huffman table as stored in a DHT segment: the number of codes of every
+/// length from 1 to 16 followed by the symbols in code order.
+struct HuffmanSpec {
+  counts: [u8; 16],
+  symbols: &'static [u8],
+}
+
+/// Table K.3.
+const LUMA_DC: HuffmanSpec = HuffmanSpec {
+  counts: [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0],
+  symbols: &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+};
+
+/// Table K.4.
+const CHROMA_DC: HuffmanSpec = HuffmanSpec {
+  counts: [0, 3, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0],
+  symbols: &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+};
+
+/// Table K.5.
+#[rustfmt::skip]
+const LUMA_AC: HuffmanSpec = HuffmanSpec {
+  counts: [0, 2, 1, 3, 3, 2, 4, 3, 5, 5, 4, 4, 0, 0, 1, 0x7d],
+  symbols: &[
+    0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61, 0x07,
+    0x22, 0x71, 0x14, 0x32, 0x81, 0x91, 0xa1, 0x08, 0x23, 0x42, 0xb1, 0xc1, 0x15, 0x52, 0xd1, 0xf0,
+    0x24, 0x33, 0x62, 0x72, 0x82, 0x09, 0x0a, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x25, 0x26, 0x27, 0x28,
+    0x29, 0x2a, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49,
+    0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69,
+    0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89,
+    0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7,
+    0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3, 0xc4, 0xc5,
+    0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda, 0xe1, 0xe2,
+    0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea, 0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8,
+    0xf9, 0xfa,
+  ],
+};
+
+/// Table K.6.
+#[rustfmt::skip]
+const CHROMA_AC: HuffmanSpec = HuffmanSpec {
+  counts: [0, 2, 1, 2, 4, 4, 3, 4, 7, 5, 4, 4, 0, 1, 2, 0x77],
+  symbols: &[
+    0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41, 0x51, 0x07, 0x61, 0x71,
+    0x13, 0x22, 0x32, 0x81, 0x08, 0x14, 0x42, 0x91, 0xa1, 0xb1, 0xc1, 0x09, 0x23, 0x33, 0x52, 0xf0,
+    0x15, 0x62, 0x72, 0xd1, 0x0a, 0x16, 0x24, 0x34, 0xe1, 0x25, 0xf1, 0x17, 0x18, 0x19, 0x1a, 0x26,
+    0x27, 0x28, 0x29, 0x2a, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48,
+    0x49, 0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68,
+    0x69, 0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87,
+    0x88, 0x89, 0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0xa2, 0xa3, 0xa4, 0xa5,
+    0xa6, 0xa7, 0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3,
+    0xc4, 0xc5, 0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda,
+    0xe2, 0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8,
+    0xf9, 0xfa,
+  ],
+};
+
+/// Returns whether `bytes` start with a JPEG start-of-image marker.
+pub fn is_jpeg(bytes: &[u8]) -> bool {
+  bytes.starts_with(&[0xff, SOI, 0xff])
+}
+
+pub struct Component {
+  id: u8,
+  horizontal: usize,
+  vertical: usize,
+  quantization: u8,
+  blocks_wide: usize,
+  blocks_high: usize,
+  /// Quantized coefficients, zigzag order, covering whole MCUs.
+  blocks: Vec<[i16; BLOCK_SIZE]>,
+}
+
+/// Segment copied verbatim from the original file, such as EXIF or ICC data.
+struct Segment {
+  marker: u8,
+  data: Vec<u8>,
+}
+
+pub struct JpegImage {
+  width: usize,
+  height: usize,
+  components: Vec<Component>,
+  /// Quantization tables in zigzag order, by destination.
+  quantization: [Option<[u16; BLOCK_SIZE]>; 4],
+  segments: Vec<Segment>,
+}
+
+impl JpegImage {
+  /// Parses a baseline or extended sequential Huffman-coded JPEG, returning
+  /// `None` if it is malformed or uses a coding process that is not supported.
+  pub fn decode(bytes: &[u8]) -> Option<Self> {
+    let mut reader = Reader(bytes);
+    if reader.take(2)? != [0xff, SOI] {
+      return None;
+    }
+    let mut image: Option<Self> = None;
+    let mut segments = Vec::new();
+    let mut quantization = [None; 4];
+    let mut dc_tables: [Option<HuffmanDecoder>; 4] = Default::default();
+    let mut ac_tables: [Option<HuffmanDecoder>; 4] = Default::default();
+    let mut restart_interval = 0;
+    let mut decoded_scan = false;
+    loop {
+      if reader.byte()? != 0xff {
+        return None;
+      }
+      let mut marker = reader.byte()?;
+      while marker == 0xff {
+        marker = reader.byte()?;
+      }
+      if marker == EOI {
+        break;
+      }
+      let len = u16::from_be_bytes(reader.array()?) as usize;
+      let mut segment = Reader(reader.take(len.checked_sub(2)?)?);
+      match marker {
+        SOF0 | SOF1 => image = Some(Self::parse_frame(&mut segment)?),
+        DHT => {
+          while !segment.0.is_empty() {
+            let class_and_id = segment.byte()?;
+            let counts: [u8; 16] = segment.array()?;
+            let total = counts.iter().map(|&count| count as usize).sum();
+            let decoder = HuffmanDecoder::new(&counts, segment.take(total)?.to_vec())?;
+            let tables = match class_and_id >> 4 {
+              0 => &mut dc_tables,
+              1 => &mut ac_tables,
+              _ => return None,
+            };
+            *tables.get_mut(class_and_id as usize & 0xf)? = Some(decoder);
+          }
+        }
+        DQT => {
+          while !segment.0.is_empty() {
+            let precision_and_id = segment.byte()?;
+            let mut table = [0u16; BLOCK_SIZE];
+            for value in &mut table {
+              *value = match precision_and_id >> 4 {
+                0 => segment.byte()? as u16,
+                1 => u16::from_be_bytes(segment.array()?),
+                _ => return None,
+              };
+            }
+            *quantization.get_mut(precision_and_id as usize & 0xf)? = Some(table);
+          }
+        }
+        DRI => restart_interval = u16::from_be_bytes(segment.array()?) as usize,
+        SOS => {
+          let image = image.as_mut()?;
+          let scan = image.parse_scan(&mut segment, &dc_tables, &ac_tables)?;
+          let entropy_len = entropy_coded_len(reader.0);
+          let entropy = reader.take(entropy_len)?;
+          image.decode_scan(&scan, entropy, restart_interval)?;
+          decoded_scan = true;
+        }
+        APP0..=APP15 | COM => segments.push(Segment {
+          marker,
+          data: segment.0.to_vec(),
+        }),
+        // Any other frame type: progressive, lossless, arithmetic coding.
+        0xc2..=0xcf => return None,
+        _ => {}
+      }
+    }
+    let mut image = image.filter(|_| decoded_scan)?;
+    if image
+      .components
+      .iter()
+      .any(|component| quantization[component.quantization as usize].is_none())
+    {
+      return None;
+    }
+    image.quantization = quantization;
+    image.segments = segments;
+    Some(image)
+  }
+
+  fn parse_frame(segment: &mut Reader) -> Option<Self> {
+    let precision = segment.byte()?;
+    let height = u16::from_be_bytes(segment.array()?) as usize;
+    let width = u16::from_be_bytes(segment.array()?) as usize;
+    let count = segment.byte()? as usize;
+    if precision != 8 || width == 0 || height == 0 || !(1..=4).contains(&count) {
+      return None;
+    }
+    let mut components = Vec::with_capacity(count);
+    for _ in 0..count {
+      let [id, sampling, quantization] = segment.array()?;
+      let (horizontal, vertical) = ((sampling >> 4) as usize, (sampling & 0xf) as usize);
+      if !(1..=4).contains(&horizontal) || !(1..=4).contains(&vertical) || quantization > 3 {
+        return None;
+      }
+      components.push(Component {
+        id,
+        horizontal,
+        vertical,
+        quantization,
+        blocks_wide: 0,
+        blocks_high: 0,
+        blocks: Vec::new(),
+      });
+    }
+    let blocks_per_mcu: usize = components.iter().map(|c| c.horizontal * c.vertical).sum();
+    if count > 1 && blocks_per_mcu > MAX_BLOCKS_PER_MCU {
+      return None;
+    }
+    let mut image = Self {
+      width,
+      height,
+      components,
+      quantization: [None; 4],
+      segments: Vec::new(),
+    };
+    image.allocate_blocks();
+    Some(image)
+  }
+
+  /// Sizes the block grid of every component to cover whole MCUs.
+  fn allocate_blocks(&mut self) {
+    let (mcus_wide, mcus_high) = self.mcus();
+    for component in &mut self.components {
+      component.blocks_wide = mcus_wide * component.horizontal;
+      component.blocks_high = mcus_high * component.vertical;
+      component.blocks = vec![[0; BLOCK_SIZE]; component.blocks_wide * component.blocks_high];
+    }
+  }
+
+  fn max_sampling(&self) -> (usize, usize) {
+    let horizontal = self.components.iter().map(|c| c.horizontal).max();
+    let vertical = self.components.iter().map(|c| c.vertical).max();
+    (horizontal.unwrap_or(1), vertical.unwrap_or(1))
+  }
+
+  /// Number of MCUs across and down an interleaved scan.
+  fn mcus(&self) -> (usize, usize) {
+    let (horizontal, vertical) = self.max_sampling();
+    (
+      self.width.div_ceil(8 * horizontal),
+      self.height.div_ceil(8 * vertical),
+    )
+  }
+
+  fn parse_scan(
+    &self,
+    segment: &mut Reader,
+    dc_tables: &[Option<HuffmanDecoder>; 4],
+    ac_tables: &[Option<HuffmanDecoder>; 4],
+  ) -> Option<Vec<ScanComponent>> {
+    let count = segment.byte()? as usize;
+    let mut scan = Vec::with_capacity(count);
+    for _ in 0..count {
+      let [id, tables] = segment.array()?;
+      let index = self.components.iter().position(|c| c.id == id)?;
+      let dc = dc_tables.get(tables as usize >> 4)?.clone()?;
+      let ac = ac_tables.get(tables as usize & 0xf)?.clone()?;
+      scan.push(ScanComponent { index, dc, ac });
+    }
+    // Spectral selection and successive approximation of a sequential scan.
+    if segment.array()? != [0, 63, 0] || scan.is_empty() {
+      return None;
+    }
+    Some(scan)
+  }
+
+  /// Lists the blocks of a scan over `components` in coding order as
+  /// `(component, block)` pairs, along with the number of blocks in an MCU.
+  fn scan_blocks(&self, components: &[usize]) -> (Vec<(usize, usize)>, usize) {
+    if let [index] = *components {
+      // A single component is coded block by block, without MCU padding.
+      let (max_horizontal, max_vertical) = self.max_sampling();
+      let component = &self.components[index];
+      let wide = (self.width * component.horizontal)
+        .div_ceil(max_horizontal)
+        .div_ceil(8);
+      let high = (self.height * component.vertical)
+        .div_ceil(max_vertical)
+        .div_ceil(8);
+      let blocks = (0..high)
+        .flat_map(|row| (0..wide).map(move |col| (index, row * component.blocks_wide + col)))
+        .collect();
+      return (blocks, 1);
+    }
+    let (mcus_wide, mcus_high) = self.mcus();
+    let mut blocks = Vec::new();
+    for mcu_row in 0..mcus_high {
+      for mcu_col in 0..mcus_wide {
+        for &index in components {
+          let component = &self.components[index];
+          for v in 0..component.vertical {
+            for h in 0..component.horizontal {
+              let row = mcu_row * component.vertical + v;
+              let col = mcu_col * component.horizontal + h;
+              blocks.push((index, row * component.blocks_wide + col));
+            }
+          }
+        }
+      }
+    }
+    let per_mcu = components
+      .iter()
+      .map(|&index| self.components[index].horizontal * self.components[index].vertical)
+      .sum();
+    (blocks, per_mcu)
+  }
+
+  fn decode_scan(
+    &mut self,
+    scan: &[ScanComponent],
+    entropy: &[u8],
+    restart_interval: usize,
+  ) -> Option<()> {
+    let indices: Vec<_> = scan.iter().map(|c| c.index).collect();
+    let (blocks, per_mcu) = self.scan_blocks(&indices);
+    let mut reader = BitReader::new(entropy);
+    let mut predictions = vec![0i16; self.components.len()];
+    for (position, &(index, block)) in blocks.iter().enumerate() {
+      if restart_interval > 0 && position > 0 && position % (restart_interval * per_mcu) == 0 {
+        reader.restart()?;
+        predictions.fill(0);
+      }
+      let tables = scan.iter().find(|c| c.index == index)?;
+      let coefficients = &mut self.components[index].blocks[block];
+      let size = tables.dc.decode(&mut reader)?;
+      if size > 11 {
+        return None;
+      }
+      predictions[index] = predictions[index].wrapping_add(reader.receive(size));
+      coefficients[0] = predictions[index];
+      let mut k = 1;
+      while k < BLOCK_SIZE {
+        let symbol = tables.ac.decode(&mut reader)?;
+        let (run, size) = ((symbol >> 4) as usize, symbol & 0xf);
+        if size == 0 {
+          if run != 15 {
+            break;
+          }
+          k += 16;
+          continue;
+        }
+        k += run;
+        *coefficients.get_mut(k)? = reader.receive(size);
+        k += 1;
+      }
+      if k > BLOCK_SIZE {
+        return None;
+      }
+    }
+    Some(())
+  }
+
+  /// Compresses `rgba` pixels, alpha being dropped, into a colour JPEG with
+  /// the quantization tables of Annex K scaled to `quality` (1 to 100) the
+  /// way the IJG encoder does.
+  pub fn from_pixels(width: usize, height: usize, rgba: &[u8], quality: u8) -> Self {
+    let quality = quality.clamp(1, 100) as u32;
+    let scale = if quality < 50 {
+      5000 / quality
+    } else {
+      200 - 2 * quality
+    };
+    let scaled = |table: &[u16; BLOCK_SIZE]| {
+      let mut zigzag = [0u16; BLOCK_SIZE];
+      for (value, &natural) in zigzag.iter_mut().zip(&UNZIGZAG) {
+        *value = ((table[natural] as u32 * scale + 50) / 100).clamp(1, 255) as u16;
+      }
+      zigzag
+    };
+    let mut image = Self {
+      width,
+      height,
+      components: (0..3u8)
+        .map(|i| Component {
+          id: i + 1,
+          horizontal: 1,
+          vertical: 1,
+          quantization: (i > 0) as u8,
+          blocks_wide: 0,
+          blocks_high: 0,
+          blocks: Vec::new(),
+        })
+        .collect(),
+      quantization: [
+        Some(scaled(&LUMA_QUANTIZATION)),
+        Some(scaled(&CHROMA_QUANTIZATION)),
+        None,
+        None,
+      ],
+      segments: vec![Segment {
+        marker: APP0,
+        data: b"JFIF\0\x01\x01\0\0\x01\0\x01\0\0".to_vec(),
+      }],
+    };
+    image.allocate_blocks();
+
+    let cosines = cosine_table();
+    let (blocks_wide, blocks_high) = (
+      image.components[0].blocks_wide,
+      image.components[0].blocks_high,
+    );
+    let mut samples = [[0f32; BLOCK_SIZE]; 3];
+    for block_row in 0..blocks_high {
+      for block_col in 0..blocks_wide {
+        for i in 0..BLOCK_SIZE {
+          let x = (block_col * 8 + i % 8).min(width - 1);
+          let y = (block_row * 8 + i / 8).min(height - 1);
+          let pixel = &rgba[(y * width + x) * RGBA_CHANNELS..][..3];
+          for (component, value) in samples.iter_mut().zip(ycbcr(pixel[0], pixel[1], pixel[2])) {
+            component[i] = value - 128.0;
+          }
+        }
+        for (component, samples) in image.components.iter_mut().zip(&samples) {
+          let table = image.quantization[component.quantization as usize].unwrap();
+          let coefficients = forward_dct(samples, &cosines);
+          let block = &mut component.blocks[block_row * blocks_wide + block_col];
+          for (k, &natural) in UNZIGZAG.iter().enumerate() {
+            let quantized = (coefficients[natural] / table[k] as f32).round();
+            block[k] = quantized.clamp(-1023.0, 1023.0) as i16;
+          }
+        }
+      }
+    }
+    image
+  }
+
+  /// Every AC coefficient, component by component in block order.
+  pub fn ac_coefficients(&self) -> impl Iterator<Item = i16> + '_ {
+    self
+      .components
+      .iter()
+      .flat_map(|component| &component.blocks)
+      .flat_map(|block| block[1..].iter().copied())
+  }
+
+  /// Mutable access to the coefficients yielded by [`Self::ac_coefficients`].
+  pub fn ac_coefficients_mut(&mut self) -> impl Iterator<Item = &mut i16> {
+    self
+      .components
+      .iter_mut()
+      .flat_map(|component| &mut component.blocks)
+      .flat_map(|block| &mut block[1..])
+  }
+
+  pub fn encode(&self) -> Vec<u8> {
+    let mut out = vec![0xff, SOI];
+    for segment in &self.segments {
+      write_segment(&mut out, segment.marker, &segment.data);
+    }
+
+    let mut extended = false;
+    for (id, table) in self.quantization.iter().enumerate() {
+      let Some(table) = table else {
+        continue;
+      };
+      if !self
+        .components
+        .iter()
+        .any(|c| c.quantization as usize == id)
+      {
+        continue;
+      }
+      let wide = table.iter().any(|&value| value > 255);
+      extended |= wide;
+      let mut data = vec![(wide as u8) << 4 | id as u8];
+      for &value in table {
+        if wide {
+          data.extend_from_slice(&value.to_be_bytes());
+        } else {
+          data.push(value as u8);
+        }
+      }
+      write_segment(&mut out, DQT, &data);
+    }
+
+    let mut frame = vec![8];
+    frame.extend_from_slice(&(self.height as u16).to_be_bytes());
+    frame.extend_from_slice(&(self.width as u16).to_be_bytes());
+    frame.push(self.components.len() as u8);
+    for component in &self.components {
+      frame.push(component.id);
+      frame.push((component.horizontal << 4 | component.vertical) as u8);
+      frame.push(component.quantization);
+    }
+    write_segment(&mut out, if extended { SOF1 } else { SOF0 }, &frame);
+
+    let specs = [
+      (0x00, &LUMA_DC),
+      (0x10, &LUMA_AC),
+      (0x01, &CHROMA_DC),
+      (0x11, &CHROMA_AC),
+    ];
+    let mut tables = Vec::new();
+    for (class_and_id, spec) in specs
+      .iter()
+      .take(if self.components.len() > 1 { 4 } else { 2 })
+    {
+      tables.push(*class_and_id);
+      tables.extend_from_slice(&spec.counts);
+      tables.extend_from_slice(spec.symbols);
+    }
+    write_segment(&mut out, DHT, &tables);
+
+    let mut scan = vec![self.components.len() as u8];
+    for (index, component) in self.components.iter().enumerate() {
+      scan.push(component.id);
+      scan.push(if index == 0 { 0x00 } else { 0x11 });
+    }
+    scan.extend_from_slice(&[0, 63, 0]);
+    write_segment(&mut out, SOS, &scan);
+
+    let luma = (HuffmanEncoder::new(&LUMA_DC), HuffmanEncoder::new(&LUMA_AC));
+    let chroma = (
+      HuffmanEncoder::new(&CHROMA_DC),
+      HuffmanEncoder::new(&CHROMA_AC),
+    );
+    let indices: Vec<_> = (0..self.components.len()).collect();
+    let (blocks, _) = self.scan_blocks(&indices);
+    let mut writer = BitWriter::new(out);
+    let mut predictions = vec![0i16; self.components.len()];
+    for (index, block) in blocks {
+      let (dc, ac) = if index == 0 { &luma } else { &chroma };
+      let coefficients = &self.components[index].blocks[block];
+      let difference = coefficients[0].wrapping_sub(predictions[index]);
+      predictions[index] = coefficients[0];
+      let (size, bits) = magnitude(difference);
+      dc.write(&mut writer, size);
+      writer.write(bits, size);
+      let mut run = 0;
+      for &coefficient in &coefficients[1..] {
+        if coefficient == 0 {
+          run += 1;
+          continue;
+        }
+        while run > 15 {
+          ac.write(&mut writer, 0xf0);
+          run -= 16;
+        }
+        let (size, bits) = magnitude(coefficient);
+        ac.write(&mut writer, run << 4 | size);
+        writer.write(bits, size);
+        run = 0;
+      }
+      if run > 0 {
+        ac.write(&mut writer, 0x00);
+      }
+    }
+    let mut out = writer.finish();
+    out.extend_from_slice(&[0xff, EOI]);
+    out
+  }
+}
+
+struct ScanComponent {
+  index: usize,
+  dc: HuffmanDecoder,
+  ac: HuffmanDecoder,
+}
+
+fn write_segment(out: &mut Vec<u8>, marker: u8, data: &[u8]) {
+  out.extend_from_slice(&[0xff, marker]);
+  out.extend_from_slice(&(data.len() as u16 + 2).to_be_bytes());
+  out.extend_from_slice(data);
+}
+
+/// Length of the entropy-coded data at the start of `bytes`, which runs up to
+/// the first marker other than a stuffed zero byte or a restart marker.
+fn entropy_coded_len(bytes: &[u8]) -> usize {
+  let mut pos = 0;
+  while pos + 1 < bytes.len() {
+    if bytes[pos] == 0xff && !matches!(bytes[pos + 1], 0x00 | RST0..=RST7) {
+      return pos;
+    }
+    pos += 1;
+  }
+  bytes.len()
+}
+
+/// Size category of `value` and its low bits as written after a Huffman code,
+/// negative values being stored as the one's complement of their magnitude.
+fn magnitude(value: i16) -> (u8, u16) {
+  let size = 16 - value.unsigned_abs().leading_zeros() as u8;
+  let bits = if value < 0 { value - 1 } else { value } as u16;
+  (size, bits & ((1u32 << size) - 1) as u16)
+}
+
+fn ycbcr(r: u8, g: u8, b: u8) -> [f32; 3] {
+  let (r, g, b) = (r as f32, g as f32, b as f32);
+  [
+    0.299 * r + 0.587 * g + 0.114 * b,
+    -0.168_736 * r - 0.331_264 * g + 0.5 * b + 128.0,
+    0.5 * r - 0.418_688 * g - 0.081_312 * b + 128.0,
+  ]
+}
+
+/// `cos((2x + 1) u pi / 16)` indexed by `[u][x]`.
+fn cosine_table() -> [[f32; 8]; 8] {
+  let mut table = [[0f32; 8]; 8];
+  for (u, row) in table.iter_mut().enumerate() {
+    for (x, value) in row.iter_mut().enumerate() {
+      *value = ((2 * x + 1) as f32 * u as f32 * std::f32::consts::PI / 16.0).cos();
+    }
+  }
+  table
+}
+
+/// Two-dimensional DCT-II of a block in natural order.
+fn forward_dct(samples: &[f32; BLOCK_SIZE], cosines: &[[f32; 8]; 8]) -> [f32; BLOCK_SIZE] {
+  let normalize = |u: usize| {
+    if u == 0 {
+      std::f32::consts::FRAC_1_SQRT_2
+    } else {
+      1.0
+    }
+  };
+  let mut rows = [0f32; BLOCK_SIZE];
+  for y in 0..8 {
+    for u in 0..8 {
+      rows[y * 8 + u] = (0..8).map(|x| samples[y * 8 + x] * cosines[u][x]).sum();
+    }
+  }
+  let mut coefficients = [0f32; BLOCK_SIZE];
+  for v in 0..8 {
+    for u in 0..8 {
+      let sum: f32 = (0..8).map(|y| rows[y * 8 + u] * cosines[v][y]).sum();
+      coefficients[v * 8 + u] = 0.25 * normalize(u) * normalize(v) * sum;
+    }
+  }
+  coefficients
+}
+
+/// Cursor over segment data that returns `None` instead of reading past the
+/// end.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+  fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+    if self.0.len() < len {
+      return None;
+    }
+    let (taken, rest) = self.0.split_at(len);
+    self.0 = rest;
+    Some(taken)
+  }
+
+  fn byte(&mut self) -> Option<u8> {
+    Some(self.take(1)?[0])
+  }
+
+  fn array<const N: usize>(&mut self) -> Option<[u8; N]> {
+    Some(self.take(N)?.try_into().unwrap())
+  }
+}
+
+/// Canonical Huffman decoder following figure F.16 of the specification.
+#[derive(Clone)]
+struct HuffmanDecoder {
+  /// Largest code of every length, or -1 if there is none.
+  max_code: [i32; 17],
+  /// Offset from a code of every length to the index of its symbol.
+  offsets: [i32; 17],
+  symbols: Vec<u8>,
+}
+
+impl HuffmanDecoder {
+  fn new(counts: &[u8; 16], symbols: Vec<u8>) -> Option<Self> {
+    let mut max_code = [-1; 17];
+    let mut offsets = [0; 17];
+    let mut code = 0;
+    let mut index = 0;
+    for len in 1..=16 {
+      let count = counts[len - 1] as i32;
+      offsets[len] = index - code;
+      code += count;
+      index += count;
+      if count > 0 {
+        max_code[len] = code - 1;
+      }
+      if code > 1 << len {
+        return None;
+      }
+      code <<= 1;
+    }
+    Some(Self {
+      max_code,
+      offsets,
+      symbols,
+    })
+  }
+
+  fn decode(&self, reader: &mut BitReader) -> Option<u8> {
+    let mut code = 0;
+    for len in 1..=16 {
+      code = code << 1 | reader.bit() as i32;
+      if code <= self.max_code[len] {
+        return self
+          .symbols
+          .get((code + self.offsets[len]) as usize)
+          .copied();
+      }
+    }
+    None
+  }
+}
+
+struct HuffmanEncoder {
+  /// Code and length of every symbol.
+  codes: [(u16, u8); 256],
+}
+
+impl HuffmanEncoder {
+  fn new(spec: &HuffmanSpec) -> Self {
+    let mut codes = [(0, 0); 256];
+    let mut code = 0u16;
+    let mut symbols = spec.symbols.iter();
+    for (len, &count) in (1..=16).zip(&spec.counts) {
+      for symbol in symbols.by_ref().take(count as usize) {
+        codes[*symbol as usize] = (code, len);
+        code += 1;
+      }
+      code <<= 1;
+    }
+    Self { codes }
+  }
+
+  fn write(&self, writer: &mut BitWriter, symbol: u8) {
+    let (code, len) = self.codes[symbol as usize];
+    writer.write(code, len);
+  }
+}
+
+/// Reads entropy-coded data most significant bit first, dropping the zero
+/// bytes stuffed after `0xff`.
+struct BitReader<'a> {
+  data: &'a [u8],
+  pos: usize,
+  current: u8,
+  bits_left: u8,
+}
+
+impl<'a> BitReader<'a> {
+  fn new(data: &'a [u8]) -> Self {
+    Self {
+      data,
+      pos: 0,
+      current: 0,
+      bits_left: 0,
+    }
+  }
+
+  /// Returns the next bit, or zeros once a marker or the end of the data is
+  /// reached.
+  fn bit(&mut self) -> u8 {
+    if self.bits_left == 0 {
+      self.current = match self.data.get(self.pos..self.pos + 2) {
+        Some([0xff, 0x00]) => {
+          self.pos += 2;
+          0xff
+        }
+        Some([0xff, _]) => 0,
+        _ => match self.data.get(self.pos) {
+          Some(&byte) if byte != 0xff => {
+            self.pos += 1;
+            byte
+          }
+          _ => 0,
+        },
+      };
+      self.bits_left = 8;
+    }
+    self.bits_left -= 1;
+    self.current >> self.bits_left & 1
+  }
+
+  /// Reads a `size`-bit value and extends it to its signed value.
+  fn receive(&mut self, size: u8) -> i16 {
+    let mut value = 0i32;
+    for _ in 0..size {
+      value = value << 1 | self.bit() as i32;
+    }
+    if size > 0 && value < 1 << (size - 1) {
+      value += 1 - (1 << size);
+    }
+    value as i16
+  }
+
+  /// Skips the padding and the restart marker ending a restart interval.
+  fn restart(&mut self) -> Option<()> {
+    self.bits_left = 0;
+    match self.data.get(self.pos..self.pos + 2) {
+      Some([0xff, RST0..=RST7]) => {
+        self.pos += 2;
+        Some(())
+      }
+      _ => None,
+    }
+  }
+}
+
+struct BitWriter {
+  out: Vec<u8>,
+  pending: u32,
+  pending_bits: u8,
+}
+
+impl BitWriter {
+  fn new(out: Vec<u8>) -> Self {
+    Self {
+      out,
+      pending: 0,
+      pending_bits: 0,
+    }
+  }
+
+  fn write(&mut self, bits: u16, len: u8) {
+    self.pending = self.pending << len | bits as u32 & ((1 << len) - 1);
+    self.pending_bits += len;
+    while self.pending_bits >= 8 {
+      self.pending_bits -= 8;
+      let byte = (self.pending >> self.pending_bits) as u8;
+      self.out.push(byte);
+      if byte == 0xff {
+        self.out.push(0x00);
+      }
+    }
+    self.pending &= (1 << self.pending_bits) - 1;
+  }
+
+  /// Pads the last byte with one bits.
+  fn finish(mut self) -> Vec<u8> {
+    if self.pending_bits > 0 {
+      let padding = 8 - self.pending_bits;
+      self.write((1 << padding) - 1, padding);
+    }
+    self.out
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use image::{codecs::jpeg::JpegEncoder, ColorType, RgbaImage};
+
+  use super::*;
+
+  /// Smooth gradients with some noise, at a size that is not a whole number
+  /// of blocks.
+  fn pixels(width: u32, height: u32) -> RgbaImage {
+    RgbaImage::from_fn(width, height, |x, y| {
+      let noise = (x * 7 + y * 13) % 11;
+      image::Rgba([
+        (x * 5 + noise) as u8,
+        (y * 3) as u8,
+        ((x + y) * 2) as u8,
+        255,
+      ])
+    })
+  }
+
+  fn coefficients(jpeg: &JpegImage) -> Vec<[i16; BLOCK_SIZE]> {
+    let blocks = jpeg
+      .components
+      .iter()
+      .flat_map(|component| &component.blocks);
+    blocks.copied().collect()
+  }
+
+  #[test]
+  fn encoding_round_trips_through_decoding() {
+    let rgba = pixels(43, 29);
+    let jpeg = JpegImage::from_pixels(43, 29, &rgba, 85);
+    let bytes = jpeg.encode();
+    assert!(is_jpeg(&bytes));
+    let decoded = JpegImage::decode(&bytes).expect("own output decodes");
+    assert_eq!((decoded.width, decoded.height), (43, 29));
+    assert_eq!(coefficients(&decoded), coefficients(&jpeg));
+    assert_eq!(decoded.encode(), bytes);
+
+    // Another decoder sees the same picture.
+    let other = image::load_from_memory(&bytes).unwrap().to_rgba8();
+    assert_eq!(other.dimensions(), (43, 29));
+    let error = other
+      .as_raw()
+      .iter()
+      .zip(rgba.as_raw())
+      .map(|(&a, &b)| u32::from(a.abs_diff(b)))
+      .max()
+      .unwrap();
+    assert!(error < 40, "pixels off by {error}");
+  }
+
+  #[test]
+  fn decoding_keeps_coefficients_of_foreign_files() {
+    let rgba = pixels(64, 48);
+    let mut foreign = Vec::new();
+    JpegEncoder::new_with_quality(&mut foreign, 75)
+      .encode(rgba.as_raw(), 64, 48, ColorType::Rgba8)
+      .unwrap();
+    let jpeg = JpegImage::decode(&foreign).expect("baseline JPEG decodes");
+    let reencoded = jpeg.encode();
+    assert_eq!(
+      coefficients(&JpegImage::decode(&reencoded).unwrap()),
+      coefficients(&jpeg)
+    );
+    // Same coefficients and tables, so the same pixels.
+    assert_eq!(
+      image::load_from_memory(&reencoded).unwrap().to_rgba8(),
+      image::load_from_memory(&foreign).unwrap().to_rgba8()
+    );
+  }
+}
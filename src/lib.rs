@@ -0,0 +1,56 @@
+//! Hides payloads in the low bits of images and gets them back.
+//!
+//! [`StegoImage`] embeds in the pixel samples of lossless images, [`StegoJpeg`]
+//! in the quantized DCT coefficients of JPEGs, and [`Carrier`] holds either.
+//! Both carriers insert and extract payloads through [`StegoCarrier`].
+//! Images are opened from and written to files or memory alike, so a payload
+//! can be embedded and extracted without touching the filesystem. The `image`
+//! crate the carriers are built on is re-exported, so its types need no
+//! separate dependency:
+//!
+//! ```
+//! use rustego::{
+//!   image::{DynamicImage, ImageFormat},
+//!   ChannelMask, EmbedConfig, StegoCarrier, StegoImage,
+//! };
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let mut img = StegoImage::from_dynamic_image(DynamicImage::new_rgb8(64, 64));
+//! img.set_password(Some("secret"));
+//! img.set_config(EmbedConfig::new(2, ChannelMask::RGBA)?);
+//! img.insert_data(b"hello")?;
+//! let stego = img.to_bytes(ImageFormat::Png)?;
+//!
+//! let mut img = StegoImage::from_bytes(&stego)?;
+//! img.set_password(Some("secret"));
+//! let data = img.extract_data()?;
+//! assert_eq!(data, b"hello");
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod analysis;
+pub mod archive;
+pub mod capacity;
+pub mod carrier;
+pub mod config;
+mod crypto;
+mod ecc;
+pub mod envelope;
+pub mod header;
+mod jpeg;
+pub mod metrics;
+mod png;
+mod sample_order;
+mod shamir;
+pub mod shard;
+pub mod stego_image;
+pub mod stego_jpeg;
+
+pub use image;
+
+pub use carrier::{Carrier, StegoCarrier};
+pub use config::{ChannelMask, ConfigOverrides, EmbedConfig};
+pub use header::ChecksumKind;
+pub use stego_image::{PayloadReader, PayloadWriter, StegoError, StegoImage, StegoResult};
+pub use stego_jpeg::StegoJpeg;
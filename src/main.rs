@@ -1,37 +1,664 @@
 use std::{
   error::Error,
-  io::{Read, Write},
-  path::Path,
+  fs,
+  io::{self, Read, Write},
+  path::{Path, PathBuf},
+  process::ExitCode,
 };
 
-use stego_image::StegoImage;
+use cli::{Command, Domain, Options, Parsed};
+use rustego::{
+  archive::Archive,
+  carrier::OutputWarning,
+  envelope::FileEnvelope,
+  header::{self, Flags},
+  shard::{self, CarrierSet},
+  stego_image::PayloadReader,
+  Carrier, ChannelMask, EmbedConfig, StegoError, StegoImage, StegoJpeg,
+};
+
+mod cli;
+
+const PASSWORD_VAR: &str = "RUSTEGO_PASSWORD";
+const ORDERING_KEY_VAR: &str = "RUSTEGO_KEY";
+
+fn main() -> ExitCode {
+  let (command, options) = match cli::parse(std::env::args().skip(1)) {
+    Ok(Parsed::Run(command, options)) => (command, options),
+    Ok(Parsed::Help(usage)) => {
+      println!("{usage}");
+      return ExitCode::SUCCESS;
+    }
+    Err(err) => {
+      eprintln!("error: {err}\n\nRun `rustego --help` for usage.");
+      return ExitCode::from(2);
+    }
+  };
+
+  let result = match command {
+    Command::Embed => embed(&options),
+    Command::Extract => extract(&options),
+    Command::Capacity => capacity(&options),
+    Command::Inspect => inspect(&options),
+    Command::List => list(&options),
+    Command::Add => add(&options),
+    Command::Remove => remove(&options),
+    Command::Analyze => analyze(&options),
+    Command::Plane => plane(&options),
+    Command::Diff => diff(&options),
+    Command::Metrics => metrics(&options),
+  };
+  match result {
+    Ok(()) => ExitCode::SUCCESS,
+    Err(err) => {
+      eprintln!("error: {err}");
+      ExitCode::FAILURE
+    }
+  }
+}
+
+/// Opens the input image, see [`open_path`].
+fn open(options: &Options) -> Result<Carrier, Box<dyn Error>> {
+  let input = options
+    .input
+    .as_deref()
+    .expect("input is validated by the parser");
+  open_path(input, options)
+}
+
+/// Opens `input` in the requested domain, by default the one
+/// [`Carrier::open`] picks, and applies the options shared by all commands.
+fn open_path(input: &Path, options: &Options) -> Result<Carrier, Box<dyn Error>> {
+  let quality = options.quality.unwrap_or(StegoJpeg::DEFAULT_QUALITY);
+  let mut img = match options.domain {
+    None => Carrier::open(input, quality)?,
+    Some(Domain::Pixel) => Carrier::Pixel(StegoImage::open(input)?),
+    Some(Domain::Dct) => Carrier::Dct(Box::new(StegoJpeg::open(input, quality)?)),
+  };
+  let config = options.config()?;
+  match &mut img {
+    Carrier::Pixel(img) => {
+      if config.bits_per_channel() > img.max_bits_per_channel() {
+        return Err(
+          format!(
+            "--bits must be at most {} for an image with 8-bit samples",
+            img.max_bits_per_channel()
+          )
+          .into(),
+        );
+      }
+      img.set_config(config);
+      img.set_allow_lossy(options.allow_lossy);
+    }
+    Carrier::Dct(img) => {
+      if options.bits.is_some() || options.channels.is_some() {
+        return Err("`--bits` and `--channels` only apply to the pixel domain".into());
+      }
+      if options.lsb_matching || options.adaptive || options.matrix.is_some() {
+        return Err(
+          "`--lsb-matching`, `--adaptive` and `--matrix` only apply to the pixel domain".into(),
+        );
+      }
+      img.set_error_correction(config.ecc_parity())?;
+    }
+  }
+  let password = options
+    .password
+    .clone()
+    .or_else(|| std::env::var(PASSWORD_VAR).ok());
+  let key = options
+    .key
+    .clone()
+    .or_else(|| std::env::var(ORDERING_KEY_VAR).ok());
+  img.set_password(password.as_deref());
+  img.set_ordering_key(key.as_deref());
+  if let Some(checksum) = options.checksum {
+    img.set_checksum(checksum)?;
+  }
+  img.set_compression(options.compress);
+  Ok(img)
+}
+
+/// Images named by `--input`, see [`shard::carrier_paths`].
+fn carrier_paths(options: &Options) -> io::Result<Option<Vec<PathBuf>>> {
+  let input = options
+    .input
+    .as_deref()
+    .expect("input is validated by the parser");
+  shard::carrier_paths(input)
+}
+
+/// Reads `--payload` or stdin, or with `--file` the payload file along with
+/// its name and metadata. Returns the envelope, if any, and the bytes to embed.
+fn read_payload(options: &Options) -> Result<(Option<FileEnvelope>, Vec<u8>), Box<dyn Error>> {
+  let file = match &options.payload {
+    Some(payload) if options.file => Some(FileEnvelope::from_path(payload)?),
+    _ => None,
+  };
+  let data = match (&file, &options.payload) {
+    (Some(file), _) => file.encode(),
+    (None, Some(payload)) => fs::read(payload)?,
+    (None, None) => {
+      let mut data = Vec::new();
+      io::stdin().read_to_end(&mut data)?;
+      data
+    }
+  };
+  Ok((file, data))
+}
+
+fn embed(options: &Options) -> Result<(), Box<dyn Error>> {
+  if let Some(paths) = carrier_paths(options)? {
+    return embed_sharded(paths, options);
+  }
+  if options.threshold.is_some() {
+    return Err("`--threshold` needs a directory or pattern of carriers as `--input`".into());
+  }
+  let output = options
+    .output
+    .as_deref()
+    .expect("output is validated by the parser");
+  let mut img = open(options)?;
+  if !options.file {
+    let mut payload: Box<dyn Read> = match &options.payload {
+      Some(payload) => Box::new(fs::File::open(payload)?),
+      None => Box::new(io::stdin().lock()),
+    };
+    let inserted = img.insert_from(&mut payload);
+    if let Err(StegoError::NotEnoughSpace) = inserted {
+      eprintln!("The image holds {} bytes", img.available());
+    }
+    inserted?;
+    return write_output(&img, output, options);
+  }
+  let (file, data) = read_payload(options)?;
+  let file = file.expect("payload is validated by the parser");
+  if let Err(err) = img.insert_file(&file) {
+    report_insert_error(&img, &data, options, &err);
+    return Err(err.into());
+  }
+  write_output(&img, output, options)
+}
+
+fn open_set(paths: Vec<PathBuf>, options: &Options) -> Result<CarrierSet, Box<dyn Error>> {
+  CarrierSet::open(paths, |path| open_path(path, options))
+}
+
+/// Spreads the payload over several carriers, which are written to the
+/// `--output` directory under their own file names.
+fn embed_sharded(paths: Vec<PathBuf>, options: &Options) -> Result<(), Box<dyn Error>> {
+  if paths.len() > shard::MAX_SHARDS {
+    let max = shard::MAX_SHARDS;
+    return Err(format!("a payload can be spread over at most {max} images").into());
+  }
+  let count = paths.len();
+  if options
+    .threshold
+    .is_some_and(|threshold| usize::from(threshold) > count)
+  {
+    return Err(format!("--threshold must be at most the {count} carriers").into());
+  }
+  let output = options
+    .output
+    .as_deref()
+    .expect("output is validated by the parser");
+  let mut set = open_set(paths, options)?;
+  if let Some(path) = set.too_small() {
+    return Err(format!("{} is too small to carry a shard", path.display()).into());
+  }
+  let (file, data) = read_payload(options)?;
+  let flags = Flags {
+    envelope: file.is_some(),
+    ..Flags::default()
+  };
+  if let Err(err) = set.spread(&data, flags, options.threshold) {
+    if let StegoError::NotEnoughSpace = err {
+      let capacity = set.capacity(options.threshold);
+      match options.threshold {
+        Some(_) => eprintln!("The smallest image holds {capacity} bytes"),
+        None => eprintln!("The images hold {capacity} bytes together"),
+      }
+    }
+    return Err(err.into());
+  }
+
+  set.write_to(output, |img, path| write_output(img, path, options))?;
+  match options.threshold {
+    Some(threshold) => eprintln!(
+      "Shared the payload among {count} images in {}, any {threshold} of which recover it",
+      output.display()
+    ),
+    None => eprintln!(
+      "Spread the payload over {count} images in {}",
+      output.display()
+    ),
+  }
+  Ok(())
+}
+
+/// Writes the carrier to `output`, pointing out how to avoid a lossy format and
+/// checking that the payload reads back with `--verify`.
+fn write_output(img: &Carrier, output: &Path, options: &Options) -> Result<(), Box<dyn Error>> {
+  match img.output_warning(output) {
+    Some(OutputWarning::Lossy) => eprintln!(
+      "warning: {} is a lossy format, the payload will not survive",
+      output.display()
+    ),
+    Some(OutputWarning::JpegData) => {
+      eprintln!("warning: writing JPEG data to {}", output.display())
+    }
+    None => {}
+  }
+  let written = img.write(output, options.verify);
+  if let Err(StegoError::LossyFormat) = written {
+    eprintln!(
+      "Write a lossless format such as PNG, embed with `--domain dct` for JPEG output, or pass \
+       `--allow-lossy`"
+    );
+  }
+  written?;
+  if options.verify {
+    eprintln!("Verified the payload in {}", output.display());
+  }
+  Ok(())
+}
+
+fn report_insert_error(img: &Carrier, data: &[u8], options: &Options, err: &StegoError) {
+  if let StegoError::NotEnoughSpace = err {
+    eprintln!(
+      "Payload needs {} bytes but the image holds {} bytes",
+      img.required_space(data),
+      img.available(),
+    );
+    if options.compress {
+      eprintln!(
+        "About {} bytes of similar data fit when compressed",
+        img.available_compressed(data)
+      );
+    }
+  }
+}
+
+/// Where the payload is extracted from.
+fn extract(options: &Options) -> Result<(), Box<dyn Error>> {
+  let extracted = match carrier_paths(options)? {
+    Some(paths) => open_set(paths, options)?.extract()?,
+    None => {
+      let img = open(options)?;
+      if options.entry.is_none() && options.dir.is_none() {
+        if let Some(reader) = img.reader()? {
+          return stream_extracted(reader, options);
+        }
+      }
+      img.extract()?
+    }
+  };
+  if extracted.corrected > 0 {
+    eprintln!("Error correction repaired {} bytes", extracted.corrected);
+  }
+  let file = match (&options.entry, &options.dir) {
+    (Some(name), _) => {
+      let archive = extracted.into_archive()?;
+      let entry = archive.get(name).cloned();
+      entry.ok_or_else(|| format!("no entry named `{name}` in the archive"))?
+    }
+    (None, Some(_)) => extracted.into_file()?,
+    (None, None) => {
+      return write_extracted(&extracted.into_data()?, options);
+    }
+  };
+  match &options.dir {
+    Some(dir) => {
+      let path = file.restore(dir)?;
+      eprintln!("Restored {}", path.display());
+      Ok(())
+    }
+    None => write_extracted(&file.data, options),
+  }
+}
 
-mod stego_image;
+/// Writes the payload to `--output` or stdout.
+fn write_extracted(data: &[u8], options: &Options) -> Result<(), Box<dyn Error>> {
+  match &options.output {
+    Some(output) => fs::write(output, data)?,
+    None => io::stdout().write_all(data)?,
+  }
+  Ok(())
+}
 
-fn main() -> Result<(), Box<dyn Error>> {
-  let args: Vec<_> = std::env::args().collect();
-  let extraction_mode = args.len() == 2;
+/// Writes the payload to `--output` or stdout as it is extracted. An output
+/// file is removed again if the payload turns out to be corrupted.
+fn stream_extracted(mut reader: PayloadReader, options: &Options) -> Result<(), Box<dyn Error>> {
+  match &options.output {
+    Some(output) => {
+      let copied = fs::File::create(output).and_then(|mut file| io::copy(&mut reader, &mut file));
+      if let Err(err) = copied {
+        let _ = fs::remove_file(output);
+        return Err(err.into());
+      }
+    }
+    None => {
+      io::copy(&mut reader, &mut io::stdout().lock())?;
+    }
+  }
+  Ok(())
+}
+
+fn capacity(options: &Options) -> Result<(), Box<dyn Error>> {
+  let img = open(options)?;
+  let (usable, encryption_overhead) = match &img {
+    Carrier::Pixel(img) => pixel_capacity(img, options)?,
+    Carrier::Dct(img) => dct_capacity(img),
+  };
+  if let Some(payload) = &options.payload {
+    let data = fs::read(payload)?;
+    if options.compress {
+      println!("raw capacity:        {usable} bytes");
+      println!(
+        "compressed estimate: {} bytes of data that compresses like the payload",
+        img.available_compressed(&data)
+      );
+    }
+    let required = img.required_space(&data) - encryption_overhead;
+    if required > usable {
+      return Err(format!("payload needs {required} bytes and does not fit").into());
+    }
+    println!("payload needs {required} bytes and fits");
+  }
+  Ok(())
+}
 
-  if args.len() != 3 && !extraction_mode {
-    eprintln!("Invalid number of arguments");
-    return Ok(());
+/// Prints the capacity of every pixel domain mode, returning the usable bytes
+/// of the selected one and the encryption overhead.
+fn pixel_capacity(img: &StegoImage, options: &Options) -> Result<(usize, usize), Box<dyn Error>> {
+  let report = img.capacity();
+  println!(
+    "pixels:              {} ({} reserved for the header)",
+    report.pixels, report.header_pixels
+  );
+  println!("color type:          {:?}", report.color);
+  println!("checksum overhead:   {} bytes", report.checksum_overhead);
+  println!("encryption overhead: {} bytes", report.encryption_overhead);
+  println!(
+    "ecc overhead:        {} bytes",
+    report.error_correction_overhead
+  );
+  println!();
+  print!("{:<10}", "channels");
+  for bits in 1..=report.max_bits_per_channel {
+    print!("{:>12}", format!("{bits} bpc"));
+  }
+  println!();
+  let config = options.config()?;
+  for channels in ChannelMask::all() {
+    print!("{:<10}", channels.to_string());
+    for bits in 1..=report.max_bits_per_channel {
+      let mode = EmbedConfig::new(bits, channels)?
+        .with_error_correction(config.ecc_parity())?
+        .with_matrix_embedding(config.matrix_embedding())
+        .map_or_else(|_| "-".to_owned(), |mode| report.usable(&mode).to_string());
+      print!("{mode:>12}");
+    }
+    println!();
   }
 
-  if extraction_mode {
-    let img = StegoImage::open(Path::new(&args[1]))?;
+  let usable = report.usable(&config);
+  let matrix = match config.matrix_embedding() {
+    0 => String::new(),
+    k => format!(", matrix {k}"),
+  };
+  println!();
+  println!(
+    "selected ({} bpc, {}{matrix}): {usable} bytes",
+    config.bits_per_channel(),
+    config.channels()
+  );
+  Ok((usable, report.encryption_overhead))
+}
 
-    let extracted = img.extract_data()?;
+/// Prints the capacity of the DCT coefficients of a JPEG, returning the
+/// usable bytes and the encryption overhead.
+fn dct_capacity(img: &StegoJpeg) -> (usize, usize) {
+  let report = img.capacity();
+  println!(
+    "coefficients:        {} ({} usable, {} reserved for the header)",
+    report.coefficients, report.usable_coefficients, report.header_coefficients
+  );
+  println!("checksum overhead:   {} bytes", report.checksum_overhead);
+  println!("encryption overhead: {} bytes", report.encryption_overhead);
+  println!(
+    "ecc overhead:        {} bytes",
+    report.error_correction_overhead
+  );
+  println!();
+  println!("usable: {} bytes", report.usable);
+  (report.usable, report.encryption_overhead)
+}
 
-    std::io::stdout().write_all(&extracted)?;
+fn inspect(options: &Options) -> Result<(), Box<dyn Error>> {
+  let header = match open(options)?.header()? {
+    Some(header) => header,
+    None => {
+      println!("No versioned header found (empty image, legacy layout or wrong --key)");
+      return Ok(());
+    }
+  };
+  println!("version:          {}", header::VERSION);
+  println!("payload length:   {}", header.payload_len);
+  if header.flags.dct {
+    println!("domain:           dct");
   } else {
-    let mut img = StegoImage::open(Path::new(&args[1]))?;
+    let channels = ChannelMask::from_bits(header.channel_mask)
+      .map(|channels| channels.to_string())
+      .unwrap_or_else(|| format!("{:#06b}", header.channel_mask));
+    println!("domain:           pixel");
+    println!("bits per channel: {}", header.bits_per_channel);
+    println!("channels:         {channels}");
+    if header.matrix_embedding > 0 {
+      let k = header.matrix_embedding;
+      println!("matrix embedding: {k} bits in {} samples", (1u16 << k) - 1);
+    }
+    if header.adaptive_threshold > 0 {
+      println!(
+        "adaptive:         texture {} and up",
+        header.adaptive_threshold
+      );
+    }
+  }
+  println!("checksum:         {:?}", header.checksum);
+  println!("ecc parity:       {}", header.ecc_parity);
+  println!("compressed:       {}", header.flags.compressed);
+  println!("encrypted:        {}", header.flags.encrypted);
+  println!("keyed order:      {}", header.flags.keyed_order);
+  println!("file envelope:    {}", header.flags.envelope);
+  println!("archive:          {}", header.flags.archive);
+  if let Some(shard) = header.shard {
+    println!("shard:            {} of {}", shard.index + 1, shard.count);
+    if shard.threshold > 0 {
+      println!("shares needed:    {}", shard.threshold);
+    }
+    println!("shard set:        {:08x}", shard.set_id);
+  }
+  Ok(())
+}
+
+fn list(options: &Options) -> Result<(), Box<dyn Error>> {
+  let archive = open(options)?.extract_archive()?;
+  for entry in archive.entries() {
+    let modified = entry
+      .modified
+      .map_or_else(|| "-".to_owned(), |modified| modified.to_string());
+    println!(
+      "{:>10}  {:>10}  {:<24}  {}",
+      entry.data.len(),
+      modified,
+      entry.content_type,
+      entry.name
+    );
+  }
+  Ok(())
+}
+
+fn write_archive(
+  mut img: Carrier,
+  archive: &Archive,
+  options: &Options,
+) -> Result<(), Box<dyn Error>> {
+  if let Err(err) = img.insert_archive(archive) {
+    report_insert_error(&img, &archive.encode(), options, &err);
+    return Err(err.into());
+  }
+  let output = options
+    .output
+    .as_deref()
+    .expect("output is validated by the parser");
+  write_output(&img, output, options)
+}
+
+fn add(options: &Options) -> Result<(), Box<dyn Error>> {
+  let mut img = open(options)?;
+  let mut archive = img.existing_archive(&options.overrides())?;
+  let payload = options
+    .payload
+    .as_deref()
+    .expect("payload is validated by the parser");
+  archive.add(FileEnvelope::from_path(payload)?);
+  write_archive(img, &archive, options)
+}
+
+fn remove(options: &Options) -> Result<(), Box<dyn Error>> {
+  let mut img = open(options)?;
+  let mut archive = img.existing_archive(&options.overrides())?;
+  let name = options
+    .entry
+    .as_deref()
+    .expect("entry is validated by the parser");
+  if archive.remove(name).is_none() {
+    return Err(format!("no entry named `{name}` in the archive").into());
+  }
+  write_archive(img, &archive, options)
+}
+
+/// Runs the steganalysis attacks in the pixel domain, whatever the format of
+/// the input.
+fn analyze(options: &Options) -> Result<(), Box<dyn Error>> {
+  let input = options
+    .input
+    .as_deref()
+    .expect("input is validated by the parser");
+  let report = StegoImage::open(input)?.analyze();
+  let rate = |rate: Option<f64>| rate.map_or_else(|| "-".to_owned(), |rate| format!("{rate:.3}"));
+  println!("color type: {:?}", report.color);
+  println!();
+  println!(
+    "{:<8}{:>12}{:>12}{:>12}{:>12}",
+    "channel", "chi2 p", "chi2 rate", "rs rate", "spa rate"
+  );
+  for channel in &report.channels {
+    println!(
+      "{:<8}{:>12.3}{:>12.3}{:>12}{:>12}",
+      channel.name,
+      channel.chi_square_p,
+      channel.chi_square_rate,
+      rate(channel.rs_rate),
+      rate(channel.spa_rate)
+    );
+  }
+  Ok(())
+}
 
-    let mut data = Vec::new();
-    std::io::stdin().read_to_end(&mut data)?;
+fn plane(options: &Options) -> Result<(), Box<dyn Error>> {
+  let input = options
+    .input
+    .as_deref()
+    .expect("input is validated by the parser");
+  let output = options
+    .output
+    .as_deref()
+    .expect("output is validated by the parser");
+  let channel = options.channel.expect("channel is validated by the parser");
+  let img = StegoImage::open(input)?;
+  let plane = match img.bit_plane(channel, options.bit.unwrap_or(0), options.bits.unwrap_or(1)) {
+    Err(StegoError::InvalidConfig) => {
+      return Err(
+        format!(
+          "the image has no such channel or bits, its colour type is {:?}",
+          img.color()
+        )
+        .into(),
+      )
+    }
+    plane => plane?,
+  };
+  plane.save(output)?;
+  Ok(())
+}
 
-    img.insert_data(&data)?;
+fn diff(options: &Options) -> Result<(), Box<dyn Error>> {
+  let input = options
+    .input
+    .as_deref()
+    .expect("input is validated by the parser");
+  let stego = options
+    .stego
+    .as_deref()
+    .expect("stego is validated by the parser");
+  let output = options
+    .output
+    .as_deref()
+    .expect("output is validated by the parser");
+  let (map, largest) = StegoImage::open(input)?.difference_map(&StegoImage::open(stego)?)?;
+  map.save(output)?;
+  if largest == 0 {
+    eprintln!("The images are identical");
+  } else {
+    eprintln!("Largest difference: {largest}");
+  }
+  Ok(())
+}
 
-    img.save(Path::new(&args[2]))?;
+fn metrics(options: &Options) -> Result<(), Box<dyn Error>> {
+  let input = options
+    .input
+    .as_deref()
+    .expect("input is validated by the parser");
+  let stego = options
+    .stego
+    .as_deref()
+    .expect("stego is validated by the parser");
+  let report = StegoImage::open(input)?.distortion(&StegoImage::open(stego)?)?;
+  println!("color type:       {:?}", report.color);
+  println!(
+    "modified samples: {} of {} ({:.2}%)",
+    report.modified_samples,
+    report.samples,
+    report.modified_samples as f64 * 100.0 / report.samples.max(1) as f64
+  );
+  println!("max delta:        {}", report.max_delta);
+  println!("MSE:              {:.6}", report.mse);
+  println!("PSNR:             {:.2} dB", report.psnr);
+  println!("SSIM:             {:.6}", report.ssim);
+  println!();
+  println!(
+    "{:<8}{:>12}{:>12}{:>12}{:>12}{:>12}",
+    "channel", "modified", "max delta", "MSE", "PSNR (dB)", "SSIM"
+  );
+  for channel in &report.channels {
+    println!(
+      "{:<8}{:>12}{:>12}{:>12.6}{:>12.2}{:>12.6}",
+      channel.name,
+      channel.modified_samples,
+      channel.max_delta,
+      channel.mse,
+      channel.psnr,
+      channel.ssim
+    );
+  }
+  if let Some(min_psnr) = options.min_psnr.filter(|&min_psnr| report.psnr < min_psnr) {
+    return Err(format!("PSNR {:.2} dB is below {min_psnr} dB", report.psnr).into());
+  }
+  if let Some(min_ssim) = options.min_ssim.filter(|&min_ssim| report.ssim < min_ssim) {
+    return Err(format!("SSIM {:.6} is below {min_ssim}", report.ssim).into());
   }
   Ok(())
 }
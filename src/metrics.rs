@@ -0,0 +1,182 @@
+//! Distortion an embedded payload causes, measured between a cover and the
+//! stego image made from it.
+//!
+//! - Mean squared error over the samples and the peak signal-to-noise ratio
+//!   derived from it, in dB relative to the largest sample value.
+//! - Structural similarity (SSIM) of Wang, Bovik, Sheikh and Simoncelli,
+//!   averaged over 8×8 windows overlapping by half. 1 means identical.
+//! - How many samples changed and by how much at most.
+
+use image::ColorType;
+
+/// Side of the square windows SSIM compares.
+const WINDOW: usize = 8;
+/// Distance between the corners of neighbouring SSIM windows.
+const STRIDE: usize = WINDOW / 2;
+/// Stabilizing constants of SSIM, as shares of the largest sample value.
+const K1: f64 = 0.01;
+const K2: f64 = 0.03;
+
+/// Distortion of a stego image with respect to its cover, see
+/// [`StegoImage::distortion`].
+///
+/// [`StegoImage::distortion`]: crate::stego_image::StegoImage::distortion
+#[derive(Debug, Clone, PartialEq)]
+pub struct DistortionReport {
+  pub color: ColorType,
+  /// Samples of the image, every channel of every pixel.
+  pub samples: usize,
+  pub modified_samples: usize,
+  /// Largest change of a sample.
+  pub max_delta: u16,
+  pub mse: f64,
+  /// In dB, infinite when the images are identical.
+  pub psnr: f64,
+  /// Mean of the SSIM of the channels.
+  pub ssim: f64,
+  pub channels: Vec<ChannelDistortion>,
+}
+
+/// Distortion of one channel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelDistortion {
+  /// `r`, `g`, `b`, `l` for grey or `a`.
+  pub name: char,
+  pub modified_samples: usize,
+  pub max_delta: u16,
+  pub mse: f64,
+  pub psnr: f64,
+  pub ssim: f64,
+}
+
+/// Compares a channel of the cover with the same channel of the stego image,
+/// both given row by row, `width` to a row. `peak` is the largest value a
+/// sample can take.
+pub(crate) fn channel_distortion(
+  name: char,
+  cover: &[u16],
+  stego: &[u16],
+  width: usize,
+  peak: f64,
+) -> ChannelDistortion {
+  let deltas = cover.iter().zip(stego).map(|(&a, &b)| a.abs_diff(b));
+  let modified_samples = deltas.clone().filter(|&delta| delta > 0).count();
+  let max_delta = deltas.clone().max().unwrap_or(0);
+  let squared: f64 = deltas.map(|delta| f64::from(delta).powi(2)).sum();
+  let mse = squared / cover.len().max(1) as f64;
+  ChannelDistortion {
+    name,
+    modified_samples,
+    max_delta,
+    mse,
+    psnr: psnr(mse, peak),
+    ssim: ssim(cover, stego, width, peak),
+  }
+}
+
+/// Peak signal-to-noise ratio in dB of a mean squared error.
+pub(crate) fn psnr(mse: f64, peak: f64) -> f64 {
+  if mse == 0.0 {
+    f64::INFINITY
+  } else {
+    10.0 * (peak * peak / mse).log10()
+  }
+}
+
+/// Mean SSIM over the windows of a channel. Images smaller than a window are
+/// compared as a whole.
+fn ssim(cover: &[u16], stego: &[u16], width: usize, peak: f64) -> f64 {
+  if cover.is_empty() || width == 0 {
+    return 1.0;
+  }
+  let height = cover.len() / width;
+  let (window_width, window_height) = (WINDOW.min(width), WINDOW.min(height));
+  let corners = |len: usize, window: usize| (0..=len - window).step_by(STRIDE);
+  let (mut sum, mut windows) = (0.0, 0u32);
+  for y in corners(height, window_height) {
+    for x in corners(width, window_width) {
+      let rows = (y..y + window_height).map(|row| row * width + x..row * width + x + window_width);
+      let pairs = rows.flat_map(|row| cover[row.clone()].iter().zip(&stego[row]));
+      sum += window_ssim(pairs.map(|(&a, &b)| (f64::from(a), f64::from(b))), peak);
+      windows += 1;
+    }
+  }
+  sum / f64::from(windows)
+}
+
+/// SSIM of the pairs of samples of one window.
+fn window_ssim(pairs: impl Iterator<Item = (f64, f64)> + Clone, peak: f64) -> f64 {
+  let n = pairs.clone().count() as f64;
+  let (sum_a, sum_b) = pairs
+    .clone()
+    .fold((0.0, 0.0), |(sum_a, sum_b), (a, b)| (sum_a + a, sum_b + b));
+  let (mean_a, mean_b) = (sum_a / n, sum_b / n);
+  let (var_a, var_b, covariance) = pairs.fold((0.0, 0.0, 0.0), |(var_a, var_b, cov), (a, b)| {
+    let (da, db) = (a - mean_a, b - mean_b);
+    (var_a + da * da, var_b + db * db, cov + da * db)
+  });
+  let (var_a, var_b, covariance) = (var_a / n, var_b / n, covariance / n);
+  let c1 = (K1 * peak).powi(2);
+  let c2 = (K2 * peak).powi(2);
+  (2.0 * mean_a * mean_b + c1) * (2.0 * covariance + c2)
+    / ((mean_a * mean_a + mean_b * mean_b + c1) * (var_a + var_b + c2))
+}
+
+#[cfg(test)]
+mod tests {
+  use image::{DynamicImage, Rgb, RgbImage};
+
+  use super::*;
+  use crate::{StegoCarrier, StegoError, StegoImage};
+
+  fn gradient() -> Vec<u16> {
+    (0..256).map(|i| (i % 16 * 8 + i / 16 * 4) as u16).collect()
+  }
+
+  #[test]
+  fn psnr_follows_the_mean_squared_error() {
+    assert_eq!(psnr(0.0, 255.0), f64::INFINITY);
+    assert!((psnr(1.0, 255.0) - 48.130_803_6).abs() < 1e-6);
+    assert!((psnr(100.0, 255.0) - 28.130_803_6).abs() < 1e-6);
+  }
+
+  #[test]
+  fn channel_distortion_counts_and_measures_changes() {
+    let cover = gradient();
+    let same = channel_distortion('r', &cover, &cover, 16, 255.0);
+    assert_eq!((same.modified_samples, same.max_delta), (0, 0));
+    assert_eq!((same.mse, same.ssim), (0.0, 1.0));
+
+    let mut stego = cover.clone();
+    stego[17] += 3;
+    stego[200] -= 1;
+    let changed = channel_distortion('r', &cover, &stego, 16, 255.0);
+    assert_eq!((changed.modified_samples, changed.max_delta), (2, 3));
+    assert_eq!(changed.mse, 10.0 / 256.0);
+    assert!(changed.ssim < 1.0 && changed.ssim > 0.99);
+
+    let inverted: Vec<u16> = cover.iter().map(|&value| 255 - value).collect();
+    assert!(channel_distortion('r', &cover, &inverted, 16, 255.0).ssim < 0.0);
+  }
+
+  #[test]
+  fn distortion_compares_every_channel() {
+    let rgb = RgbImage::from_fn(32, 32, |x, y| Rgb([(x * 8) as u8, (y * 8) as u8, 77]));
+    let cover = StegoImage::from_dynamic_image(DynamicImage::ImageRgb8(rgb.clone()));
+    let mut stego = StegoImage::from_dynamic_image(DynamicImage::ImageRgb8(rgb));
+    stego.insert_data(&[0xc3; 100]).unwrap();
+    let report = cover.distortion(&stego).unwrap();
+    assert_eq!(report.samples, 32 * 32 * 3);
+    let names: Vec<char> = report.channels.iter().map(|channel| channel.name).collect();
+    assert_eq!(names, ['r', 'g', 'b']);
+    assert!(report.modified_samples > 0);
+    assert!((1..=3).contains(&report.max_delta));
+    assert!(report.psnr.is_finite() && report.psnr > 40.0);
+
+    let other = StegoImage::from_dynamic_image(DynamicImage::ImageRgb8(RgbImage::new(8, 8)));
+    assert!(matches!(
+      cover.distortion(&other),
+      Err(StegoError::ImageMismatch)
+    ));
+  }
+}
@@ -0,0 +1,460 @@
+//! PNG container structure, so a carrier can be written back looking like the
+//! file it was read from.
+//!
+//! [`PngTemplate::parse`] records the ancillary chunks of a file, where they sit
+//! relative to the image data, the filter type of every scanline, the
+//! compression level announced by the zlib stream and how the data is split
+//! into IDAT chunks. [`PngTemplate::encode`] writes new pixels with all of
+//! them. Chunks describing a palette or a sample depth (PLTE, tRNS, bKGD, hIST,
+//! sBIT) are dropped when the pixels no longer use the original colour type or
+//! depth, e.g. for palette and low bit depth files which are decoded to 8 bits.
+//! Animated PNGs are not understood.
+//!
+//! [`PngTemplate::new`] gives a plain template for images read from other
+//! formats. 16-bit images are always written through a template, as the PNG
+//! encoder of `image` 0.24 stores their samples in the wrong byte order.
+
+use std::io::{Read, Write};
+
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use image::{ColorType, DynamicImage};
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+const IHDR: [u8; 4] = *b"IHDR";
+const IDAT: [u8; 4] = *b"IDAT";
+const IEND: [u8; 4] = *b"IEND";
+const ACTL: [u8; 4] = *b"acTL";
+/// Chunks that only make sense for the colour type and depth of the original.
+const LAYOUT_CHUNKS: [[u8; 4]; 5] = [*b"PLTE", *b"tRNS", *b"bKGD", *b"hIST", *b"sBIT"];
+
+const GRAYSCALE: u8 = 0;
+const TRUECOLOR: u8 = 2;
+const INDEXED: u8 = 3;
+const GRAYSCALE_ALPHA: u8 = 4;
+const TRUECOLOR_ALPHA: u8 = 6;
+
+const FILTER_NONE: u8 = 0;
+const FILTER_SUB: u8 = 1;
+const FILTER_UP: u8 = 2;
+const FILTER_AVERAGE: u8 = 3;
+const FILTER_PAETH: u8 = 4;
+
+/// Origin and spacing of the seven Adam7 passes, as `(x, y, dx, dy)`.
+const ADAM7: [(u32, u32, u32, u32); 7] = [
+  (0, 0, 8, 8),
+  (4, 0, 8, 8),
+  (0, 4, 4, 8),
+  (2, 0, 4, 4),
+  (0, 2, 2, 4),
+  (1, 0, 2, 2),
+  (0, 1, 1, 2),
+];
+
+#[derive(Debug, Clone)]
+struct Chunk {
+  kind: [u8; 4],
+  data: Vec<u8>,
+}
+
+/// Structure of a PNG file that new pixels of the same dimensions can be
+/// written in.
+#[derive(Debug, Clone)]
+pub struct PngTemplate {
+  width: u32,
+  height: u32,
+  bit_depth: u8,
+  color_type: u8,
+  interlaced: bool,
+  /// Chunks between IHDR and the image data, in file order.
+  before_data: Vec<Chunk>,
+  /// Chunks between the image data and IEND, in file order.
+  after_data: Vec<Chunk>,
+  /// Filter type of every scanline, pass after pass for interlaced files.
+  filters: Vec<u8>,
+  compression: Compression,
+  /// Size of every IDAT chunk in the original.
+  idat_sizes: Vec<usize>,
+}
+
+impl PngTemplate {
+  /// Returns `None` for anything but a well-formed, non-animated PNG.
+  pub fn parse(bytes: &[u8]) -> Option<Self> {
+    let mut rest = bytes.strip_prefix(&SIGNATURE)?;
+    let mut chunks = Vec::new();
+    while !rest.is_empty() {
+      let len = u32::from_be_bytes(rest.get(..4)?.try_into().ok()?) as usize;
+      let kind: [u8; 4] = rest.get(4..8)?.try_into().ok()?;
+      let data = rest.get(8..8 + len)?.to_vec();
+      rest = rest.get(12 + len..)?;
+      let end = kind == IEND;
+      chunks.push(Chunk { kind, data });
+      if end {
+        break;
+      }
+    }
+
+    let (ihdr, chunks) = chunks.split_first()?;
+    if ihdr.kind != IHDR || ihdr.data.len() != 13 || chunks.iter().any(|c| c.kind == ACTL) {
+      return None;
+    }
+    let header = &ihdr.data;
+    let first_data = chunks.iter().position(|chunk| chunk.kind == IDAT)?;
+    let data_len = chunks[first_data..]
+      .iter()
+      .take_while(|chunk| chunk.kind == IDAT)
+      .count();
+    let data_chunks = &chunks[first_data..first_data + data_len];
+    let after_data = &chunks[first_data + data_len..];
+    if after_data.last()?.kind != IEND || after_data.iter().any(|c| c.kind == IDAT) {
+      return None;
+    }
+
+    let mut template = Self {
+      width: u32::from_be_bytes(header[0..4].try_into().ok()?),
+      height: u32::from_be_bytes(header[4..8].try_into().ok()?),
+      bit_depth: header[8],
+      color_type: header[9],
+      interlaced: match header[12] {
+        0 => false,
+        1 => true,
+        _ => return None,
+      },
+      before_data: chunks[..first_data].to_vec(),
+      after_data: after_data[..after_data.len() - 1].to_vec(),
+      filters: Vec::new(),
+      compression: Compression::default(),
+      idat_sizes: data_chunks.iter().map(|chunk| chunk.data.len()).collect(),
+    };
+    let zlib: Vec<u8> = data_chunks
+      .iter()
+      .flat_map(|chunk| chunk.data.iter().copied())
+      .collect();
+    template.compression = Self::compression_level(&zlib)?;
+    template.filters = template.read_filters(&zlib)?;
+    Some(template)
+  }
+
+  /// Template of a plain, non-interlaced PNG without ancillary chunks, for
+  /// images that were not read from a PNG.
+  pub fn new(img: &DynamicImage) -> Self {
+    Self {
+      width: img.width(),
+      height: img.height(),
+      bit_depth: 0,
+      color_type: 0,
+      interlaced: false,
+      before_data: Vec::new(),
+      after_data: Vec::new(),
+      filters: vec![FILTER_PAETH; img.height() as usize],
+      compression: Compression::default(),
+      idat_sizes: Vec::new(),
+    }
+  }
+
+  /// Level matching the FLEVEL field of the zlib header. The fastest level
+  /// is told apart from no compression by the type of the first deflate block.
+  fn compression_level(zlib: &[u8]) -> Option<Compression> {
+    let (&flags, &first) = (zlib.get(1)?, zlib.get(2)?);
+    Some(match flags >> 6 {
+      0 if first >> 1 & 0b11 == 0 => Compression::none(),
+      0 => Compression::fast(),
+      1 => Compression::new(3),
+      2 => Compression::default(),
+      _ => Compression::best(),
+    })
+  }
+
+  fn read_filters(&self, zlib: &[u8]) -> Option<Vec<u8>> {
+    let mut data = Vec::new();
+    ZlibDecoder::new(zlib).read_to_end(&mut data).ok()?;
+    let bits_per_pixel = self.bit_depth as usize * channels(self.color_type)?;
+    let mut filters = Vec::new();
+    let mut offset = 0;
+    for (width, height) in self.passes() {
+      let row_len = 1 + (width as usize * bits_per_pixel).div_ceil(8);
+      for _ in 0..height {
+        let filter = *data.get(offset)?;
+        if filter > FILTER_PAETH {
+          return None;
+        }
+        filters.push(filter);
+        offset += row_len;
+      }
+    }
+    (offset <= data.len()).then_some(filters)
+  }
+
+  /// Dimensions of every non-empty pass.
+  fn passes(&self) -> Vec<(u32, u32)> {
+    if !self.interlaced {
+      return vec![(self.width, self.height)];
+    }
+    ADAM7
+      .iter()
+      .map(|&(x, y, dx, dy)| {
+        (
+          self.width.saturating_sub(x).div_ceil(dx),
+          self.height.saturating_sub(y).div_ceil(dy),
+        )
+      })
+      .filter(|&(width, height)| width > 0 && height > 0)
+      .collect()
+  }
+
+  /// Writes `img`, which must have the dimensions of the original, as a PNG
+  /// structured like the original file.
+  pub fn encode(&self, img: &DynamicImage) -> Vec<u8> {
+    let color = img.color();
+    let color_type = match color.channel_count() {
+      1 => GRAYSCALE,
+      2 => GRAYSCALE_ALPHA,
+      3 => TRUECOLOR,
+      _ => TRUECOLOR_ALPHA,
+    };
+    let bytes_per_sample = color.bytes_per_pixel() / color.channel_count();
+    let bit_depth = bytes_per_sample * 8;
+    let same_layout = color_type == self.color_type && bit_depth == self.bit_depth;
+
+    let mut out = SIGNATURE.to_vec();
+    let mut header = Vec::with_capacity(13);
+    header.extend(self.width.to_be_bytes());
+    header.extend(self.height.to_be_bytes());
+    header.extend([bit_depth, color_type, 0, 0, self.interlaced as u8]);
+    write_chunk(&mut out, IHDR, &header);
+    let keep = |chunk: &&Chunk| same_layout || !LAYOUT_CHUNKS.contains(&chunk.kind);
+    for chunk in self.before_data.iter().filter(keep) {
+      write_chunk(&mut out, chunk.kind, &chunk.data);
+    }
+
+    let mut encoder = ZlibEncoder::new(Vec::new(), self.compression);
+    encoder
+      .write_all(&self.filtered(img, color))
+      .expect("writing to a vector cannot fail");
+    let zlib = encoder.finish().expect("writing to a vector cannot fail");
+    let mut rest = zlib.as_slice();
+    for size in self.idat_split(zlib.len()) {
+      let (data, tail) = rest.split_at(size);
+      write_chunk(&mut out, IDAT, data);
+      rest = tail;
+    }
+
+    for chunk in self.after_data.iter().filter(keep) {
+      write_chunk(&mut out, chunk.kind, &chunk.data);
+    }
+    write_chunk(&mut out, IEND, &[]);
+    out
+  }
+
+  /// Sizes of the IDAT chunks `len` bytes of zlib data are written in, as
+  /// many as in the original. Every chunk but the last keeps its original
+  /// size, as encoders usually cut the data into chunks of a fixed size, and
+  /// the last takes the rest. When the data shrank below what those chunks
+  /// hold, all of them are scaled down instead.
+  fn idat_split(&self, len: usize) -> Vec<usize> {
+    let (_, leading) = self.idat_sizes.split_last().unwrap_or((&0, &[]));
+    let leading_len: usize = leading.iter().sum();
+    let mut sizes: Vec<usize> = if leading_len < len {
+      leading.to_vec()
+    } else {
+      let total = leading_len + self.idat_sizes.last().unwrap_or(&0);
+      leading
+        .iter()
+        .map(|&size| size * len / total.max(1))
+        .collect()
+    };
+    sizes.push(len - sizes.iter().sum::<usize>());
+    sizes
+  }
+
+  /// Scanlines of `img` with the filters of the original, big-endian as PNG
+  /// stores 16-bit samples.
+  fn filtered(&self, img: &DynamicImage, color: ColorType) -> Vec<u8> {
+    let bytes_per_pixel = color.bytes_per_pixel() as usize;
+    let bytes_per_sample = bytes_per_pixel / color.channel_count() as usize;
+    let mut pixels = img.as_bytes().to_vec();
+    if bytes_per_sample == 2 {
+      for sample in pixels.chunks_exact_mut(2) {
+        let value = u16::from_ne_bytes([sample[0], sample[1]]);
+        sample.copy_from_slice(&value.to_be_bytes());
+      }
+    }
+    let stride = self.width as usize * bytes_per_pixel;
+
+    let mut out = Vec::new();
+    let mut filters = self.filters.iter().copied();
+    let origins: &[(u32, u32, u32, u32)] = if self.interlaced {
+      &ADAM7
+    } else {
+      &[(0, 0, 1, 1)]
+    };
+    for &(x0, y0, dx, dy) in origins {
+      let xs: Vec<usize> = (x0..self.width)
+        .step_by(dx as usize)
+        .map(|x| x as usize)
+        .collect();
+      if xs.is_empty() {
+        continue;
+      }
+      let mut prior = vec![0u8; xs.len() * bytes_per_pixel];
+      for y in (y0..self.height).step_by(dy as usize) {
+        let line = &pixels[y as usize * stride..][..stride];
+        let row: Vec<u8> = xs
+          .iter()
+          .flat_map(|&x| &line[x * bytes_per_pixel..][..bytes_per_pixel])
+          .copied()
+          .collect();
+        let filter = filters.next().unwrap_or(FILTER_NONE);
+        filter_row(filter, &row, &prior, bytes_per_pixel, &mut out);
+        prior = row;
+      }
+    }
+    out
+  }
+}
+
+fn channels(color_type: u8) -> Option<usize> {
+  match color_type {
+    GRAYSCALE | INDEXED => Some(1),
+    GRAYSCALE_ALPHA => Some(2),
+    TRUECOLOR => Some(3),
+    TRUECOLOR_ALPHA => Some(4),
+    _ => None,
+  }
+}
+
+fn filter_row(filter: u8, row: &[u8], prior: &[u8], bytes_per_pixel: usize, out: &mut Vec<u8>) {
+  out.push(filter);
+  for (i, (&value, &up)) in row.iter().zip(prior).enumerate() {
+    let (left, up_left) = match i.checked_sub(bytes_per_pixel) {
+      Some(j) => (row[j], prior[j]),
+      None => (0, 0),
+    };
+    let prediction = match filter {
+      FILTER_SUB => left,
+      FILTER_UP => up,
+      FILTER_AVERAGE => ((left as u16 + up as u16) / 2) as u8,
+      FILTER_PAETH => paeth(left, up, up_left),
+      _ => 0,
+    };
+    out.push(value.wrapping_sub(prediction));
+  }
+}
+
+fn paeth(left: u8, up: u8, up_left: u8) -> u8 {
+  let estimate = left as i16 + up as i16 - up_left as i16;
+  let distance = |value: u8| (estimate - value as i16).abs();
+  if distance(left) <= distance(up) && distance(left) <= distance(up_left) {
+    left
+  } else if distance(up) <= distance(up_left) {
+    up
+  } else {
+    up_left
+  }
+}
+
+fn write_chunk(out: &mut Vec<u8>, kind: [u8; 4], data: &[u8]) {
+  out.extend((data.len() as u32).to_be_bytes());
+  let start = out.len();
+  out.extend(kind);
+  out.extend(data);
+  let crc = crc32fast::hash(&out[start..]);
+  out.extend(crc.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// A noisy 40×30 RGB PNG, its zlib stream written at `level` and cut into
+  /// IDAT chunks of `sizes` bytes, the last taking the rest.
+  fn png(level: u32, sizes: &[usize]) -> Vec<u8> {
+    let (width, height) = (40u32, 30u32);
+    let mut state = 1u32;
+    let mut noise = || {
+      state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+      (state >> 24) as u8 & 0x0f
+    };
+    let mut scanlines = Vec::new();
+    for y in 0..height {
+      scanlines.push(FILTER_NONE);
+      scanlines.extend((0..width * 3).map(|i| ((i * 31 + y * 17) % 251) as u8 & 0xf0 | noise()));
+    }
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::new(level));
+    encoder.write_all(&scanlines).unwrap();
+    let zlib = encoder.finish().unwrap();
+
+    let mut out = SIGNATURE.to_vec();
+    let mut header = width.to_be_bytes().to_vec();
+    header.extend(height.to_be_bytes());
+    header.extend([8, TRUECOLOR, 0, 0, 0]);
+    write_chunk(&mut out, IHDR, &header);
+    let mut rest = zlib.as_slice();
+    for &size in sizes {
+      let (data, tail) = rest.split_at(size);
+      write_chunk(&mut out, IDAT, data);
+      rest = tail;
+    }
+    write_chunk(&mut out, IDAT, rest);
+    write_chunk(&mut out, IEND, &[]);
+    out
+  }
+
+  fn idat_chunks(png: &[u8]) -> Vec<&[u8]> {
+    let mut rest = &png[SIGNATURE.len()..];
+    let mut chunks = Vec::new();
+    while !rest.is_empty() {
+      let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+      if rest[4..8] == IDAT {
+        chunks.push(&rest[8..8 + len]);
+      }
+      rest = &rest[12 + len..];
+    }
+    chunks
+  }
+
+  /// Writes the pixels of `original` through its template.
+  fn rewrite(original: &[u8]) -> Vec<u8> {
+    let template = PngTemplate::parse(original).unwrap();
+    let img = image::load_from_memory(original).unwrap();
+    let rewritten = template.encode(&img);
+    assert_eq!(image::load_from_memory(&rewritten).unwrap(), img);
+    rewritten
+  }
+
+  #[test]
+  fn keeps_the_zlib_header() {
+    for level in 0..=9 {
+      let original = png(level, &[]);
+      let rewritten = rewrite(&original);
+      assert_eq!(
+        idat_chunks(&rewritten)[0][..2],
+        idat_chunks(&original)[0][..2],
+        "level {level}"
+      );
+    }
+  }
+
+  #[test]
+  fn keeps_the_idat_chunks() {
+    for sizes in [&[][..], &[100], &[1000, 1000], &[64, 64, 64, 64]] {
+      let original = png(9, sizes);
+      let rewritten = rewrite(&original);
+      let chunk_sizes = |png| idat_chunks(png).iter().map(|chunk| chunk.len()).collect();
+      let (original, rewritten): (Vec<_>, Vec<_>) =
+        (chunk_sizes(&original), chunk_sizes(&rewritten));
+      assert_eq!(rewritten.len(), original.len());
+      assert_eq!(rewritten[..sizes.len()], original[..sizes.len()]);
+    }
+  }
+
+  #[test]
+  fn scales_idat_chunks_down_to_shorter_data() {
+    let mut template = PngTemplate::parse(&png(9, &[])).unwrap();
+    template.idat_sizes = vec![1000, 1000, 500];
+    assert_eq!(template.idat_split(3000), [1000, 1000, 1000]);
+    assert_eq!(template.idat_split(1000), [400, 400, 200]);
+    template.idat_sizes = vec![1000];
+    assert_eq!(template.idat_split(3000), [3000]);
+  }
+}
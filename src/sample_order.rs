@@ -0,0 +1,86 @@
+use crate::crypto;
+
+/// Most channels a pixel can have.
+pub const MAX_CHANNELS: usize = 4;
+
+/// Order in which samples of the image receive payload bits.
+///
+/// Samples are numbered `pixel * channels + channel`, `channels` being the
+/// number of channels of every pixel. Without a key samples are visited in
+/// raster order. With a key the pixels are
+/// visited in a pseudo-random permutation seeded by the key, see
+/// [`Self::seed`], and the channels of
+/// every pixel are shuffled as well, so the payload is scattered over the whole
+/// image. The permutation is produced lazily with a partial Fisher-Yates shuffle
+/// so only the visited part of the image is ever shuffled.
+pub enum SampleOrder {
+  Sequential(std::ops::Range<usize>),
+  Keyed {
+    rng: crypto::ChaChaRng,
+    pixels: Vec<u32>,
+    visited: usize,
+    channel_count: usize,
+    channels: [usize; MAX_CHANNELS],
+    channel: usize,
+  },
+}
+
+impl SampleOrder {
+  /// Salt deriving the seed of the order from the key, see [`Self::seed`].
+  const DOMAIN: &'static [u8] = b"rustego sample order";
+
+  /// Derives the seed of the keyed order from the key, which is slow on
+  /// purpose, see [`crypto::derive_seed`].
+  pub fn seed(key: &[u8]) -> [u8; 32] {
+    crypto::derive_seed(key, Self::DOMAIN)
+  }
+
+  pub fn new(pixel_count: usize, channel_count: usize, seed: Option<[u8; 32]>) -> Self {
+    match seed {
+      None => SampleOrder::Sequential(0..pixel_count * channel_count),
+      Some(seed) => SampleOrder::Keyed {
+        rng: crypto::ChaChaRng::new(seed),
+        pixels: (0..pixel_count as u32).collect(),
+        visited: 0,
+        channel_count,
+        channels: [0; MAX_CHANNELS],
+        channel: channel_count,
+      },
+    }
+  }
+}
+
+impl Iterator for SampleOrder {
+  type Item = usize;
+
+  fn next(&mut self) -> Option<usize> {
+    match self {
+      SampleOrder::Sequential(range) => range.next(),
+      SampleOrder::Keyed {
+        rng,
+        pixels,
+        visited,
+        channel_count,
+        channels,
+        channel,
+      } => {
+        if *channel == *channel_count {
+          if *visited == pixels.len() {
+            return None;
+          }
+          let remaining = (pixels.len() - *visited) as u32;
+          pixels.swap(*visited, *visited + rng.below(remaining) as usize);
+          *visited += 1;
+          *channels = [0, 1, 2, 3];
+          for i in (1..*channel_count).rev() {
+            channels.swap(i, rng.below(i as u32 + 1) as usize);
+          }
+          *channel = 0;
+        }
+        let sample = pixels[*visited - 1] as usize * *channel_count + channels[*channel];
+        *channel += 1;
+        Some(sample)
+      }
+    }
+  }
+}
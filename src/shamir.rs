@@ -0,0 +1,102 @@
+//! Shamir secret sharing over GF(2^8), byte by byte.
+//!
+//! Every byte of the secret is the constant term of its own random polynomial
+//! of degree `threshold - 1`, and share `x` holds the value of each polynomial
+//! at `x`. Any `threshold` shares determine the polynomials and so the secret,
+//! while fewer are consistent with every possible secret of that length.
+
+use crate::ecc::{div, eval, mul};
+
+/// Splits `secret` into `count` shares, any `threshold` of which recover it.
+/// Share `i` is the evaluation at `x = i + 1`.
+pub fn split(secret: &[u8], threshold: u8, count: u8) -> Vec<Vec<u8>> {
+  assert!(
+    (1..=count).contains(&threshold),
+    "threshold must be between 1 and the number of shares"
+  );
+  let degree = usize::from(threshold) - 1;
+  let mut random = vec![0u8; secret.len() * degree];
+  getrandom::getrandom(&mut random).expect("system random number generator is unavailable");
+
+  let mut shares = vec![Vec::with_capacity(secret.len()); count.into()];
+  let mut poly = vec![0u8; degree + 1];
+  for (i, &byte) in secret.iter().enumerate() {
+    poly[0] = byte;
+    poly[1..].copy_from_slice(&random[i * degree..(i + 1) * degree]);
+    for (x, share) in (1..=count).zip(&mut shares) {
+      share.push(eval(&poly, x));
+    }
+  }
+  shares
+}
+
+/// Recovers the secret from `shares`, pairs of the `x` a share was evaluated
+/// at and its bytes. The `x` must be distinct and non-zero and the shares of
+/// equal length; with fewer shares than the threshold the result is garbage.
+pub fn combine(shares: &[(u8, &[u8])]) -> Vec<u8> {
+  // Lagrange basis polynomials evaluated at zero.
+  let weights: Vec<u8> = shares
+    .iter()
+    .map(|&(x, _)| {
+      shares
+        .iter()
+        .filter(|&&(other, _)| other != x)
+        .fold(1, |weight, &(other, _)| mul(weight, div(other, other ^ x)))
+    })
+    .collect();
+  let len = shares.first().map_or(0, |(_, share)| share.len());
+  (0..len)
+    .map(|i| {
+      shares
+        .iter()
+        .zip(&weights)
+        .fold(0, |byte, (&(_, share), &weight)| {
+          byte ^ mul(share[i], weight)
+        })
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Every way of picking `size` of the indices below `count`.
+  fn subsets(count: u8, size: usize) -> Vec<Vec<u8>> {
+    (0u32..1 << count)
+      .filter(|set| set.count_ones() as usize == size)
+      .map(|set| (0..count).filter(|i| set & 1 << i != 0).collect())
+      .collect()
+  }
+
+  fn combine_subset(shares: &[Vec<u8>], subset: &[u8]) -> Vec<u8> {
+    let picked: Vec<(u8, &[u8])> = subset
+      .iter()
+      .map(|&i| (i + 1, shares[usize::from(i)].as_slice()))
+      .collect();
+    combine(&picked)
+  }
+
+  #[test]
+  fn any_threshold_shares_recover_the_secret() {
+    let secret: Vec<u8> = (0..=255).collect();
+    for (threshold, count) in [(1, 1), (1, 3), (2, 2), (3, 5), (5, 5)] {
+      let shares = split(&secret, threshold, count);
+      assert_eq!(shares.len(), usize::from(count));
+      for subset in subsets(count, threshold.into()) {
+        assert_eq!(combine_subset(&shares, &subset), secret);
+      }
+    }
+  }
+
+  #[test]
+  fn fewer_shares_do_not() {
+    let secret: Vec<u8> = (0..=255).collect();
+    for (threshold, count) in [(2, 2), (2, 4), (3, 5), (5, 5)] {
+      let shares = split(&secret, threshold, count);
+      for subset in subsets(count, usize::from(threshold) - 1) {
+        assert_ne!(combine_subset(&shares, &subset), secret);
+      }
+    }
+  }
+}
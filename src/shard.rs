@@ -0,0 +1,425 @@
+//! Spreading one payload over several carrier images.
+//!
+//! The payload is compressed and sealed once, then cut into consecutive shards
+//! sized after what each carrier can hold. Every carrier stores its shard
+//! behind its own header, which names the set the shard belongs to, its index
+//! and the number of shards, so the payload can be put back together from the
+//! carriers in any order.
+//!
+//! Alternatively every carrier stores a full-size Shamir share of the payload,
+//! and any `threshold` of the carriers recover it.
+
+use std::{
+  ffi::OsStr,
+  fs, io,
+  ops::{Deref, DerefMut},
+  path::{Path, PathBuf},
+};
+
+use image::ImageFormat;
+
+use crate::{
+  carrier::{sealed::Domain, Carrier, StegoCarrier},
+  header::{Flags, Shard},
+  shamir,
+  stego_image::{Extracted, StegoError, StegoResult},
+};
+
+/// Most carriers a payload can be spread over.
+pub const MAX_SHARDS: usize = u8::MAX as usize;
+
+/// Spreads `data` over `carriers` in proportion to what each can hold. It is
+/// compressed and sealed with the settings of the first carrier.
+///
+/// Carriers are anything pointing to a [`StegoCarrier`], such as [`Carrier`]
+/// or `&mut StegoImage`.
+pub fn insert<C>(carriers: &mut [C], data: &[u8], mut flags: Flags) -> StegoResult<()>
+where
+  C: DerefMut,
+  C::Target: StegoCarrier,
+{
+  check_carriers(carriers, data)?;
+  let data = carriers[0].settings().encode(data, &mut flags);
+  let capacities: Vec<usize> = carriers.iter().map(|carrier| carrier.available()).collect();
+  // Even an empty shard needs room for its header and checksum.
+  if capacities.contains(&0) {
+    return Err(StegoError::TooSmallImage);
+  }
+  let mut capacity_left: usize = capacities.iter().sum();
+  if capacity_left < data.len() {
+    return Err(StegoError::NotEnoughSpace);
+  }
+
+  let set_id = new_set_id();
+  let count = carriers.len() as u8;
+  let mut rest = &data[..];
+  for (index, (carrier, capacity)) in carriers.iter_mut().zip(capacities).enumerate() {
+    // Rounding up keeps every shard within its carrier, as what is left never
+    // exceeds the capacity left, and hands the last carrier exactly the rest.
+    let len = (rest.len() as u128 * capacity as u128).div_ceil(capacity_left.max(1) as u128);
+    let (shard, tail) = rest.split_at(len as usize);
+    let shard_info = Shard {
+      set_id,
+      index: index as u8,
+      count,
+      threshold: 0,
+    };
+    carrier.store(shard, flags, Some(shard_info))?;
+    rest = tail;
+    capacity_left -= capacity;
+  }
+  Ok(())
+}
+
+/// Shares `data` among `carriers` so that any `threshold` of them recover it,
+/// while fewer reveal nothing but its length and flags. Every carrier holds a
+/// share as large as the whole payload, compressed and sealed with the
+/// settings of the first carrier.
+pub fn share<C>(carriers: &mut [C], data: &[u8], mut flags: Flags, threshold: u8) -> StegoResult<()>
+where
+  C: DerefMut,
+  C::Target: StegoCarrier,
+{
+  check_carriers(carriers, data)?;
+  if !(1..=carriers.len()).contains(&threshold.into()) {
+    return Err(StegoError::InvalidConfig);
+  }
+  let data = carriers[0].settings().encode(data, &mut flags);
+  if carriers
+    .iter()
+    .any(|carrier| carrier.available() < data.len())
+  {
+    return Err(StegoError::NotEnoughSpace);
+  }
+
+  let set_id = new_set_id();
+  let count = carriers.len() as u8;
+  let shares = shamir::split(&data, threshold, count);
+  for (index, (carrier, share)) in carriers.iter_mut().zip(shares).enumerate() {
+    let shard = Shard {
+      set_id,
+      index: index as u8,
+      count,
+      threshold,
+    };
+    carrier.store(&share, flags, Some(shard))?;
+  }
+  Ok(())
+}
+
+/// Shares `data` among `carriers` if a `threshold` is given, see [`share`],
+/// and spreads it over them otherwise, see [`insert`].
+pub fn spread<C>(
+  carriers: &mut [C],
+  data: &[u8],
+  flags: Flags,
+  threshold: Option<u8>,
+) -> StegoResult<()>
+where
+  C: DerefMut,
+  C::Target: StegoCarrier,
+{
+  match threshold {
+    Some(threshold) => share(carriers, data, flags, threshold),
+    None => insert(carriers, data, flags),
+  }
+}
+
+/// Bytes `carriers` hold together: all they hold when a payload is spread
+/// over them, and what the smallest holds when it is shared among them.
+pub fn capacity<C>(carriers: &[C], threshold: Option<u8>) -> usize
+where
+  C: Deref,
+  C::Target: StegoCarrier,
+{
+  let capacities = carriers.iter().map(|carrier| carrier.available());
+  match threshold {
+    Some(_) => capacities.min().unwrap_or(0),
+    None => capacities.sum(),
+  }
+}
+
+/// Carriers named by `input` when it is a directory or a pattern with `*` or
+/// `?` in its file name, sorted by path. A directory names the files in it
+/// whose extension is an image format. `None` for a single image.
+pub fn carrier_paths(input: &Path) -> io::Result<Option<Vec<PathBuf>>> {
+  let (dir, pattern) = if input.is_dir() {
+    (input, None)
+  } else {
+    match input.file_name().and_then(OsStr::to_str) {
+      Some(name) if name.contains(['*', '?']) => {
+        let parent = input
+          .parent()
+          .filter(|parent| !parent.as_os_str().is_empty());
+        (parent.unwrap_or(Path::new(".")), Some(name))
+      }
+      _ => return Ok(None),
+    }
+  };
+  let mut paths = Vec::new();
+  for entry in fs::read_dir(dir)? {
+    let path = entry?.path();
+    let Some(name) = path.file_name().and_then(OsStr::to_str) else {
+      continue;
+    };
+    let selected = match pattern {
+      Some(pattern) => glob_matches(pattern, name),
+      None => ImageFormat::from_path(&path).is_ok(),
+    };
+    if selected && path.is_file() {
+      paths.push(path);
+    }
+  }
+  if paths.is_empty() {
+    return Err(io::Error::new(
+      io::ErrorKind::NotFound,
+      format!("no images match {}", input.display()),
+    ));
+  }
+  paths.sort();
+  Ok(Some(paths))
+}
+
+/// Whether `name` matches `pattern`, in which `*` stands for any run of
+/// characters and `?` for any single character.
+fn glob_matches(pattern: &str, name: &str) -> bool {
+  let pattern: Vec<char> = pattern.chars().collect();
+  let name: Vec<char> = name.chars().collect();
+  let (mut p, mut n) = (0, 0);
+  // Pattern position after the last `*` and the name position it resumes at
+  // when the rest of the pattern fails to match.
+  let mut backtrack = None;
+  while n < name.len() {
+    match pattern.get(p) {
+      Some('*') => {
+        p += 1;
+        backtrack = Some((p, n));
+      }
+      Some(&c) if c == '?' || c == name[n] => {
+        p += 1;
+        n += 1;
+      }
+      _ => match backtrack {
+        Some((after_star, resume)) => {
+          p = after_star;
+          n = resume + 1;
+          backtrack = Some((after_star, n));
+        }
+        None => return false,
+      },
+    }
+  }
+  pattern[p..].iter().all(|&c| c == '*')
+}
+
+fn check_carriers<C>(carriers: &[C], data: &[u8]) -> StegoResult<()> {
+  if data.is_empty() {
+    return Err(StegoError::NothingToInsert);
+  }
+  if carriers.is_empty() || carriers.len() > MAX_SHARDS {
+    return Err(StegoError::InvalidConfig);
+  }
+  Ok(())
+}
+
+fn new_set_id() -> u32 {
+  let mut set_id = [0u8; 4];
+  getrandom::getrandom(&mut set_id).expect("system random number generator is unavailable");
+  u32::from_le_bytes(set_id)
+}
+
+/// Puts back together the payload spread over or shared among `carriers`,
+/// given in any order. It is opened and decompressed with the settings of the
+/// first carrier. Carriers that cannot be read are skipped as long as the
+/// others suffice.
+pub fn extract<C>(carriers: &[C]) -> StegoResult<Extracted>
+where
+  C: Deref,
+  C::Target: StegoCarrier,
+{
+  let mut set: Option<(Shard, Flags)> = None;
+  let mut shards: Vec<Option<Vec<u8>>> = Vec::new();
+  let mut corrected = 0;
+  let mut failure = None;
+  for carrier in carriers {
+    let loaded = carrier.load().and_then(|stored| match stored.header.shard {
+      Some(shard) => Ok((stored, shard)),
+      None => Err(StegoError::NotSharded),
+    });
+    let (stored, shard) = match loaded {
+      Ok(loaded) => loaded,
+      Err(err) => {
+        failure.get_or_insert(err);
+        continue;
+      }
+    };
+    // How each carrier embeds its shard is its own business.
+    let flags = Flags {
+      keyed_order: false,
+      dct: false,
+      ..stored.header.flags
+    };
+    match set {
+      None => {
+        set = Some((shard, flags));
+        shards = vec![None; shard.count.into()];
+      }
+      Some((first, first_flags)) => {
+        let describe = |shard: Shard, flags| (shard.set_id, shard.count, shard.threshold, flags);
+        if describe(first, first_flags) != describe(shard, flags) {
+          return Err(StegoError::MixedShards);
+        }
+      }
+    }
+    shards[usize::from(shard.index)] = Some(stored.data);
+    corrected += stored.corrected;
+  }
+
+  let Some((shard, flags)) = set else {
+    return Err(failure.unwrap_or(StegoError::NoPayload));
+  };
+  let data = if shard.threshold == 0 {
+    match shards.into_iter().collect::<Option<Vec<_>>>() {
+      Some(shards) => shards.concat(),
+      None => return Err(failure.unwrap_or(StegoError::MissingShards)),
+    }
+  } else {
+    let shares: Vec<(u8, &[u8])> = shards
+      .iter()
+      .zip(1..)
+      .filter_map(|(share, x)| Some((x, share.as_deref()?)))
+      .take(shard.threshold.into())
+      .collect();
+    if shares.len() < shard.threshold.into() {
+      return Err(failure.unwrap_or(StegoError::TooFewShares));
+    }
+    if shares
+      .iter()
+      .any(|(_, share)| share.len() != shares[0].1.len())
+    {
+      return Err(StegoError::MixedShards);
+    }
+    shamir::combine(&shares)
+  };
+  Ok(Extracted {
+    data: carriers[0].settings().decode(data, flags)?,
+    flags,
+    corrected,
+  })
+}
+
+/// Carriers read from files, see [`carrier_paths`], each written back under
+/// its own file name.
+pub struct CarrierSet {
+  paths: Vec<PathBuf>,
+  carriers: Vec<Carrier>,
+}
+
+impl CarrierSet {
+  /// Opens every image in `paths` with `open`, which picks its domain and
+  /// settings.
+  pub fn open<E>(
+    paths: Vec<PathBuf>,
+    open: impl FnMut(&Path) -> Result<Carrier, E>,
+  ) -> Result<Self, E> {
+    let carriers = paths
+      .iter()
+      .map(PathBuf::as_path)
+      .map(open)
+      .collect::<Result<_, _>>()?;
+    Ok(Self { paths, carriers })
+  }
+
+  pub fn carriers(&self) -> &[Carrier] {
+    &self.carriers
+  }
+
+  /// The first image too small to carry even an empty shard.
+  pub fn too_small(&self) -> Option<&Path> {
+    self
+      .paths
+      .iter()
+      .zip(&self.carriers)
+      .find_map(|(path, carrier)| (carrier.available() == 0).then_some(path.as_path()))
+  }
+
+  /// See [`spread`].
+  pub fn spread(&mut self, data: &[u8], flags: Flags, threshold: Option<u8>) -> StegoResult<()> {
+    spread(&mut self.carriers, data, flags, threshold)
+  }
+
+  /// See [`capacity`].
+  pub fn capacity(&self, threshold: Option<u8>) -> usize {
+    capacity(&self.carriers, threshold)
+  }
+
+  /// See [`extract`].
+  pub fn extract(&self) -> StegoResult<Extracted> {
+    extract(&self.carriers)
+  }
+
+  /// Writes every carrier with `write` into the directory `dir`, created if
+  /// need be, under the file name it was read from.
+  pub fn write_to<E: From<io::Error>>(
+    &self,
+    dir: &Path,
+    mut write: impl FnMut(&Carrier, &Path) -> Result<(), E>,
+  ) -> Result<(), E> {
+    fs::create_dir_all(dir)?;
+    for (carrier, path) in self.carriers.iter().zip(&self.paths) {
+      let name = path.file_name().expect("carriers are files");
+      write(carrier, &dir.join(name))?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use image::{DynamicImage, Rgb, RgbImage};
+
+  use super::*;
+  use crate::StegoImage;
+
+  fn cover(seed: u8) -> StegoImage {
+    let img = RgbImage::from_fn(48, 48, |x, y| Rgb([x as u8, y as u8, seed]));
+    StegoImage::from_dynamic_image(DynamicImage::ImageRgb8(img))
+  }
+
+  #[test]
+  fn shards_and_shares_verify_on_their_own() {
+    let dir = std::env::temp_dir().join(format!("rustego-shard-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    for threshold in [None, Some(2)] {
+      let (mut a, mut b, mut c) = (cover(1), cover(2), cover(3));
+      let mut carriers = [&mut a, &mut b, &mut c];
+      spread(
+        &mut carriers,
+        b"spread payload",
+        Flags::default(),
+        threshold,
+      )
+      .unwrap();
+      let mut written = Vec::new();
+      for (index, carrier) in carriers.iter().enumerate() {
+        let path = dir.join(format!("{index}.png"));
+        carrier.save_verified(&path).unwrap();
+        written.push(StegoImage::open(&path).unwrap());
+      }
+      let extracted = extract(&written.iter().collect::<Vec<_>>()).unwrap();
+      assert_eq!(extracted.data, b"spread payload");
+    }
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn glob_matches_wildcards() {
+    assert!(glob_matches("*.png", "cover.png"));
+    assert!(glob_matches("shard-??.png", "shard-01.png"));
+    assert!(glob_matches("a*b*c", "aXbYbZc"));
+    assert!(glob_matches("*", ""));
+    assert!(!glob_matches("*.png", "cover.jpg"));
+    assert!(!glob_matches("shard-?.png", "shard-01.png"));
+    assert!(!glob_matches("a*b", "aXbY"));
+  }
+}
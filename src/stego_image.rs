@@ -1,22 +1,71 @@
 use std::{
-  collections::hash_map::DefaultHasher,
+  cmp::Ordering,
   error::Error,
   fmt::Display,
-  hash::{Hash, Hasher},
+  fs,
+  io::{self, Cursor},
   path::Path,
 };
 
-use image::{io::Reader, ImageResult, Rgba};
+use image::{
+  io::Reader, ColorType, DynamicImage, GenericImageView, ImageError, ImageFormat, ImageResult,
+};
+
+use crate::{
+  analysis::{self, AnalysisReport},
+  archive::Archive,
+  capacity::{CapacityReport, ModeCapacity},
+  carrier::{
+    sealed::{Domain, PayloadSettings},
+    StegoCarrier,
+  },
+  config::{ChannelMask, EmbedConfig},
+  crypto::ChaChaRng,
+  ecc,
+  envelope::FileEnvelope,
+  header::{ChecksumKind, Flags, Header, Shard, HEADER_SIZE, PROTECTED_COPIES},
+  metrics::{self, DistortionReport},
+  png::PngTemplate,
+  sample_order::SampleOrder,
+};
 
-type ImageBuffer = image::ImageBuffer<Rgba<u8>, Vec<u8>>;
+mod planes;
+mod stream;
+mod texture;
+
+pub use stream::{PayloadReader, PayloadWriter};
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum StegoError {
   NotEnoughSpace,
   NothingToInsert,
   InvalidDataLength,
   TooSmallImage,
   InvalidHashCheck,
+  InvalidEncryptedData,
+  WrongPassword,
+  PasswordRequired,
+  InvalidHeader,
+  UnsupportedHeader,
+  InvalidCompressedData,
+  InvalidConfig,
+  InvalidEnvelope,
+  NotAFile,
+  InvalidArchive,
+  IsArchive,
+  Uncorrectable,
+  NoPayload,
+  LossyFormat,
+  VerificationFailed,
+  Sharded,
+  NotSharded,
+  MixedShards,
+  MissingShards,
+  TooFewShares,
+  ImageMismatch,
+  Image(ImageError),
+  Payload(io::Error),
 }
 
 impl Display for StegoError {
@@ -26,114 +75,1372 @@ impl Display for StegoError {
       StegoError::NothingToInsert => "Supplied data contains zero bytes",
       StegoError::InvalidDataLength => "Image contains invalid data length marker",
       StegoError::TooSmallImage => "Image is too small to contain any data",
-      StegoError::InvalidHashCheck => "Image is too small to contain any data",
+      StegoError::InvalidHashCheck => "Extracted data is corrupted",
+      StegoError::InvalidEncryptedData => "Extracted data is not a valid encrypted payload",
+      StegoError::WrongPassword => "Wrong password or tampered encrypted payload",
+      StegoError::PasswordRequired => {
+        "Image contains an encrypted payload but no password was given"
+      }
+      StegoError::InvalidHeader => "Image contains a corrupted header",
+      StegoError::UnsupportedHeader => "Image was written by a newer, incompatible version",
+      StegoError::InvalidCompressedData => "Extracted data could not be decompressed",
+      StegoError::InvalidConfig => "Embedding configuration is out of range",
+      StegoError::InvalidEnvelope => "Extracted file envelope is malformed",
+      StegoError::NotAFile => "Image carries bare data rather than a file",
+      StegoError::InvalidArchive => "Extracted archive is malformed",
+      StegoError::IsArchive => "Image carries an archive, extract its entries by name",
+      StegoError::Uncorrectable => "Extracted data is damaged beyond repair by error correction",
+      StegoError::NoPayload => "Image does not carry a payload",
+      StegoError::LossyFormat => "Output format would alter the samples and destroy the payload",
+      StegoError::VerificationFailed => "Payload could not be read back from the written image",
+      StegoError::Sharded => "Image carries one shard of a payload spread over several images",
+      StegoError::NotSharded => "Image does not carry a shard of a payload spread over images",
+      StegoError::MixedShards => "Images carry shards of different payloads",
+      StegoError::MissingShards => "Some shards of the payload are missing",
+      StegoError::TooFewShares => "Fewer images than the threshold carry shares of the payload",
+      StegoError::ImageMismatch => "Images differ in size or colour type",
+      StegoError::Image(err) => return write!(f, "Image could not be read or written: {err}"),
+      StegoError::Payload(err) => return write!(f, "Payload could not be read: {err}"),
     })
   }
 }
 
-impl Error for StegoError {}
+impl Error for StegoError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match self {
+      StegoError::Image(err) => Some(err),
+      StegoError::Payload(err) => Some(err),
+      _ => None,
+    }
+  }
+}
 
 pub type StegoResult<T> = Result<T, StegoError>;
 
 pub struct StegoImage {
-  img: ImageBuffer,
+  /// Carrier in its original colour type and depth, except for floating
+  /// point images which are converted to 16 bits.
+  img: DynamicImage,
+  settings: PayloadSettings,
+  config: EmbedConfig,
+  allow_lossy: bool,
+  /// Structure of the original file when it is a PNG, which PNG output
+  /// follows.
+  png: Option<Box<PngTemplate>>,
 }
 
 impl StegoImage {
+  /// Opens an image file, in the format implied by the extension of `path`
+  /// or else guessed from its contents.
   pub fn open(path: &Path) -> ImageResult<Self> {
-    let img = Reader::open(path)?.decode()?.to_rgba8();
-    Ok(Self { img })
+    let bytes = fs::read(path)?;
+    Self::decode_image(&bytes, ImageFormat::from_path(path).ok())
   }
 
-  pub fn save(&self, path: &Path) -> ImageResult<()> {
-    self.img.save(path)
+  /// Decodes an image held in memory, guessing its format from its contents.
+  pub fn from_bytes(bytes: &[u8]) -> ImageResult<Self> {
+    Self::decode_image(bytes, None)
   }
 
-  const HEADER_SIZE: usize = std::mem::size_of::<usize>() + std::mem::size_of::<u64>();
-  pub fn avaliable(&self) -> usize {
-    (self.img.width() as usize * self.img.height() as usize)
-      .checked_sub(Self::HEADER_SIZE)
-      .unwrap_or_default()
+  fn decode_image(bytes: &[u8], format: Option<ImageFormat>) -> ImageResult<Self> {
+    let mut reader = Reader::new(Cursor::new(bytes));
+    match format {
+      Some(format) => reader.set_format(format),
+      None => reader = reader.with_guessed_format()?,
+    }
+    let mut img = Self::from_dynamic_image(reader.decode()?);
+    img.png = PngTemplate::parse(bytes).map(Box::new);
+    Ok(img)
   }
 
-  fn calculate_hash(data: &[u8]) -> StegoResult<u64> {
-    let mut hasher = DefaultHasher::new();
-    for byte in data {
-      byte.hash(&mut hasher);
+  /// Wraps an image decoded elsewhere. Floating point images are converted to
+  /// 16 bits.
+  pub fn from_dynamic_image(img: DynamicImage) -> Self {
+    let img = match img.color() {
+      ColorType::Rgb32F => DynamicImage::ImageRgb16(img.to_rgb16()),
+      ColorType::Rgba32F => DynamicImage::ImageRgba16(img.to_rgba16()),
+      _ => img,
+    };
+    Self {
+      img,
+      settings: PayloadSettings::default(),
+      config: EmbedConfig::default(),
+      allow_lossy: false,
+      png: None,
     }
-    Ok(hasher.finish())
   }
 
-  pub fn insert_data(&mut self, data: &[u8]) -> StegoResult<()> {
-    if data.len() == 0 {
-      return Err(StegoError::NothingToInsert);
+  /// The image with the payload embedded so far.
+  pub fn as_dynamic_image(&self) -> &DynamicImage {
+    &self.img
+  }
+
+  /// Selects how many bits of which channels carry the payload.
+  pub fn set_config(&mut self, config: EmbedConfig) {
+    self.config = config;
+  }
+
+  /// Lets [`Self::save`] write lossy formats, which destroy the payload.
+  pub fn set_allow_lossy(&mut self, allow: bool) {
+    self.allow_lossy = allow;
+  }
+
+  /// Whether lossy formats were allowed with [`Self::set_allow_lossy`].
+  pub fn allows_lossy(&self) -> bool {
+    self.allow_lossy
+  }
+
+  /// Returns whether writing the image as `format` alters its samples, be it
+  /// through lossy compression, a palette or another colour type or bit
+  /// depth. Only the formats known to store the colour type of the image
+  /// unchanged are trusted.
+  pub fn is_lossy(&self, format: ImageFormat) -> bool {
+    use ColorType::*;
+    let color = self.img.color();
+    !match format {
+      ImageFormat::Png => true,
+      ImageFormat::Tiff => matches!(color, L8 | Rgb8 | Rgba8 | L16 | Rgb16 | Rgba16),
+      ImageFormat::Tga => matches!(color, L8 | La8 | Rgb8 | Rgba8),
+      ImageFormat::Bmp => matches!(color, Rgb8 | Rgba8),
+      ImageFormat::Pnm => matches!(color, L8 | Rgb8),
+      ImageFormat::Ico => color == Rgba8,
+      ImageFormat::Farbfeld => color == Rgba16,
+      _ => false,
     }
-    if self.avaliable() < data.len() {
-      return Err(StegoError::NotEnoughSpace);
+  }
+
+  /// Encodes the image in `format`, refusing lossy formats with
+  /// [`StegoError::LossyFormat`] unless they were allowed with
+  /// [`Self::set_allow_lossy`]. A PNG carrier encoded as a PNG keeps the
+  /// ancillary chunks, IDAT chunking, filters and compression level of the
+  /// original. Other 16-bit images are written as plain PNGs by the same
+  /// encoder, which stores their samples big-endian as PNG requires.
+  pub fn to_bytes(&self, format: ImageFormat) -> StegoResult<Vec<u8>> {
+    if self.is_lossy(format) && !self.allow_lossy {
+      return Err(StegoError::LossyFormat);
     }
-    let data_len_bytes = data.len().to_le_bytes();
-    let hash_bytes = Self::calculate_hash(data)?.to_le_bytes();
-    let data = data_len_bytes.iter().chain(&hash_bytes).chain(data);
-    for (pixel, data) in self.img.chunks_exact_mut(4).zip(data) {
-      for (i, channel) in pixel.iter_mut().enumerate() {
-        *channel &= !0x3;
-        *channel |= *data >> (i << 1) & 0x3;
+    if format == ImageFormat::Png {
+      if let Some(png) = &self.png {
+        return Ok(png.encode(&self.img));
+      }
+      if self.is_16_bit() {
+        return Ok(PngTemplate::new(&self.img).encode(&self.img));
       }
     }
-    Ok(())
+    let mut bytes = Cursor::new(Vec::new());
+    self
+      .img
+      .write_to(&mut bytes, format)
+      .map_err(StegoError::Image)?;
+    Ok(bytes.into_inner())
   }
 
-  fn extract_size(&self) -> StegoResult<usize> {
-    let channels = self.img.chunks_exact(4);
-    let mut extracted_size_bytes = [0u8; std::mem::size_of::<usize>()];
-    for (i, pixel) in channels.take(std::mem::size_of::<usize>()).enumerate() {
-      for (j, channel) in pixel.iter().enumerate() {
-        extracted_size_bytes[i] |= (channel & 0x3) << (j << 1);
-      }
+  const LEGACY_HEADER_SIZE: usize = std::mem::size_of::<usize>() + std::mem::size_of::<u64>();
+
+  /// Pixels reserved for one copy of the versioned header, which is written
+  /// into the least significant bit of the colour channels so it can be
+  /// decoded before the embedding parameters it describes are known.
+  fn header_pixels(&self) -> usize {
+    (HEADER_SIZE * 8).div_ceil(self.layout().color_channels)
+  }
+
+  fn pixel_count(&self) -> usize {
+    self.img.width() as usize * self.img.height() as usize
+  }
+
+  fn layout(&self) -> Layout {
+    let color = self.img.color();
+    Layout {
+      channels: color.channel_count() as usize,
+      color_channels: color.channel_count() as usize - color.has_alpha() as usize,
+    }
+  }
+
+  /// Colour type and depth the image is embedded in and saved with.
+  pub fn color(&self) -> ColorType {
+    self.img.color()
+  }
+
+  /// Most bits per channel the samples of the image can give up: 16-bit
+  /// samples take twice as many as 8-bit ones.
+  pub fn max_bits_per_channel(&self) -> u8 {
+    if self.is_16_bit() {
+      EmbedConfig::MAX_BITS_PER_CHANNEL
+    } else {
+      EmbedConfig::MAX_BITS_PER_8_BIT_CHANNEL
+    }
+  }
+
+  fn is_16_bit(&self) -> bool {
+    let color = self.img.color();
+    color.bytes_per_pixel() > color.channel_count()
+  }
+
+  fn samples(&self) -> Samples<'_> {
+    match &self.img {
+      DynamicImage::ImageLuma8(img) => Samples::U8(img),
+      DynamicImage::ImageLumaA8(img) => Samples::U8(img),
+      DynamicImage::ImageRgb8(img) => Samples::U8(img),
+      DynamicImage::ImageRgba8(img) => Samples::U8(img),
+      DynamicImage::ImageLuma16(img) => Samples::U16(img),
+      DynamicImage::ImageLumaA16(img) => Samples::U16(img),
+      DynamicImage::ImageRgb16(img) => Samples::U16(img),
+      DynamicImage::ImageRgba16(img) => Samples::U16(img),
+      _ => unreachable!("floating point images are converted on open"),
+    }
+  }
+
+  /// Every sample widened to 16 bits, pixel by pixel.
+  fn sample_values(&self) -> Vec<u16> {
+    match self.samples() {
+      Samples::U8(samples) => samples.iter().map(|&sample| u16::from(sample)).collect(),
+      Samples::U16(samples) => samples.to_vec(),
+    }
+  }
+
+  fn samples_mut(&mut self) -> SamplesMut<'_> {
+    match &mut self.img {
+      DynamicImage::ImageLuma8(img) => SamplesMut::U8(img),
+      DynamicImage::ImageLumaA8(img) => SamplesMut::U8(img),
+      DynamicImage::ImageRgb8(img) => SamplesMut::U8(img),
+      DynamicImage::ImageRgba8(img) => SamplesMut::U8(img),
+      DynamicImage::ImageLuma16(img) => SamplesMut::U16(img),
+      DynamicImage::ImageLumaA16(img) => SamplesMut::U16(img),
+      DynamicImage::ImageRgb16(img) => SamplesMut::U16(img),
+      DynamicImage::ImageRgba16(img) => SamplesMut::U16(img),
+      _ => unreachable!("floating point images are converted on open"),
+    }
+  }
+
+  /// Payload bytes that fit with `config` and the current checksum, after
+  /// error correction overhead.
+  fn payload_capacity(&self, config: &EmbedConfig) -> usize {
+    ecc::data_capacity(self.body_capacity(config), config.ecc_parity())
+      .saturating_sub(self.settings.checksum.size())
+  }
+
+  /// Bytes that fit after the header with `config`, including the checksum and
+  /// error correction.
+  fn body_capacity(&self, config: &EmbedConfig) -> usize {
+    let header_pixels = self.header_pixels() * Header::copies(config.ecc_parity());
+    let samples = self.pixel_count().saturating_sub(header_pixels)
+      * self.layout().carrying_channels(config.channels());
+    config.carried_bits(samples) / 8
+  }
+
+  /// Reports the capacity of the image for every embedding configuration
+  /// along with the overhead the current settings add to a payload.
+  pub fn capacity(&self) -> CapacityReport {
+    let parity = self.config.ecc_parity();
+    let modes = (1..=self.max_bits_per_channel())
+      .flat_map(|bits| ChannelMask::all().map(move |channels| (bits, channels)))
+      .filter_map(|(bits, channels)| EmbedConfig::new(bits, channels).ok())
+      .filter_map(|config| config.with_error_correction(parity).ok())
+      .filter_map(|config| {
+        config
+          .with_matrix_embedding(self.config.matrix_embedding())
+          .ok()
+      })
+      .map(|config| ModeCapacity {
+        config,
+        usable: self
+          .payload_capacity(&config)
+          .saturating_sub(self.settings.encryption_overhead()),
+      })
+      .collect();
+    let body_capacity = self.body_capacity(&self.config);
+    CapacityReport {
+      color: self.img.color(),
+      pixels: self.pixel_count(),
+      header_pixels: (self.header_pixels() * Header::copies(parity)).min(self.pixel_count()),
+      max_bits_per_channel: self.max_bits_per_channel(),
+      checksum_overhead: self.settings.checksum.size(),
+      encryption_overhead: self.settings.encryption_overhead(),
+      error_correction_overhead: body_capacity - ecc::data_capacity(body_capacity, parity),
+      modes,
+    }
+  }
+
+  /// Runs the steganalysis attacks of [`crate::analysis`] on every channel,
+  /// alpha included.
+  pub fn analyze(&self) -> AnalysisReport {
+    let width = self.img.width() as usize;
+    let channels = self
+      .planes()
+      .into_iter()
+      .map(|(name, plane)| analysis::analyze_channel(name, &plane, width))
+      .collect();
+    AnalysisReport {
+      color: self.img.color(),
+      channels,
+    }
+  }
+
+  /// Measures how far `stego` strays from this image, its cover, channel by
+  /// channel, see [`crate::metrics`]. Fails with [`StegoError::ImageMismatch`]
+  /// unless both have the same size and colour type.
+  pub fn distortion(&self, stego: &StegoImage) -> StegoResult<DistortionReport> {
+    if self.img.dimensions() != stego.img.dimensions() || self.img.color() != stego.img.color() {
+      return Err(StegoError::ImageMismatch);
+    }
+    let width = self.img.width() as usize;
+    let peak = f64::from((1u32 << self.sample_bits()) - 1);
+    let channels: Vec<_> = self
+      .planes()
+      .into_iter()
+      .zip(stego.planes())
+      .map(|((name, cover), (_, stego))| {
+        metrics::channel_distortion(name, &cover, &stego, width, peak)
+      })
+      .collect();
+    let samples = self.sample_values().len();
+    // Every channel has as many samples, so their mean is the overall MSE.
+    let mse = channels.iter().map(|channel| channel.mse).sum::<f64>() / channels.len() as f64;
+    Ok(DistortionReport {
+      color: self.img.color(),
+      samples,
+      modified_samples: channels
+        .iter()
+        .map(|channel| channel.modified_samples)
+        .sum(),
+      max_delta: channels
+        .iter()
+        .map(|channel| channel.max_delta)
+        .max()
+        .unwrap_or(0),
+      mse,
+      psnr: metrics::psnr(mse, peak),
+      ssim: channels.iter().map(|channel| channel.ssim).sum::<f64>() / channels.len() as f64,
+      channels,
+    })
+  }
+
+  /// Samples of every channel, alpha included, named as in the reports of
+  /// [`Self::analyze`] and [`Self::distortion`].
+  fn planes(&self) -> Vec<(char, Vec<u16>)> {
+    let layout = self.layout();
+    let names = if layout.color_channels == 1 {
+      "la"
+    } else {
+      "rgba"
+    };
+    let values = self.sample_values();
+    names
+      .chars()
+      .take(layout.channels)
+      .enumerate()
+      .map(|(channel, name)| {
+        let plane = values
+          .iter()
+          .skip(channel)
+          .step_by(layout.channels)
+          .copied()
+          .collect();
+        (name, plane)
+      })
+      .collect()
+  }
+
+  fn legacy_available(&self) -> usize {
+    self.pixel_count().saturating_sub(Self::LEGACY_HEADER_SIZE)
+  }
+
+  fn sample_order(&self) -> SampleOrder {
+    SampleOrder::new(
+      self.pixel_count(),
+      self.layout().channels,
+      self.settings.ordering_seed,
+    )
+  }
+
+  /// Takes the pixels reserved for a copy of the header off the front of
+  /// `order` and returns their colour samples.
+  fn header_samples(&self, order: &mut SampleOrder) -> impl Iterator<Item = usize> {
+    let layout = self.layout();
+    let samples: Vec<_> = order
+      .take(self.header_pixels() * layout.channels)
+      .filter(|sample| sample % layout.channels < layout.color_channels)
+      .collect();
+    samples.into_iter()
+  }
+
+  /// Restricts the samples left in `order` to the channels in `channels`.
+  fn body_samples(&self, order: SampleOrder, channels: ChannelMask) -> impl Iterator<Item = usize> {
+    let layout = self.layout();
+    order.filter(move |sample| layout.carries(channels, sample % layout.channels))
+  }
+
+  fn extract_header(&self, order: &mut SampleOrder) -> StegoResult<Option<Header>> {
+    if self.pixel_count() < self.header_pixels() {
+      return Ok(None);
+    }
+    let samples = self.samples();
+    let mut header_bytes = [0u8; HEADER_SIZE];
+    samples.extract_bits(self.header_samples(order), 1, &mut header_bytes);
+    let decoded = Header::decode(&header_bytes);
+    let copies = match decoded {
+      Ok(Some(header)) => Header::copies(header.ecc_parity),
+      _ => PROTECTED_COPIES,
+    };
+    let mut copy_bytes = header_bytes.repeat(copies);
+    for copy in copy_bytes.chunks_exact_mut(HEADER_SIZE).skip(1) {
+      samples.extract_bits(self.header_samples(order), 1, copy);
     }
+    Header::recover(&copy_bytes)
+  }
+
+  fn extract_size(&self, order: &mut SampleOrder) -> StegoResult<usize> {
+    let mut extracted_size_bytes = [0u8; std::mem::size_of::<usize>()];
+    self
+      .samples()
+      .extract_bits(order, 2, &mut extracted_size_bytes);
     let extracted_size = usize::from_le_bytes(extracted_size_bytes);
-    if extracted_size > self.avaliable() {
+    if extracted_size > self.legacy_available() {
       return Err(StegoError::InvalidDataLength);
     }
     Ok(extracted_size)
   }
 
-  fn extract_hash(&self) -> StegoResult<u64> {
-    let channels = self.img.chunks_exact(4);
-    let mut extracted_hash_bytes = [0u8; std::mem::size_of::<u64>()];
-    for (i, pixel) in channels
-      .skip(std::mem::size_of::<usize>())
-      .take(std::mem::size_of::<u64>())
-      .enumerate()
+  /// Reads a `checksum` and the `size` bytes of payload it covers, see
+  /// [`verify_stream`].
+  fn extract_checked<I: Iterator<Item = usize>>(
+    &self,
+    mut reader: BitReader<I>,
+    checksum: ChecksumKind,
+    ecc_parity: u8,
+    size: usize,
+  ) -> StegoResult<(Vec<u8>, usize)> {
+    let mut stream = vec![0u8; ecc::encoded_len(checksum.size() + size, ecc_parity)];
+    self.samples().read(&mut reader, &mut stream);
+    verify_stream(stream, size, checksum, ecc_parity)
+  }
+
+  /// Checks `header` against the image, returning the embedding configuration
+  /// and the size of the payload it describes.
+  fn payload_layout(&self, header: &Header) -> StegoResult<(EmbedConfig, usize)> {
+    if header.flags.keyed_order != self.settings.ordering_seed.is_some() || header.flags.dct {
+      return Err(StegoError::InvalidHeader);
+    }
+    let config = header.config()?;
+    if config.bits_per_channel() > self.max_bits_per_channel() {
+      return Err(StegoError::InvalidHeader);
+    }
+    let size = header.payload_size(self.adaptive_capacity(&config, header.adaptive_threshold))?;
+    Ok((config, size))
+  }
+
+  fn extract_payload(&self, header: &Header, order: SampleOrder) -> StegoResult<(Vec<u8>, usize)> {
+    let (config, extracted_size) = self.payload_layout(header)?;
+    let body_samples = self.body_samples(order, config.channels());
+    let body_samples = self.textured_samples(
+      body_samples,
+      config.bits_per_channel(),
+      header.adaptive_threshold,
+    );
+    self.extract_checked(
+      BitReader::for_body(body_samples, &config),
+      header.checksum,
+      config.ecc_parity(),
+      extracted_size,
+    )
+  }
+}
+
+impl StegoCarrier for StegoImage {
+  fn available(&self) -> usize {
+    self.payload_capacity(&self.config)
+  }
+
+  fn header(&self) -> StegoResult<Option<Header>> {
+    self.extract_header(&mut self.sample_order())
+  }
+
+  /// Without a key the payload is embedded sequentially from the top-left
+  /// corner.
+  fn set_ordering_key(&mut self, key: Option<&str>) {
+    self.settings.ordering_seed = key.map(|key| SampleOrder::seed(key.as_bytes()));
+  }
+
+  /// Writes the image in the format implied by the extension of `path`, see
+  /// [`Self::to_bytes`].
+  fn save(&self, path: &Path) -> StegoResult<()> {
+    let format = ImageFormat::from_path(path).map_err(StegoError::Image)?;
+    let bytes = self.to_bytes(format)?;
+    fs::write(path, bytes).map_err(|err| StegoError::Image(err.into()))
+  }
+}
+
+impl Domain for StegoImage {
+  fn settings(&self) -> &PayloadSettings {
+    &self.settings
+  }
+
+  fn settings_mut(&mut self) -> &mut PayloadSettings {
+    &mut self.settings
+  }
+
+  fn store(&mut self, data: &[u8], mut flags: Flags, shard: Option<Shard>) -> StegoResult<()> {
+    if self.config.bits_per_channel() > self.max_bits_per_channel()
+      || self.config.adaptive() && self.config.lsb_matching()
     {
-      for (j, channel) in pixel.iter().enumerate() {
-        extracted_hash_bytes[i] |= (channel & 0x3) << (j << 1);
-      }
+      return Err(StegoError::InvalidConfig);
+    }
+    flags.keyed_order = self.settings.ordering_seed.is_some();
+    if self.available() < data.len() {
+      return Err(StegoError::NotEnoughSpace);
+    }
+    let stream = checked_stream(data, self.settings.checksum, self.config.ecc_parity());
+    let adaptive_threshold = if self.config.adaptive() {
+      self.adaptive_threshold(&self.config, stream.len())
+    } else {
+      0
+    };
+    let header = Header {
+      flags,
+      bits_per_channel: self.config.bits_per_channel(),
+      channel_mask: self.config.channels().bits(),
+      checksum: self.settings.checksum,
+      ecc_parity: self.config.ecc_parity(),
+      matrix_embedding: self.config.matrix_embedding(),
+      adaptive_threshold,
+      payload_len: data.len() as u64,
+      shard,
+    };
+    let mut order = self.sample_order();
+    let header_samples: Vec<_> = (0..Header::copies(header.ecc_parity))
+      .map(|_| self.header_samples(&mut order))
+      .collect();
+    let body_samples = self.body_samples(order, self.config.channels());
+    let body_samples = self.textured_samples(
+      body_samples,
+      self.config.bits_per_channel(),
+      adaptive_threshold,
+    );
+    let matching = self.config.lsb_matching();
+    let body_writer = BitWriter::for_body(body_samples, &self.config);
+    let mut samples = self.samples_mut();
+    for header_samples in header_samples {
+      samples.embed(
+        BitWriter::new(header_samples, 1).with_lsb_matching(matching),
+        &header.encode(),
+      );
     }
-    Ok(u64::from_le_bytes(extracted_hash_bytes))
+    samples.embed(body_writer, &stream);
+    Ok(())
+  }
+
+  fn stored(&self) -> StegoResult<Option<Stored>> {
+    let mut order = self.sample_order();
+    let Some(header) = self.extract_header(&mut order)? else {
+      return Ok(None);
+    };
+    let (data, corrected) = self.extract_payload(&header, order)?;
+    Ok(Some(Stored {
+      header,
+      data,
+      corrected,
+    }))
   }
 
-  pub fn extract_data(&self) -> StegoResult<Vec<u8>> {
-    let channels = self.img.chunks_exact(4);
-    if channels.len() < Self::HEADER_SIZE {
+  /// Reads images written before the versioned header was introduced: a
+  /// native-endian `usize` length and a `DefaultHasher` hash followed by the
+  /// payload, all two bits per channel.
+  fn legacy(&self) -> StegoResult<Vec<u8>> {
+    if self.pixel_count() < Self::LEGACY_HEADER_SIZE {
       return Err(StegoError::TooSmallImage);
     }
-    let extracted_size = self.extract_size()?;
-    let extracted_hash = self.extract_hash()?;
+    let mut order = self.sample_order();
+    let extracted_size = self.extract_size(&mut order)?;
+    let (data, _) = self.extract_checked(
+      BitReader::new(order, 2),
+      ChecksumKind::DefaultHasher,
+      0,
+      extracted_size,
+    )?;
+    Ok(data)
+  }
+
+  fn reopen(&self, path: &Path) -> StegoResult<Box<dyn StegoCarrier>> {
+    let mut written = Self::open(path).map_err(StegoError::Image)?;
+    written.settings = self.settings.clone();
+    Ok(Box::new(written))
+  }
+}
+
+/// Payload as embedded, still sealed and compressed, along with its header.
+pub struct Stored {
+  pub header: Header,
+  pub data: Vec<u8>,
+  /// Bytes repaired by error correction.
+  pub corrected: usize,
+}
+
+/// Opened and decompressed payload along with the flags it was stored with.
+pub struct Extracted {
+  pub data: Vec<u8>,
+  pub flags: Flags,
+  /// Bytes repaired by error correction.
+  pub corrected: usize,
+}
+
+impl Extracted {
+  /// The bare data, or the contents of a file.
+  pub fn into_data(self) -> StegoResult<Vec<u8>> {
+    match self.flags {
+      Flags { archive: true, .. } => Err(StegoError::IsArchive),
+      Flags { envelope: true, .. } => Ok(FileEnvelope::decode(&self.data)?.data),
+      _ => Ok(self.data),
+    }
+  }
 
-    let mut data = vec![0u8; extracted_size];
-    for (i, pixel) in channels
-      .skip(Self::HEADER_SIZE)
-      .take(extracted_size)
-      .enumerate()
-    {
-      for (j, channel) in pixel.iter().enumerate() {
-        data[i] |= (channel & 0x3) << (j << 1);
+  pub fn into_file(self) -> StegoResult<FileEnvelope> {
+    match self.flags {
+      Flags { archive: true, .. } => Err(StegoError::IsArchive),
+      Flags { envelope: true, .. } => FileEnvelope::decode(&self.data),
+      _ => Err(StegoError::NotAFile),
+    }
+  }
+
+  pub fn into_archive(self) -> StegoResult<Archive> {
+    match self.flags {
+      Flags { archive: true, .. } => Archive::decode(&self.data),
+      Flags { envelope: true, .. } => {
+        let mut archive = Archive::new();
+        archive.add(FileEnvelope::decode(&self.data)?);
+        Ok(archive)
       }
+      _ => Err(StegoError::NotAFile),
+    }
+  }
+}
+
+/// Prefixes `data` with its `checksum` and protects both with error correction
+/// if `ecc_parity` is not zero.
+pub(crate) fn checked_stream(data: &[u8], checksum: ChecksumKind, ecc_parity: u8) -> Vec<u8> {
+  let mut stream = checksum.compute(data);
+  stream.extend_from_slice(data);
+  if ecc_parity > 0 {
+    stream = ecc::encode(&stream, ecc_parity);
+  }
+  stream
+}
+
+/// Reverses [`checked_stream`] for `size` bytes of payload, repairing the
+/// stream with error correction and verifying the payload against the
+/// checksum. Returns the payload and the number of corrected bytes.
+pub(crate) fn verify_stream(
+  mut stream: Vec<u8>,
+  size: usize,
+  checksum: ChecksumKind,
+  ecc_parity: u8,
+) -> StegoResult<(Vec<u8>, usize)> {
+  let mut corrected = 0;
+  if ecc_parity > 0 {
+    (stream, corrected) =
+      ecc::decode(&stream, checksum.size() + size, ecc_parity).ok_or(StegoError::Uncorrectable)?;
+  }
+  if stream.len() < checksum.size() {
+    return Err(StegoError::InvalidDataLength);
+  }
+  let payload = stream.split_off(checksum.size());
+  if checksum.compute(&payload) != stream {
+    return Err(StegoError::InvalidHashCheck);
+  }
+  Ok((payload, corrected))
+}
+
+/// Channels of every pixel of a carrier, colour channels first and alpha, if
+/// any, last.
+#[derive(Debug, Clone, Copy)]
+struct Layout {
+  channels: usize,
+  color_channels: usize,
+}
+
+impl Layout {
+  /// Index of alpha in a [`ChannelMask`].
+  const ALPHA: usize = 3;
+
+  /// Whether `channel` of a pixel is among the `selected` ones. The single
+  /// channel of a grey image stands for red, green and blue alike.
+  fn carries(self, selected: ChannelMask, channel: usize) -> bool {
+    if channel >= self.color_channels {
+      selected.contains(Self::ALPHA)
+    } else if self.color_channels == 1 {
+      (0..Self::ALPHA).any(|channel| selected.contains(channel))
+    } else {
+      selected.contains(channel)
     }
-    if Self::calculate_hash(&data)? != extracted_hash {
-      return Err(StegoError::InvalidHashCheck);
+  }
+
+  /// Sample of a pixel standing for `channel`, an index into a
+  /// [`ChannelMask`], or `None` if the image lacks that channel.
+  fn sample_channel(self, channel: usize) -> Option<usize> {
+    match channel {
+      Self::ALPHA => (self.channels > self.color_channels).then_some(self.color_channels),
+      _ if self.color_channels == 1 => (channel < Self::ALPHA).then_some(0),
+      _ => (channel < self.color_channels).then_some(channel),
     }
-    Ok(data)
+  }
+
+  fn carrying_channels(self, selected: ChannelMask) -> usize {
+    (0..self.channels)
+      .filter(|&channel| self.carries(selected, channel))
+      .count()
+  }
+}
+
+/// Samples of a carrier at their native depth.
+enum Samples<'a> {
+  U8(&'a [u8]),
+  U16(&'a [u16]),
+}
+
+impl Samples<'_> {
+  fn extract_bits(&self, order: impl Iterator<Item = usize>, bits: u8, bytes: &mut [u8]) {
+    match self {
+      Samples::U8(samples) => extract_bits(samples, order, bits, bytes),
+      Samples::U16(samples) => extract_bits(samples, order, bits, bytes),
+    }
+  }
+
+  fn read<I: Iterator<Item = usize>>(&self, reader: &mut BitReader<I>, bytes: &mut [u8]) {
+    for byte in bytes {
+      *byte = match self {
+        Samples::U8(samples) => reader.read(samples),
+        Samples::U16(samples) => reader.read(samples),
+      };
+    }
+  }
+}
+
+enum SamplesMut<'a> {
+  U8(&'a mut [u8]),
+  U16(&'a mut [u16]),
+}
+
+impl SamplesMut<'_> {
+  /// Writes all of `data` with `writer`, see [`embed_bits`].
+  fn embed<I: Iterator<Item = usize>>(&mut self, mut writer: BitWriter<I>, data: &[u8]) {
+    self.write(&mut writer, data);
+    self.flush(&mut writer);
+  }
+
+  /// Writes `data` as far as the samples of `writer` go, callers check the
+  /// capacity beforehand.
+  fn write<I: Iterator<Item = usize>>(&mut self, writer: &mut BitWriter<I>, data: &[u8]) {
+    for &byte in data {
+      let written = match self {
+        SamplesMut::U8(samples) => writer.write(samples, byte),
+        SamplesMut::U16(samples) => writer.write(samples, byte),
+      };
+      if !written {
+        return;
+      }
+    }
+  }
+
+  fn flush<I: Iterator<Item = usize>>(&mut self, writer: &mut BitWriter<I>) {
+    match self {
+      SamplesMut::U8(samples) => writer.flush(samples),
+      SamplesMut::U16(samples) => writer.flush(samples),
+    }
+  }
+}
+
+/// Integer sample whose low bits can carry payload bits.
+pub(crate) trait Sample: Copy + Into<u32> + TryFrom<u32> {
+  fn low_bits(self, mask: u8) -> u8;
+  fn with_low_bits(self, mask: u8, bits: u8) -> Self;
+
+  /// Returns the value nearest to the sample whose bits under `mask` are
+  /// `bits`, going up or down at random when both are as near and never
+  /// leaving the range of the sample. A single bit is embedded by ±1.
+  fn matching_low_bits(self, mask: u8, bits: u8, rng: &mut ChaChaRng) -> Self {
+    let value: u32 = self.into();
+    let replaced = self.with_low_bits(mask, bits);
+    let step = u32::from(mask) + 1;
+    let replaced_value: u32 = replaced.into();
+    // The other value carrying the bits on the far side of the sample.
+    let alternative = if replaced_value > value {
+      replaced_value.checked_sub(step)
+    } else {
+      replaced_value.checked_add(step)
+    };
+    match alternative.and_then(|alternative| Self::try_from(alternative).ok()) {
+      Some(alternative) => {
+        let distance = |sample: Self| value.abs_diff(sample.into());
+        match distance(alternative).cmp(&distance(replaced)) {
+          Ordering::Less => alternative,
+          Ordering::Equal if rng.next_u32() & 1 == 1 => alternative,
+          _ => replaced,
+        }
+      }
+      None => replaced,
+    }
+  }
+}
+
+impl Sample for u8 {
+  fn low_bits(self, mask: u8) -> u8 {
+    self & mask
+  }
+
+  fn with_low_bits(self, mask: u8, bits: u8) -> Self {
+    self & !mask | bits & mask
+  }
+}
+
+impl Sample for u16 {
+  fn low_bits(self, mask: u8) -> u8 {
+    self as u8 & mask
+  }
+
+  fn with_low_bits(self, mask: u8, bits: u8) -> Self {
+    self & !(mask as u16) | (bits & mask) as u16
+  }
+}
+
+fn low_mask(bits: u8) -> u8 {
+  ((1u16 << bits) - 1) as u8
+}
+
+/// Number of samples matrix embedding hides `k` bits in.
+fn matrix_block(k: u8) -> usize {
+  (1 << k) - 1
+}
+
+/// Syndrome of the lowest bits of `block` under the Hamming code whose parity
+/// check matrix has the binary representation of `i + 1` as column `i`.
+fn syndrome<S: Sample>(samples: &[S], block: &[usize]) -> u8 {
+  block
+    .iter()
+    .zip(1..=u8::MAX)
+    .filter(|&(&sample, _)| samples[sample].low_bits(1) == 1)
+    .fold(0, |syndrome, (_, column)| syndrome ^ column)
+}
+
+/// Writes a little-endian bit stream into the `bits` least significant bits of
+/// the samples yielded by `order`, a byte at a time.
+struct BitWriter<I> {
+  order: I,
+  /// Bits stored in every sample, or every block with matrix embedding.
+  bits: u8,
+  matrix: bool,
+  pending: u32,
+  pending_bits: u8,
+  /// Picks the direction of ties with LSB matching, `None` when low bits are
+  /// replaced.
+  matching: Option<ChaChaRng>,
+}
+
+impl<I: Iterator<Item = usize>> BitWriter<I> {
+  fn new(order: I, bits: u8) -> Self {
+    Self {
+      order,
+      bits,
+      matrix: false,
+      pending: 0,
+      pending_bits: 0,
+      matching: None,
+    }
+  }
+
+  /// Writer of a payload embedded with `config`.
+  fn for_body(order: I, config: &EmbedConfig) -> Self {
+    Self::new(order, config.bits_per_channel())
+      .with_matrix_embedding(config.matrix_embedding())
+      .with_lsb_matching(config.lsb_matching())
+  }
+
+  /// Stores every `k` bits in a block of samples if `k` is not zero, see
+  /// [`EmbedConfig::with_matrix_embedding`].
+  fn with_matrix_embedding(mut self, k: u8) -> Self {
+    if k > 0 {
+      self.bits = k;
+      self.matrix = true;
+    }
+    self
+  }
+
+  /// Embeds with [`Sample::matching_low_bits`] if `lsb_matching` is set, see
+  /// [`EmbedConfig::with_lsb_matching`].
+  fn with_lsb_matching(mut self, lsb_matching: bool) -> Self {
+    self.matching = lsb_matching.then(|| {
+      let mut seed = [0u8; 32];
+      getrandom::getrandom(&mut seed).expect("system random number generator is unavailable");
+      ChaChaRng::new(seed)
+    });
+    self
+  }
+
+  /// Returns `false` once the samples run out.
+  fn write<S: Sample>(&mut self, samples: &mut [S], byte: u8) -> bool {
+    self.pending |= (byte as u32) << self.pending_bits;
+    self.pending_bits += 8;
+    while self.pending_bits >= self.bits {
+      if !self.put(samples, low_mask(self.bits)) {
+        return false;
+      }
+      self.pending >>= self.bits;
+      self.pending_bits -= self.bits;
+    }
+    true
+  }
+
+  /// Stores the pending bits under `mask` in the next sample, or the next
+  /// block of samples with matrix embedding, leaving the other bits it carries
+  /// untouched. Returns `false` once the samples run out.
+  fn put<S: Sample>(&mut self, samples: &mut [S], mask: u8) -> bool {
+    let bits = self.pending as u8;
+    if !self.matrix {
+      let Some(sample) = self.order.next() else {
+        return false;
+      };
+      samples[sample] = match &mut self.matching {
+        // Matching only part of the low bits could carry into the others.
+        Some(rng) if mask == low_mask(self.bits) => {
+          samples[sample].matching_low_bits(mask, bits, rng)
+        }
+        _ => samples[sample].with_low_bits(mask, bits),
+      };
+      return true;
+    }
+    let block: Vec<usize> = self.order.by_ref().take(matrix_block(self.bits)).collect();
+    if block.len() < matrix_block(self.bits) {
+      return false;
+    }
+    // Flipping the lowest bit of sample `i` toggles the bits of `i + 1` in the
+    // syndrome, so one flip turns it into any value.
+    let change = (syndrome(samples, &block) ^ bits) & mask;
+    if change != 0 {
+      let sample = block[usize::from(change) - 1];
+      let flipped = samples[sample].low_bits(1) ^ 1;
+      samples[sample] = match &mut self.matching {
+        Some(rng) => samples[sample].matching_low_bits(1, flipped, rng),
+        None => samples[sample].with_low_bits(1, flipped),
+      };
+    }
+    true
+  }
+
+  /// Passes over the samples of `bytes` bytes without touching them, where a
+  /// later writer will put its own. Must come before any write; a sample the
+  /// skipped bytes share with the next ones is written as if they were zero.
+  fn skip(&mut self, bytes: usize) {
+    let samples = if self.matrix {
+      matrix_block(self.bits)
+    } else {
+      1
+    };
+    for _ in 0..bytes {
+      self.pending_bits += 8;
+      while self.pending_bits >= self.bits {
+        if self.order.nth(samples - 1).is_none() {
+          return;
+        }
+        self.pending_bits -= self.bits;
+      }
+    }
+  }
+
+  /// Writes the bits left over into the next sample or block, leaving the
+  /// other bits it carries untouched.
+  fn flush<S: Sample>(&mut self, samples: &mut [S]) {
+    if self.pending_bits == 0 {
+      return;
+    }
+    self.put(samples, low_mask(self.pending_bits));
+    self.pending = 0;
+    self.pending_bits = 0;
+  }
+}
+
+/// Reverses [`BitWriter`].
+struct BitReader<I> {
+  order: I,
+  bits: u8,
+  matrix: bool,
+  pending: u32,
+  pending_bits: u8,
+}
+
+impl<I: Iterator<Item = usize>> BitReader<I> {
+  fn new(order: I, bits: u8) -> Self {
+    Self {
+      order,
+      bits,
+      matrix: false,
+      pending: 0,
+      pending_bits: 0,
+    }
+  }
+
+  /// Reader of a payload embedded with `config`.
+  fn for_body(order: I, config: &EmbedConfig) -> Self {
+    Self::new(order, config.bits_per_channel()).with_matrix_embedding(config.matrix_embedding())
+  }
+
+  /// Reads every `k` bits from a block of samples if `k` is not zero.
+  fn with_matrix_embedding(mut self, k: u8) -> Self {
+    if k > 0 {
+      self.bits = k;
+      self.matrix = true;
+    }
+    self
+  }
+
+  /// Reads the next byte, padded with zeros once the samples run out.
+  fn read<S: Sample>(&mut self, samples: &[S]) -> u8 {
+    while self.pending_bits < 8 {
+      let bits = if self.matrix {
+        let block: Vec<usize> = self.order.by_ref().take(matrix_block(self.bits)).collect();
+        if block.len() < matrix_block(self.bits) {
+          break;
+        }
+        syndrome(samples, &block)
+      } else {
+        let Some(sample) = self.order.next() else {
+          break;
+        };
+        samples[sample].low_bits(low_mask(self.bits))
+      };
+      self.pending |= (bits as u32) << self.pending_bits;
+      self.pending_bits += self.bits;
+    }
+    let byte = self.pending as u8;
+    self.pending >>= 8;
+    self.pending_bits = self.pending_bits.saturating_sub(8);
+    byte
+  }
+}
+
+/// Writes `data` as a little-endian bit stream into the `bits` least
+/// significant bits of the samples yielded by `order`.
+pub(crate) fn embed_bits<S: Sample>(
+  samples: &mut [S],
+  order: impl Iterator<Item = usize>,
+  bits: u8,
+  data: impl IntoIterator<Item = u8>,
+) {
+  let mut writer = BitWriter::new(order, bits);
+  for byte in data {
+    if !writer.write(samples, byte) {
+      return;
+    }
+  }
+  writer.flush(samples);
+}
+
+/// Reverses [`embed_bits`], filling `bytes` from the samples yielded by `order`.
+pub(crate) fn extract_bits<S: Sample>(
+  samples: &[S],
+  order: impl Iterator<Item = usize>,
+  bits: u8,
+  bytes: &mut [u8],
+) {
+  let mut reader = BitReader::new(order, bits);
+  for byte in bytes {
+    *byte = reader.read(samples);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use image::{DynamicImage, ImageBuffer, ImageFormat, Rgb, RgbImage, Rgba, RgbaImage};
+
+  use super::{BitWriter, StegoError, StegoImage};
+  use crate::{header::ChecksumKind, ChannelMask, EmbedConfig, StegoCarrier};
+
+  fn cover() -> StegoImage {
+    let img = RgbImage::from_fn(64, 64, |x, y| Rgb([x as u8, y as u8, (x * y) as u8]));
+    StegoImage::from_dynamic_image(DynamicImage::ImageRgb8(img))
+  }
+
+  fn cover_16() -> StegoImage {
+    let img = ImageBuffer::from_fn(48, 48, |x, y| {
+      let (x, y) = (x as u16, y as u16);
+      Rgba([x * 1201, y * 977 + 3, (x * y).wrapping_mul(331), 0xfffe - x])
+    });
+    StegoImage::from_dynamic_image(DynamicImage::ImageRgba16(img))
+  }
+
+  /// Embeds `data` the way images were written before the versioned header.
+  fn embed_legacy(img: &mut StegoImage, data: &[u8]) {
+    let mut stream = data.len().to_ne_bytes().to_vec();
+    stream.extend(ChecksumKind::DefaultHasher.compute(data));
+    stream.extend(data);
+    let order = img.sample_order();
+    img.samples_mut().embed(BitWriter::new(order, 2), &stream);
+  }
+
+  /// Indices of the samples `stego` changed in `cover`.
+  fn changed_samples(cover: &StegoImage, stego: &StegoImage) -> Vec<usize> {
+    let (cover, stego) = (cover.sample_values(), stego.sample_values());
+    (0..cover.len()).filter(|&i| cover[i] != stego[i]).collect()
+  }
+
+  /// Writes the image as `format` and decodes it again.
+  fn reencode(img: &StegoImage, format: ImageFormat) -> StegoImage {
+    StegoImage::from_bytes(&img.to_bytes(format).unwrap()).unwrap()
+  }
+
+  #[test]
+  fn legacy_payloads_ignore_the_password() {
+    let mut img = cover();
+    embed_legacy(&mut img, b"written long ago");
+    assert!(img.header().unwrap().is_none());
+    img.set_password(Some("secret"));
+    let extracted = img.extract().unwrap();
+    assert!(!extracted.flags.encrypted);
+    assert_eq!(extracted.data, b"written long ago");
+  }
+
+  #[test]
+  fn default_hasher_is_only_read() {
+    let mut img = cover();
+    assert!(matches!(
+      img.set_checksum(ChecksumKind::DefaultHasher),
+      Err(StegoError::InvalidConfig)
+    ));
+    embed_legacy(&mut img, b"checked by DefaultHasher");
+    assert_eq!(img.extract_data().unwrap(), b"checked by DefaultHasher");
+  }
+
+  #[test]
+  fn refuses_formats_that_change_the_samples() {
+    let mut img = cover_16();
+    for format in [
+      ImageFormat::Ico,
+      ImageFormat::Bmp,
+      ImageFormat::Jpeg,
+      ImageFormat::Gif,
+    ] {
+      assert!(img.is_lossy(format), "{format:?}");
+      assert!(matches!(img.to_bytes(format), Err(StegoError::LossyFormat)));
+    }
+    for format in [ImageFormat::Png, ImageFormat::Tiff, ImageFormat::Farbfeld] {
+      assert!(!img.is_lossy(format), "{format:?}");
+    }
+    img.set_allow_lossy(true);
+    assert!(img.to_bytes(ImageFormat::Gif).is_ok());
+  }
+
+  #[test]
+  fn sixteen_bit_tiff_round_trips_through_png() {
+    let mut img = reencode(&cover_16(), ImageFormat::Tiff);
+    img.insert_data(b"sixteen bits").unwrap();
+    let png = reencode(&img, ImageFormat::Png);
+    assert_eq!(png.as_dynamic_image(), img.as_dynamic_image());
+    assert_eq!(png.extract_data().unwrap(), b"sixteen bits");
+  }
+
+  #[test]
+  fn keyed_order_scatters_the_payload() {
+    let samples = cover().sample_values().len();
+    let mut img = cover();
+    img.insert_data(&[0x5a; 64]).unwrap();
+    assert!(changed_samples(&cover(), &img)
+      .iter()
+      .all(|&i| i < samples / 4));
+
+    let mut img = cover();
+    img.set_ordering_key(Some("key"));
+    img.insert_data(&[0x5a; 64]).unwrap();
+    assert!(changed_samples(&cover(), &img)
+      .iter()
+      .any(|&i| i > samples / 2));
+    assert_eq!(img.extract_data().unwrap(), [0x5a; 64]);
+    img.set_ordering_key(Some("other key"));
+    assert!(img.extract_data().is_err());
+    img.set_ordering_key(None);
+    assert!(img.extract_data().is_err());
+  }
+
+  #[test]
+  fn compression_is_skipped_unless_it_helps() {
+    let mut img = cover();
+    img.set_compression(true);
+    let text = b"2024-01-01 12:00:00 INFO request served\n".repeat(100);
+    assert!(img.required_space(&text) < text.len());
+    assert!(img.available_compressed(&text) > img.available());
+    img.insert_data(&text).unwrap();
+    assert!(img.header().unwrap().unwrap().flags.compressed);
+    assert_eq!(img.extract_data().unwrap(), text);
+
+    let mut state = 1u32;
+    let noise: Vec<u8> = (0..1000)
+      .map(|_| {
+        state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+        (state >> 24) as u8
+      })
+      .collect();
+    assert_eq!(img.required_space(&noise), noise.len());
+    assert_eq!(img.available_compressed(&noise), img.available());
+    img.insert_data(&noise).unwrap();
+    assert!(!img.header().unwrap().unwrap().flags.compressed);
+    assert_eq!(img.extract_data().unwrap(), noise);
+  }
+
+  #[test]
+  fn configurations_are_read_back_from_the_header() {
+    let rgba = RgbaImage::from_fn(32, 32, |x, y| Rgba([x as u8 * 7, y as u8 * 5, 90, 200]));
+    let cover = StegoImage::from_dynamic_image(DynamicImage::ImageRgba8(rgba));
+    let header_samples = cover.header_pixels() * 4;
+    for bits in 1..=4 {
+      for mask in [0b0001, 0b0110, 0b0111, 0b1111] {
+        let channels = ChannelMask::from_bits(mask).unwrap();
+        let mut img = StegoImage::from_dynamic_image(cover.as_dynamic_image().clone());
+        img.set_config(EmbedConfig::new(bits, channels).unwrap());
+        img.insert_data(&[0xa5; 40]).unwrap();
+        let header = img.header().unwrap().unwrap();
+        assert_eq!((header.bits_per_channel, header.channel_mask), (bits, mask));
+        let untouched = changed_samples(&cover, &img)
+          .into_iter()
+          .filter(|&i| i >= header_samples)
+          .all(|i| channels.contains(i % 4));
+        assert!(untouched, "{bits} bpc, mask {mask:#06b}");
+
+        let read = StegoImage::from_dynamic_image(img.as_dynamic_image().clone());
+        assert_eq!(read.extract_data().unwrap(), [0xa5; 40]);
+      }
+    }
+
+    let mut img = cover;
+    img.set_config(EmbedConfig::new(5, ChannelMask::RGBA).unwrap());
+    assert!(matches!(
+      img.insert_data(b"too deep"),
+      Err(StegoError::InvalidConfig)
+    ));
+    assert!(EmbedConfig::new(0, ChannelMask::RGBA).is_err());
+    assert!(EmbedConfig::new(9, ChannelMask::RGBA).is_err());
+    assert_eq!(ChannelMask::from_bits(0), None);
+  }
+
+  #[test]
+  fn capacity_report_matches_what_fits() {
+    let mut img = cover();
+    let config = EmbedConfig::new(2, ChannelMask::RGBA).unwrap();
+    img.set_config(config.with_error_correction(16).unwrap());
+    let report = img.capacity();
+    assert_eq!(report.modes.len(), 4 * 15);
+    assert_eq!(report.encryption_overhead, 0);
+    assert!(report.error_correction_overhead > 0);
+    let usable = report.usable(&config);
+    assert_eq!(usable, img.available());
+    let one_bit = EmbedConfig::new(1, ChannelMask::RGBA).unwrap();
+    assert!(report.usable(&one_bit) < usable);
+
+    img.insert_data(&vec![0x3c; usable]).unwrap();
+    assert!(matches!(
+      img.insert_data(&vec![0x3c; usable + 1]),
+      Err(StegoError::NotEnoughSpace)
+    ));
+
+    img.set_password(Some("secret"));
+    let report = img.capacity();
+    assert_eq!(report.encryption_overhead, crate::crypto::OVERHEAD);
+    assert_eq!(report.usable(&config), usable - crate::crypto::OVERHEAD);
+  }
+
+  #[test]
+  fn lsb_matching_moves_samples_by_the_least_amount() {
+    // Every other row saturated, to exercise the edges of the range.
+    let rgb = RgbImage::from_fn(64, 64, |x, y| match y % 4 {
+      0 => Rgb([0; 3]),
+      2 => Rgb([255; 3]),
+      _ => Rgb([x as u8, y as u8, (x * y) as u8]),
+    });
+    let cover = StegoImage::from_dynamic_image(DynamicImage::ImageRgb8(rgb));
+    // Saturated samples can only move one way, up to the whole mask away.
+    for (bits, furthest, saturated) in [(1, 1, 1), (2, 2, 3)] {
+      let mut img = StegoImage::from_dynamic_image(cover.as_dynamic_image().clone());
+      let config = EmbedConfig::new(bits, ChannelMask::RGBA).unwrap();
+      img.set_config(config.with_lsb_matching(true));
+      let data: Vec<u8> = (0..=255).collect();
+      img.insert_data(&data).unwrap();
+      assert_eq!(img.extract_data().unwrap(), data);
+      let (before, after) = (cover.sample_values(), img.sample_values());
+      let changed = changed_samples(&cover, &img);
+      for &i in &changed {
+        let furthest = match before[i] {
+          0 | 255 => saturated,
+          _ => furthest,
+        };
+        assert!(before[i].abs_diff(after[i]) <= furthest, "{bits} bpc");
+      }
+      // Replacing the low bits would have left the higher ones alone.
+      let mask = (1 << bits) - 1;
+      assert!(changed
+        .iter()
+        .any(|&i| before[i] & !mask != after[i] & !mask));
+    }
+  }
+
+  #[test]
+  fn matrix_embedding_changes_one_sample_per_code_word_at_most() {
+    let mut state = 5u32;
+    let data: Vec<u8> = (0..300)
+      .map(|_| {
+        state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+        (state >> 24) as u8
+      })
+      .collect();
+    let header_samples = cover().header_pixels() * 3;
+    let body_changes = |img: &StegoImage| {
+      let changed = changed_samples(&cover(), img);
+      changed.into_iter().filter(|&i| i >= header_samples).count()
+    };
+    let one_bit = EmbedConfig::new(1, ChannelMask::RGBA).unwrap();
+    let mut plain = cover();
+    plain.set_config(one_bit);
+    plain.insert_data(&data).unwrap();
+
+    let mut img = cover();
+    img.set_config(one_bit.with_matrix_embedding(3).unwrap());
+    img.insert_data(&data).unwrap();
+    assert_eq!(img.header().unwrap().unwrap().matrix_embedding, 3);
+    let read = StegoImage::from_dynamic_image(img.as_dynamic_image().clone());
+    assert_eq!(read.extract_data().unwrap(), data);
+    // Three bits of payload and checksum per code word.
+    let code_words = ((data.len() + 4) * 8).div_ceil(3);
+    assert!(body_changes(&img) <= code_words);
+    assert!(body_changes(&img) < body_changes(&plain));
+
+    assert_eq!(
+      one_bit.with_matrix_embedding(3).unwrap().carried_bits(14),
+      6
+    );
+    let two_bits = EmbedConfig::new(2, ChannelMask::RGBA).unwrap();
+    assert!(two_bits.with_matrix_embedding(3).is_err());
+    assert!(one_bit.with_matrix_embedding(9).is_err());
+  }
+
+  #[test]
+  fn adaptive_embedding_keeps_to_textured_pixels() {
+    // A flat left half, like a clear sky, and a busy right half.
+    let rgb = RgbImage::from_fn(64, 64, |x, y| match x {
+      0..32 => Rgb([90, 140, 200]),
+      _ => Rgb([(x * 37 + y * 101) as u8, (x * y * 13) as u8, (y * 59) as u8]),
+    });
+    let cover = StegoImage::from_dynamic_image(DynamicImage::ImageRgb8(rgb));
+    let header_samples = cover.header_pixels() * 3;
+    let mut img = StegoImage::from_dynamic_image(cover.as_dynamic_image().clone());
+    let config = EmbedConfig::new(2, ChannelMask::RGBA).unwrap();
+    img.set_config(config.with_adaptive(true));
+    img.insert_data(&[0x69; 200]).unwrap();
+
+    assert!(img.header().unwrap().unwrap().adaptive_threshold > 0);
+    let changed = changed_samples(&cover, &img);
+    let flat = |&i: &usize| i >= header_samples && (i / 3) % 64 < 31;
+    assert!(!changed.iter().any(flat));
+    let read = StegoImage::from_dynamic_image(img.as_dynamic_image().clone());
+    assert_eq!(read.extract_data().unwrap(), [0x69; 200]);
+
+    img.set_config(config.with_adaptive(true).with_lsb_matching(true));
+    assert!(matches!(
+      img.insert_data(b"matching reaches the texture bits"),
+      Err(StegoError::InvalidConfig)
+    ));
   }
 }
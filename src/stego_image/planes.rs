@@ -0,0 +1,125 @@
+//! Renderings of a [`StegoImage`] that make its low bits visible, for
+//! debugging and for showing how detectable a payload is.
+
+use image::{GenericImageView, GrayImage};
+
+use super::{StegoError, StegoImage, StegoResult};
+
+impl StegoImage {
+  /// Renders `bits` bits of `channel`, from bit `lowest` up, as a greyscale
+  /// image stretched over the full range, so a single bit plane comes out black
+  /// and white. `channel` indexes RGBA like a
+  /// [`ChannelMask`](crate::config::ChannelMask), red, green and blue all
+  /// standing for the single channel of a grey image.
+  pub fn bit_plane(&self, channel: usize, lowest: u8, bits: u8) -> StegoResult<GrayImage> {
+    let layout = self.layout();
+    let channel = layout
+      .sample_channel(channel)
+      .ok_or(StegoError::InvalidConfig)?;
+    if bits == 0 || u32::from(lowest) + u32::from(bits) > self.sample_bits() {
+      return Err(StegoError::InvalidConfig);
+    }
+    let mask = (1u32 << bits) - 1;
+    let plane = self
+      .sample_values()
+      .into_iter()
+      .skip(channel)
+      .step_by(layout.channels)
+      .map(|sample| ((u32::from(sample) >> lowest & mask) * 255 / mask) as u8)
+      .collect();
+    let (width, height) = self.img.dimensions();
+    Ok(GrayImage::from_raw(width, height, plane).expect("one sample per pixel"))
+  }
+
+  /// Maps how far every pixel of `stego` strays from this image, its cover:
+  /// the largest difference between the channels of a pixel, stretched so the
+  /// largest difference in the image comes out white. Also returns that
+  /// difference.
+  pub fn difference_map(&self, stego: &StegoImage) -> StegoResult<(GrayImage, u16)> {
+    if self.img.dimensions() != stego.img.dimensions() || self.img.color() != stego.img.color() {
+      return Err(StegoError::ImageMismatch);
+    }
+    let channels = self.layout().channels;
+    let cover = self.sample_values();
+    let stego = stego.sample_values();
+    let differences: Vec<u16> = cover
+      .chunks_exact(channels)
+      .zip(stego.chunks_exact(channels))
+      .map(|(cover, stego)| {
+        let differences = cover.iter().zip(stego).map(|(&a, &b)| a.abs_diff(b));
+        differences.max().unwrap_or(0)
+      })
+      .collect();
+    let largest = differences.iter().copied().max().unwrap_or(0);
+    let map = differences
+      .into_iter()
+      .map(|difference| (u32::from(difference) * 255 / u32::from(largest.max(1))) as u8)
+      .collect();
+    let (width, height) = self.img.dimensions();
+    let map = GrayImage::from_raw(width, height, map).expect("one difference per pixel");
+    Ok((map, largest))
+  }
+
+  /// Bits in every sample, 8 or 16.
+  pub(super) fn sample_bits(&self) -> u32 {
+    let color = self.img.color();
+    u32::from(color.bytes_per_pixel() / color.channel_count()) * 8
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use image::{DynamicImage, GrayImage, Luma, Rgb, RgbImage};
+
+  use crate::{stego_image::StegoImage, StegoCarrier, StegoError};
+
+  fn rgb() -> RgbImage {
+    RgbImage::from_fn(16, 8, |x, y| Rgb([(x * 16 + y) as u8, y as u8, 255]))
+  }
+
+  #[test]
+  fn bit_planes_stretch_the_selected_bits() {
+    let img = StegoImage::from_dynamic_image(DynamicImage::ImageRgb8(rgb()));
+    let lowest = img.bit_plane(0, 0, 1).unwrap();
+    let two = img.bit_plane(0, 1, 2).unwrap();
+    for (x, y, &Rgb([r, ..])) in rgb().enumerate_pixels() {
+      assert_eq!(lowest.get_pixel(x, y).0, [(r & 1) * 255]);
+      assert_eq!(two.get_pixel(x, y).0, [(r >> 1 & 3) * 85]);
+    }
+
+    let grey = GrayImage::from_fn(4, 4, |x, _| Luma([x as u8]));
+    let grey = StegoImage::from_dynamic_image(DynamicImage::ImageLuma8(grey));
+    assert_eq!(
+      grey.bit_plane(2, 0, 1).unwrap(),
+      grey.bit_plane(0, 0, 1).unwrap()
+    );
+
+    for (channel, lowest, bits) in [(3, 0, 1), (0, 7, 2), (0, 0, 0)] {
+      assert!(matches!(
+        img.bit_plane(channel, lowest, bits),
+        Err(StegoError::InvalidConfig)
+      ));
+    }
+  }
+
+  #[test]
+  fn difference_maps_show_what_the_payload_changed() {
+    let cover = StegoImage::from_dynamic_image(DynamicImage::ImageRgb8(rgb()));
+    let (map, largest) = cover.difference_map(&cover).unwrap();
+    assert_eq!(largest, 0);
+    assert!(map.pixels().all(|pixel| pixel.0 == [0]));
+
+    let mut stego = StegoImage::from_dynamic_image(DynamicImage::ImageRgb8(rgb()));
+    stego.insert_data(&[0xff; 8]).unwrap();
+    let (map, largest) = cover.difference_map(&stego).unwrap();
+    assert!((1..=3).contains(&largest));
+    assert!(map.pixels().any(|pixel| pixel.0 == [255]));
+
+    let smaller = RgbImage::new(8, 8);
+    let smaller = StegoImage::from_dynamic_image(DynamicImage::ImageRgb8(smaller));
+    assert!(matches!(
+      cover.difference_map(&smaller),
+      Err(StegoError::ImageMismatch)
+    ));
+  }
+}
@@ -0,0 +1,354 @@
+//! Streaming counterparts of [`StegoImage::insert_data`] and
+//! [`StegoImage::extract_data`], which move the payload between the pixels and
+//! a [`Write`] or [`Read`] piece by piece instead of holding all of it in
+//! memory.
+//!
+//! Only plain payloads stream: compression is skipped for payloads it does not
+//! shrink, encryption authenticates the whole payload and Reed-Solomon codes
+//! interleave it, so none of them can be applied or undone before the end of
+//! the payload is known. A writer with any of them enabled gathers the payload
+//! and embeds it with [`StegoImage::insert_data`] on
+//! [`PayloadWriter::finish`], and a reader of such a payload, or of a file
+//! envelope or the legacy layout, extracts and verifies it in one go. A plain
+//! payload is read twice instead, so that nothing is released before its
+//! checksum matches.
+
+use std::io::{self, Cursor, Read, Write};
+
+use super::{BitReader, BitWriter, StegoError, StegoImage, StegoResult};
+use crate::{
+  carrier::StegoCarrier,
+  config::EmbedConfig,
+  header::{Checksum, Flags, Header},
+};
+
+/// Samples left to carry the payload, in order.
+type Order = Box<dyn Iterator<Item = usize>>;
+
+impl StegoImage {
+  /// Starts embedding a payload written piece by piece, with the password,
+  /// compression, checksum and configuration [`Self::insert_data`] would use.
+  pub fn writer(&mut self) -> StegoResult<PayloadWriter<'_>> {
+    if self.config.bits_per_channel() > self.max_bits_per_channel() {
+      return Err(StegoError::InvalidConfig);
+    }
+    let buffered = self.settings.compression
+      || self.settings.password.is_some()
+      || self.config.ecc_parity() > 0
+      || self.config.adaptive();
+    let sink = if buffered {
+      Sink::Buffered {
+        img: self,
+        data: Vec::new(),
+      }
+    } else {
+      let flags = Flags {
+        keyed_order: self.settings.ordering_seed.is_some(),
+        ..Flags::default()
+      };
+      Sink::Pixels(Box::new(PixelSink::new(self, flags)))
+    };
+    Ok(PayloadWriter { sink, written: 0 })
+  }
+
+  /// Starts reading the payload piece by piece, see [`PayloadReader`]. Fails
+  /// right away if the payload is damaged.
+  pub fn reader(&self) -> StegoResult<PayloadReader<'_>> {
+    let plain = |header: &Header| {
+      let flags = header.flags;
+      header.ecc_parity == 0
+        && header.shard.is_none()
+        && !(flags.compressed || flags.encrypted || flags.envelope || flags.archive)
+    };
+    let header = match self.header()? {
+      Some(header) if plain(&header) => header,
+      _ => {
+        return Ok(PayloadReader {
+          inner: Box::new(Cursor::new(self.extract_data()?)),
+        });
+      }
+    };
+    let (config, size) = self.payload_layout(&header)?;
+    let samples = self.samples();
+
+    let mut reader = self.body_reader(&header, &config);
+    let mut expected = vec![0; header.checksum.size()];
+    samples.read(&mut reader, &mut expected);
+    let mut checksum = header.checksum.hasher();
+    let mut buf = [0u8; 4096];
+    let mut remaining = size;
+    while remaining > 0 {
+      let len = buf.len().min(remaining);
+      samples.read(&mut reader, &mut buf[..len]);
+      checksum.update(&buf[..len]);
+      remaining -= len;
+    }
+    if checksum.finalize() != expected {
+      return Err(StegoError::InvalidHashCheck);
+    }
+
+    let mut reader = self.body_reader(&header, &config);
+    samples.read(&mut reader, &mut expected);
+    Ok(PayloadReader {
+      inner: Box::new(PixelSource {
+        img: self,
+        reader,
+        remaining: size,
+      }),
+    })
+  }
+
+  /// Reader of the checksum and payload behind the only copy of `header`.
+  fn body_reader(&self, header: &Header, config: &EmbedConfig) -> BitReader<Order> {
+    let mut order = self.sample_order();
+    let _ = self.header_samples(&mut order);
+    let order = self.body_samples(order, config.channels());
+    let order = self.textured_samples(order, config.bits_per_channel(), header.adaptive_threshold);
+    BitReader::for_body(Box::new(order), config)
+  }
+}
+
+/// Embeds what is written to it, see [`StegoImage::writer`].
+///
+/// The payload only becomes readable once [`Self::finish`] has stored its
+/// checksum and the header. Dropping the writer before, or after a failed
+/// write, leaves the image with a damaged payload.
+pub struct PayloadWriter<'a> {
+  sink: Sink<'a>,
+  written: usize,
+}
+
+enum Sink<'a> {
+  Pixels(Box<PixelSink<'a>>),
+  /// Payload held back until it is complete, see the module documentation.
+  Buffered {
+    img: &'a mut StegoImage,
+    data: Vec<u8>,
+  },
+}
+
+impl PayloadWriter<'_> {
+  /// Completes the payload. Write errors carry a [`StegoError`], such as
+  /// [`StegoError::NotEnoughSpace`] once the image is full.
+  pub fn finish(self) -> StegoResult<()> {
+    if self.written == 0 {
+      return Err(StegoError::NothingToInsert);
+    }
+    match self.sink {
+      Sink::Pixels(sink) => {
+        sink.finish();
+        Ok(())
+      }
+      Sink::Buffered { img, data } => img.insert_data(&data),
+    }
+  }
+}
+
+impl Write for PayloadWriter<'_> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let written = match &mut self.sink {
+      Sink::Pixels(sink) => sink.write(buf)?,
+      Sink::Buffered { data, .. } => data.write(buf)?,
+    };
+    self.written += written;
+    Ok(written)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+}
+
+/// Reads the payload of an image, see [`StegoImage::reader`].
+pub struct PayloadReader<'a> {
+  inner: Box<dyn Read + 'a>,
+}
+
+impl Read for PayloadReader<'_> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    self.inner.read(buf)
+  }
+}
+
+fn to_io(err: StegoError) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+/// Spreads the payload over the samples, leaving room for the checksum in
+/// front of it.
+struct PixelSink<'a> {
+  img: &'a mut StegoImage,
+  writer: BitWriter<Order>,
+  checksum: Checksum,
+  flags: Flags,
+  len: usize,
+  capacity: usize,
+}
+
+impl<'a> PixelSink<'a> {
+  fn new(img: &'a mut StegoImage, flags: Flags) -> Self {
+    let mut order = img.sample_order();
+    // The header is written by `finish`, once the length is known.
+    let _ = img.header_samples(&mut order);
+    let order: Order = Box::new(img.body_samples(order, img.config.channels()));
+    let mut writer = BitWriter::for_body(order, &img.config);
+    // Skipped rather than zeroed, so that LSB matching moves the samples of the
+    // checksum only once.
+    writer.skip(img.settings.checksum.size());
+    Self {
+      checksum: img.settings.checksum.hasher(),
+      capacity: img.available(),
+      img,
+      writer,
+      flags,
+      len: 0,
+    }
+  }
+
+  /// Writes the checksum and the header.
+  fn finish(mut self) {
+    self.img.samples_mut().flush(&mut self.writer);
+    let config = self.img.config;
+    let header = Header {
+      flags: self.flags,
+      bits_per_channel: config.bits_per_channel(),
+      channel_mask: config.channels().bits(),
+      checksum: self.img.settings.checksum,
+      ecc_parity: 0,
+      matrix_embedding: config.matrix_embedding(),
+      adaptive_threshold: 0,
+      payload_len: self.len as u64,
+      shard: None,
+    };
+    let mut order = self.img.sample_order();
+    let header_samples = self.img.header_samples(&mut order);
+    let body_samples = self.img.body_samples(order, config.channels());
+    let mut writer = BitWriter::for_body(body_samples, &config);
+    let mut samples = self.img.samples_mut();
+    samples.embed(
+      BitWriter::new(header_samples, 1).with_lsb_matching(config.lsb_matching()),
+      &header.encode(),
+    );
+    samples.write(&mut writer, &self.checksum.finalize());
+    samples.flush(&mut writer);
+  }
+}
+
+impl Write for PixelSink<'_> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    if self.len + buf.len() > self.capacity {
+      return Err(to_io(StegoError::NotEnoughSpace));
+    }
+    self.img.samples_mut().write(&mut self.writer, buf);
+    self.checksum.update(buf);
+    self.len += buf.len();
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+}
+
+/// Gathers a payload whose checksum has already been checked from the
+/// samples.
+struct PixelSource<'a> {
+  img: &'a StegoImage,
+  reader: BitReader<Order>,
+  remaining: usize,
+}
+
+impl Read for PixelSource<'_> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let len = buf.len().min(self.remaining);
+    self.img.samples().read(&mut self.reader, &mut buf[..len]);
+    self.remaining -= len;
+    Ok(len)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::{Read, Write};
+
+  use image::{DynamicImage, RgbImage};
+
+  use crate::{
+    stego_image::{SamplesMut, StegoImage},
+    StegoCarrier, StegoError,
+  };
+
+  fn cover() -> StegoImage {
+    let img = RgbImage::from_fn(64, 64, |x, y| image::Rgb([x as u8, y as u8, (x * y) as u8]));
+    StegoImage::from_dynamic_image(DynamicImage::ImageRgb8(img))
+  }
+
+  fn noise(len: usize) -> Vec<u8> {
+    let mut state = 1u32;
+    (0..len)
+      .map(|_| {
+        state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+        (state >> 24) as u8
+      })
+      .collect()
+  }
+
+  fn write(img: &mut StegoImage, data: &[u8]) {
+    let mut writer = img.writer().unwrap();
+    for piece in data.chunks(100) {
+      writer.write_all(piece).unwrap();
+    }
+    writer.finish().unwrap();
+  }
+
+  fn read(img: &StegoImage) -> Vec<u8> {
+    let mut data = Vec::new();
+    img.reader().unwrap().read_to_end(&mut data).unwrap();
+    data
+  }
+
+  #[test]
+  fn plain_payloads_round_trip() {
+    let data = noise(3000);
+    let mut img = cover();
+    write(&mut img, &data);
+    assert_eq!(read(&img), data);
+    assert_eq!(img.extract_data().unwrap(), data);
+  }
+
+  #[test]
+  fn writer_skips_compression_that_does_not_help() {
+    let mut img = cover();
+    img.set_compression(true);
+    let data = noise(3000);
+    write(&mut img, &data);
+    let header = img.header().unwrap().unwrap();
+    assert!(!header.flags.compressed);
+    assert_eq!(header.payload_len, 3000);
+    assert_eq!(read(&img), data);
+
+    let data = vec![7; 3000];
+    write(&mut img, &data);
+    assert!(img.header().unwrap().unwrap().flags.compressed);
+    assert_eq!(read(&img), data);
+  }
+
+  #[test]
+  fn reader_releases_nothing_unverified() {
+    let mut img = cover();
+    img.set_password(Some("secret"));
+    write(&mut img, b"sealed payload");
+    assert_eq!(read(&img), b"sealed payload");
+    img.set_password(Some("wrong"));
+    assert!(matches!(img.reader(), Err(StegoError::WrongPassword)));
+
+    let mut img = cover();
+    write(&mut img, &noise(3000));
+    // Flip a bit of the payload, past the header and the checksum.
+    let last = img.sample_values().len() - 1;
+    if let SamplesMut::U8(samples) = img.samples_mut() {
+      samples[last / 2] ^= 1;
+    }
+    assert!(matches!(img.reader(), Err(StegoError::InvalidHashCheck)));
+  }
+}
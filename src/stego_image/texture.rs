@@ -0,0 +1,105 @@
+//! Content-adaptive selection of the pixels carrying a payload.
+//!
+//! The noise of a payload shows in smooth areas such as skies and hides in
+//! textured ones. Adaptive embedding keeps the payload to the pixels whose
+//! texture reaches a threshold, picked as high as the payload allows and
+//! recorded in the header. Texture is measured on the bits above those
+//! carrying the payload, which LSB replacement leaves alone, so the extractor
+//! finds the same pixels in the stego image.
+
+use image::GenericImageView;
+
+use super::StegoImage;
+use crate::{config::EmbedConfig, header::Header};
+
+impl StegoImage {
+  /// Texture of every pixel: the mean over its four neighbours of the largest
+  /// difference between their colour channels, ignoring the `bits` low bits
+  /// of every sample.
+  pub(crate) fn texture(&self, bits: u8) -> Vec<u16> {
+    let layout = self.layout();
+    let values = self.sample_values();
+    let (width, height) = self.img.dimensions();
+    let (width, height) = (width as usize, height as usize);
+    let difference = |a: usize, b: usize| {
+      let channels = 0..layout.color_channels;
+      let differences = channels.map(|channel| {
+        let value = |pixel: usize| values[pixel * layout.channels + channel] >> bits;
+        value(a).abs_diff(value(b))
+      });
+      u32::from(differences.max().unwrap_or(0))
+    };
+    (0..width * height)
+      .map(|pixel| {
+        let (x, y) = (pixel % width, pixel / width);
+        let neighbours = [
+          (x > 0).then(|| pixel - 1),
+          (x + 1 < width).then(|| pixel + 1),
+          (y > 0).then(|| pixel - width),
+          (y + 1 < height).then(|| pixel + width),
+        ];
+        let neighbours = neighbours.into_iter().flatten();
+        let (sum, count) = neighbours.fold((0, 0), |(sum, count), neighbour| {
+          (sum + difference(pixel, neighbour), count + 1)
+        });
+        (sum / u32::max(count, 1)) as u16
+      })
+      .collect()
+  }
+
+  /// Restricts `samples` to the pixels whose [`Self::texture`] reaches
+  /// `threshold`, all of them for a zero threshold.
+  pub(crate) fn textured_samples(
+    &self,
+    samples: impl Iterator<Item = usize>,
+    bits: u8,
+    threshold: u16,
+  ) -> impl Iterator<Item = usize> {
+    let texture = (threshold > 0).then(|| self.texture(bits));
+    let channels = self.layout().channels;
+    samples.filter(move |sample| {
+      texture
+        .as_ref()
+        .is_none_or(|texture| texture[sample / channels] >= threshold)
+    })
+  }
+
+  /// Highest texture threshold leaving samples enough for `len` bytes behind
+  /// the header with `config`. Zero when the payload needs every pixel.
+  pub(crate) fn adaptive_threshold(&self, config: &EmbedConfig, len: usize) -> u16 {
+    let texture = self.texture(config.bits_per_channel());
+    let channels = self.layout().channels;
+    let mut histogram = vec![0usize; 1 << 16];
+    for sample in self.carrying_samples(config) {
+      histogram[usize::from(texture[sample / channels])] += 1;
+    }
+    let mut samples = 0;
+    for threshold in (1..=u16::MAX).rev() {
+      samples += histogram[usize::from(threshold)];
+      if config.carried_bits(samples) / 8 >= len {
+        return threshold;
+      }
+    }
+    0
+  }
+
+  /// Bytes that fit behind the header with `config` in the pixels whose
+  /// texture reaches `threshold`, see [`Self::body_capacity`].
+  pub(crate) fn adaptive_capacity(&self, config: &EmbedConfig, threshold: u16) -> usize {
+    if threshold == 0 {
+      return self.body_capacity(config);
+    }
+    let samples = self.carrying_samples(config);
+    let samples = self.textured_samples(samples, config.bits_per_channel(), threshold);
+    config.carried_bits(samples.count()) / 8
+  }
+
+  /// Samples after the header and its copies in the channels `config` embeds in.
+  fn carrying_samples(&self, config: &EmbedConfig) -> impl Iterator<Item = usize> {
+    let mut order = self.sample_order();
+    for _ in 0..Header::copies(config.ecc_parity()) {
+      let _ = self.header_samples(&mut order);
+    }
+    self.body_samples(order, config.channels())
+  }
+}
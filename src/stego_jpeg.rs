@@ -0,0 +1,266 @@
+//! Embedding in the quantized DCT coefficients of a JPEG.
+//!
+//! Payload bits replace the least significant bit of AC coefficients the way
+//! JSteg does, so the result can be stored as a JPEG without losing them. The
+//! versioned header takes the first 256 usable coefficients, once per copy of
+//! it, and the checksummed payload follows, both one bit per coefficient. With
+//! an ordering key the usable coefficients are visited in a key-seeded
+//! permutation instead of file order.
+//!
+//! The payload only survives as long as the coefficients do: re-encoding the
+//! file with another quality or another tool still destroys it.
+
+use std::{fs, path::Path};
+
+use image::{
+  error::{LimitError, LimitErrorKind},
+  ImageError, ImageResult,
+};
+
+pub use crate::jpeg::is_jpeg;
+use crate::{
+  capacity::DctCapacityReport,
+  carrier::{
+    sealed::{Domain, PayloadSettings},
+    StegoCarrier,
+  },
+  config::EmbedConfig,
+  crypto, ecc,
+  header::{Flags, Header, Shard, HEADER_SIZE, PROTECTED_COPIES},
+  jpeg::JpegImage,
+  stego_image::{
+    checked_stream, embed_bits, extract_bits, verify_stream, StegoError, StegoResult, Stored,
+  },
+};
+
+/// Coefficients carrying the header.
+const HEADER_BITS: usize = HEADER_SIZE * 8;
+/// Largest dimension a JPEG can describe.
+const MAX_DIMENSION: u32 = u16::MAX as u32;
+
+pub struct StegoJpeg {
+  jpeg: JpegImage,
+  settings: PayloadSettings,
+  ecc_parity: u8,
+}
+
+impl StegoJpeg {
+  /// Quality a carrier is compressed at when it is not already a JPEG.
+  pub const DEFAULT_QUALITY: u8 = 90;
+
+  /// Opens a JPEG keeping its coefficients untouched. Other images, and JPEGs
+  /// whose coefficients cannot be read directly such as progressive ones, are
+  /// decoded and compressed at `quality` (1 to 100).
+  pub fn open(path: &Path, quality: u8) -> ImageResult<Self> {
+    Self::from_bytes(&fs::read(path)?, quality)
+  }
+
+  /// Like [`Self::open`] for an image held in memory.
+  pub fn from_bytes(bytes: &[u8], quality: u8) -> ImageResult<Self> {
+    let jpeg = match JpegImage::decode(bytes) {
+      Some(jpeg) => jpeg,
+      None => {
+        let img = image::load_from_memory(bytes)?.to_rgba8();
+        if img.width() > MAX_DIMENSION || img.height() > MAX_DIMENSION {
+          return Err(ImageError::Limits(LimitError::from_kind(
+            LimitErrorKind::DimensionError,
+          )));
+        }
+        JpegImage::from_pixels(img.width() as usize, img.height() as usize, &img, quality)
+      }
+    };
+    Ok(Self {
+      jpeg,
+      settings: PayloadSettings::default(),
+      ecc_parity: 0,
+    })
+  }
+
+  /// Sets the Reed-Solomon parity bytes per block, see
+  /// [`EmbedConfig::with_error_correction`](crate::config::EmbedConfig::with_error_correction).
+  pub fn set_error_correction(&mut self, parity: u8) -> StegoResult<()> {
+    if parity > EmbedConfig::MAX_ECC_PARITY {
+      return Err(StegoError::InvalidConfig);
+    }
+    self.ecc_parity = parity;
+    Ok(())
+  }
+
+  /// Encodes the image as a JPEG.
+  pub fn to_bytes(&self) -> Vec<u8> {
+    self.jpeg.encode()
+  }
+
+  /// Whether an AC coefficient carries a payload bit. Zeros and ones are
+  /// skipped as in JSteg, since flipping their least significant bit turns
+  /// one into the other and would change which coefficients are usable;
+  /// -1023 is skipped because -1024 cannot be coded.
+  fn is_usable(coefficient: i16) -> bool {
+    !matches!(coefficient, 0 | 1 | i16::MIN..=-1023)
+  }
+
+  /// Indices of the usable coefficients in the order they carry the header
+  /// and then the payload.
+  fn coefficient_order(&self) -> Vec<usize> {
+    let mut order: Vec<usize> = self
+      .jpeg
+      .ac_coefficients()
+      .enumerate()
+      .filter(|&(_, coefficient)| Self::is_usable(coefficient))
+      .map(|(index, _)| index)
+      .collect();
+    if let Some(seed) = self.settings.ordering_seed {
+      let mut rng = crypto::ChaChaRng::new(seed);
+      for i in (1..order.len()).rev() {
+        order.swap(i, rng.below(i as u32 + 1) as usize);
+      }
+    }
+    order
+  }
+
+  /// The low byte of every AC coefficient, whose least significant bit is what
+  /// [`embed_bits`] and [`extract_bits`] work on.
+  fn samples(&self) -> Vec<u8> {
+    self
+      .jpeg
+      .ac_coefficients()
+      .map(|coefficient| coefficient as u8)
+      .collect()
+  }
+
+  fn usable_coefficients(&self) -> usize {
+    self
+      .jpeg
+      .ac_coefficients()
+      .filter(|&coefficient| Self::is_usable(coefficient))
+      .count()
+  }
+
+  /// Bytes that fit after the header and its copies, including the checksum
+  /// and `ecc_parity` error correction.
+  fn body_capacity(&self, ecc_parity: u8) -> usize {
+    let header_bits = HEADER_BITS * Header::copies(ecc_parity);
+    self.usable_coefficients().saturating_sub(header_bits) / 8
+  }
+
+  pub fn capacity(&self) -> DctCapacityReport {
+    let body_capacity = self.body_capacity(self.ecc_parity);
+    DctCapacityReport {
+      coefficients: self.jpeg.ac_coefficients().count(),
+      usable_coefficients: self.usable_coefficients(),
+      header_coefficients: HEADER_BITS * Header::copies(self.ecc_parity),
+      checksum_overhead: self.settings.checksum.size(),
+      encryption_overhead: self.settings.encryption_overhead(),
+      error_correction_overhead: body_capacity - ecc::data_capacity(body_capacity, self.ecc_parity),
+      usable: self
+        .available()
+        .saturating_sub(self.settings.encryption_overhead()),
+    }
+  }
+}
+
+impl StegoCarrier for StegoJpeg {
+  fn available(&self) -> usize {
+    let body_capacity = self.body_capacity(self.ecc_parity);
+    ecc::data_capacity(body_capacity, self.ecc_parity).saturating_sub(self.settings.checksum.size())
+  }
+
+  fn header(&self) -> StegoResult<Option<Header>> {
+    let order = self.coefficient_order();
+    if order.len() < HEADER_BITS {
+      return Ok(None);
+    }
+    let mut copies = vec![0u8; HEADER_SIZE * PROTECTED_COPIES];
+    let header_order = order.iter().take(HEADER_BITS * PROTECTED_COPIES);
+    extract_bits(&self.samples(), header_order.copied(), 1, &mut copies);
+    Header::recover(&copies)
+  }
+
+  /// Without a key the payload is embedded in file order.
+  fn set_ordering_key(&mut self, key: Option<&str>) {
+    self.settings.ordering_seed =
+      key.map(|key| crypto::derive_seed(key.as_bytes(), b"rustego coefficient order"));
+  }
+
+  /// Writes the image as a JPEG, whatever the extension of `path`.
+  fn save(&self, path: &Path) -> StegoResult<()> {
+    fs::write(path, self.to_bytes()).map_err(|err| StegoError::Image(err.into()))
+  }
+}
+
+impl Domain for StegoJpeg {
+  fn settings(&self) -> &PayloadSettings {
+    &self.settings
+  }
+
+  fn settings_mut(&mut self) -> &mut PayloadSettings {
+    &mut self.settings
+  }
+
+  fn store(&mut self, data: &[u8], mut flags: Flags, shard: Option<Shard>) -> StegoResult<()> {
+    flags.keyed_order = self.settings.ordering_seed.is_some();
+    flags.dct = true;
+    if self.available() < data.len() {
+      return Err(StegoError::NotEnoughSpace);
+    }
+    let checksum = self.settings.checksum;
+    let header = Header {
+      flags,
+      bits_per_channel: 1,
+      channel_mask: 0,
+      checksum,
+      ecc_parity: self.ecc_parity,
+      matrix_embedding: 0,
+      adaptive_threshold: 0,
+      payload_len: data.len() as u64,
+      shard,
+    };
+    let stream = checked_stream(data, checksum, self.ecc_parity);
+    let order = self.coefficient_order();
+    let (header_order, body_order) = order.split_at(HEADER_BITS * Header::copies(self.ecc_parity));
+    let mut samples = self.samples();
+    embed_bits(
+      &mut samples,
+      header_order.iter().copied(),
+      1,
+      header.encode_copies(),
+    );
+    embed_bits(&mut samples, body_order.iter().copied(), 1, stream);
+    for (coefficient, sample) in self.jpeg.ac_coefficients_mut().zip(samples) {
+      *coefficient = *coefficient & !1 | (sample & 1) as i16;
+    }
+    Ok(())
+  }
+
+  fn stored(&self) -> StegoResult<Option<Stored>> {
+    let Some(header) = self.header()? else {
+      return Ok(None);
+    };
+    if header.flags.keyed_order != self.settings.ordering_seed.is_some() || !header.flags.dct {
+      return Err(StegoError::InvalidHeader);
+    }
+    let size = header.payload_size(self.body_capacity(header.ecc_parity))?;
+    let mut stream = vec![0u8; ecc::encoded_len(header.checksum.size() + size, header.ecc_parity)];
+    let order = self.coefficient_order();
+    extract_bits(
+      &self.samples(),
+      order[HEADER_BITS * Header::copies(header.ecc_parity)..]
+        .iter()
+        .copied(),
+      1,
+      &mut stream,
+    );
+    let (data, corrected) = verify_stream(stream, size, header.checksum, header.ecc_parity)?;
+    Ok(Some(Stored {
+      header,
+      data,
+      corrected,
+    }))
+  }
+
+  fn reopen(&self, path: &Path) -> StegoResult<Box<dyn StegoCarrier>> {
+    let mut written = Self::open(path, Self::DEFAULT_QUALITY).map_err(StegoError::Image)?;
+    written.settings = self.settings.clone();
+    Ok(Box::new(written))
+  }
+}